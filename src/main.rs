@@ -1,16 +1,81 @@
 use clap::Parser;
 use log::{debug, error, info, trace, warn};
-use reqwest::Client;
+use reqwest::{Certificate, Client, Method, NoProxy, Proxy, StatusCode};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{env, fs, io::Write, path::Path, process::Command as ProcessCommand};
+use sha2::{Digest, Sha256};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{hash_map::RandomState, HashMap},
+    env, fs,
+    hash::{BuildHasher, Hasher},
+    io::{IsTerminal, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::Command as ProcessCommand,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use thiserror::Error;
 
 // Constants for API configuration
-const GEMINI_API_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta/models";
-const DEFAULT_GEMINI_MODEL: &str = "gemini-2.0-flash-thinking-exp-01-21";
+const GEMINI_API_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+const DEFAULT_GEMINI_MODEL: &str = "gemini-2.5-flash";
 const GEMINI_MODEL_ENV_VAR: &str = "GEMINI_MODEL";
 const GEMINI_API_ENDPOINT_ENV_VAR: &str = "GEMINI_API_ENDPOINT";
+const GEMINI_API_KEY_ENV_VAR: &str = "GEMINI_API_KEY";
+const GEMINI_CONFIG_ENV_VAR: &str = "GEMINI_CONFIG";
+const GOOGLE_CLOUD_PROJECT_ENV_VAR: &str = "GOOGLE_CLOUD_PROJECT";
+const GOOGLE_CLOUD_LOCATION_ENV_VAR: &str = "GOOGLE_CLOUD_LOCATION";
+const GOOGLE_APPLICATION_CREDENTIALS_ENV_VAR: &str = "GOOGLE_APPLICATION_CREDENTIALS";
+const DEFAULT_VERTEX_LOCATION: &str = "us-central1";
+const DEFAULT_OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_OLLAMA_BASE_URL: &str = "http://localhost:11434/v1";
+const OPENAI_API_KEY_ENV_VAR: &str = "OPENAI_API_KEY";
+const VERTEX_API_VERSION: &str = "v1";
+const GOOGLE_OAUTH_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const ACCESS_TOKEN_LIFETIME_SECS: u64 = 3600;
+const ACCESS_TOKEN_REFRESH_MARGIN_SECS: u64 = 60;
+const DEFAULT_CHAT_HISTORY_LENGTH: usize = 20;
+const DEFAULT_RETRY_MAX_ATTEMPTS: u32 = 4;
+const DEFAULT_RETRY_BASE_DELAY_MS: u64 = 1000;
+const DEFAULT_RETRY_MAX_DELAY_MS: u64 = 60_000;
+const MAX_FUNCTION_CALL_ROUNDS: usize = 10;
+const DEFAULT_INPUT_TOKEN_LIMIT: u32 = 1_048_576;
+const INPUT_TOKEN_WARNING_RATIO: f64 = 0.9;
+const MAX_INLINE_ATTACHMENT_BYTES: u64 = 15 * 1024 * 1024;
+const MAX_ATTACHMENT_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+const ATTACHMENT_TOKEN_ESTIMATE: usize = 258;
+const FILE_PROCESSING_POLL_INTERVAL_MS: u64 = 2000;
+const FILE_PROCESSING_MAX_POLLS: u32 = 60;
+const DEFAULT_CACHE_TTL_SECS: u64 = 3600;
+const CACHE_RENEWAL_MARGIN_SECS: u64 = 300;
+const MAX_CACHED_FILE_BYTES: u64 = 1024 * 1024;
+const REDACTED: &str = "[REDACTED]";
+const SYSTEM_INFO_HEADER: &str = "System Information:";
+/// Lines of the system information that differ between machines and checkouts
+const VOLATILE_SYSTEM_INFO: [&str; 3] = ["OS: ", "Arch: ", "Dir: "];
+const DEFAULT_MOCK_SERVER_ADDRESS: &str = "127.0.0.1:8080";
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 600;
+const DEFAULT_USER_AGENT: &str = concat!("gemini-codemaker/", env!("CARGO_PKG_VERSION"));
+const RATE_LIMIT_STATE_FILE: &str = "rate-limits.json";
+const DEFAULT_MAX_CONTINUATIONS: u32 = 3;
+const MIN_CONTINUATION_OVERLAP: usize = 20;
+const INCOMPLETE_FILE_SUFFIX: &str = ".incomplete";
+const CONTINUE_PROMPT: &str = "Your previous answer was cut off. Continue exactly where you left off, \
+    without repeating anything that is already complete and in the same format.";
+const CONTINUE_IN_BLOCK_PROMPT: &str = "Your previous answer was cut off inside a code block. Continue exactly \
+    where you left off, without repeating anything and without opening a new code block.";
+const DEFAULT_EMBEDDING_MODEL: &str = "gemini-embedding-001";
+const DEFAULT_EMBEDDING_DIMENSIONS: u32 = 768;
+const DEFAULT_INDEX_FILE: &str = ".gemini-codemaker-index.json";
+const DEFAULT_CHUNK_LINES: usize = 60;
+const MAX_CHUNK_BYTES: usize = 8000;
+const MAX_EMBED_BATCH_SIZE: usize = 100;
+const DEFAULT_INDEX_TOP_K: usize = 5;
 
 /// Custom error type for the application
 /// 
@@ -45,6 +110,26 @@ pub enum AppError {
     /// Error in the response from Gemini API
     #[error("Response error: {0}")]
     ResponseError(String),
+
+    /// Error in the configuration file or settings
+    #[error("Configuration error: {0}")]
+    ConfigError(String),
+
+    /// Error when reading, identifying or uploading an attached file
+    #[error("Attachment error: {0}")]
+    AttachmentError(String),
+
+    /// Error when loading credentials or obtaining an access token
+    #[error("Authentication error: {0}")]
+    AuthError(String),
+
+    /// Error when the server stops sending data for longer than the read timeout
+    #[error("Timeout: {0}")]
+    TimeoutError(String),
+
+    /// Error when a request has more input tokens than the model accepts
+    #[error("Input too large: {0}")]
+    InputTooLarge(String),
 }
 
 impl From<String> for AppError {
@@ -61,6 +146,68 @@ impl From<String> for AppError {
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Stream responses and print them as they arrive
+    #[arg(long, global = true)]
+    stream: bool,
+    /// Maximum number of attempts for a request that fails with a retryable error
+    #[arg(long, global = true, default_value_t = DEFAULT_RETRY_MAX_ATTEMPTS)]
+    retry_max_attempts: u32,
+    /// Delay before the first retry in milliseconds, doubled on every further attempt
+    #[arg(long, global = true, default_value_t = DEFAULT_RETRY_BASE_DELAY_MS)]
+    retry_base_delay_ms: u64,
+    /// Upper bound for the computed retry delay in milliseconds
+    #[arg(long, global = true, default_value_t = DEFAULT_RETRY_MAX_DELAY_MS)]
+    retry_max_delay_ms: u64,
+    /// Disable random jitter on retry delays
+    #[arg(long, global = true)]
+    no_retry_jitter: bool,
+    /// JSON file with per-model prices in USD per million tokens, used to estimate cost
+    #[arg(long, global = true)]
+    price_table: Option<String>,
+    /// JSON configuration file (defaults to the GEMINI_CONFIG environment variable)
+    #[arg(long, global = true)]
+    config: Option<String>,
+    /// File with instructions replacing the default assistant persona
+    #[arg(long, global = true)]
+    system_prompt_file: Option<String>,
+    /// Maximum number of input tokens the model accepts [default: 1048576]
+    #[arg(long, global = true)]
+    input_token_limit: Option<u32>,
+    /// Name of a cached content (see the cache subcommand) to use as context
+    #[arg(long, global = true)]
+    cached_content: Option<String>,
+    /// Attach a file (image, PDF, audio, video or text) to the prompt; can be repeated
+    #[arg(long, global = true, value_name = "PATH")]
+    attach: Vec<String>,
+    /// Print the model's thought summaries dimmed (implies --include-thoughts)
+    #[arg(long, global = true)]
+    show_thoughts: bool,
+    /// Ground answers with Google Search and list the sources under them (chat and execute)
+    #[arg(long, global = true)]
+    grounding: bool,
+    /// How to choose among several response candidates
+    #[arg(long, global = true, value_enum, default_value_t = SelectionStrategy::First)]
+    select: SelectionStrategy,
+    /// Command run in a candidate's generated files for `--select verify`; exit code 0 passes
+    #[arg(long, global = true)]
+    verify_command: Option<String>,
+    /// Save every model request and reply to this directory, with API keys redacted
+    #[arg(long, global = true, value_name = "DIR", conflicts_with = "replay")]
+    record: Option<String>,
+    /// Answer model requests from a directory written with --record instead of the network
+    #[arg(long, global = true, value_name = "DIR")]
+    replay: Option<String>,
+    /// With --replay, answer requests that match no recording with the next unplayed reply
+    #[arg(long, global = true, requires = "replay")]
+    replay_lenient: bool,
+    /// Index written by the index subcommand; the most relevant chunks are sent with each query
+    #[arg(long, global = true, value_name = "FILE")]
+    index: Option<String>,
+    /// Number of index chunks sent with each query
+    #[arg(long, global = true, default_value_t = DEFAULT_INDEX_TOP_K)]
+    index_top_k: usize,
+    #[command(flatten)]
+    generation: GenerationSettings,
 }
 
 /// Subcommands for the CLI application
@@ -76,6 +223,15 @@ enum Commands {
         /// The initial query to send to Gemini (optional, will prompt if not provided)
         #[arg(long)]
         query: Option<String>,
+        /// Maximum number of conversation turns kept and resent to Gemini
+        #[arg(long, default_value_t = DEFAULT_CHAT_HISTORY_LENGTH)]
+        history_length: usize,
+        /// Stop the chat once this many tokens have been used in the session
+        #[arg(long)]
+        max_tokens_budget: Option<u64>,
+        /// How Gemini should return the commands to execute
+        #[arg(long, value_enum, default_value_t = CommandMode::Functions)]
+        command_mode: CommandMode,
     },
     /// Execute code with Gemini
     Execute {
@@ -91,109 +247,566 @@ enum Commands {
         /// Output directory for the generated codebase
         #[arg(long, default_value = ".")]
         output_dir: String,
+        /// Follow-up requests for a response cut off at the output token limit (0 disables them)
+        ///
+        /// Only `create-codebase` continues cut-off responses, because it joins the parts
+        /// before writing any file. `chat` and `execute` show a cut-off answer as-is.
+        #[arg(long, default_value_t = DEFAULT_MAX_CONTINUATIONS)]
+        max_continuations: u32,
+    },
+    /// List and inspect the available models
+    Models {
+        #[command(subcommand)]
+        action: ModelsAction,
+    },
+    /// Manage cached project context
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+    /// Embed project files into a local index for retrieval with --index
+    Index {
+        /// File or directory to index; directories are read recursively (can be repeated)
+        #[arg(long, default_value = ".")]
+        path: Vec<String>,
+        /// File the index is written to; chunks that did not change since the last run are reused
+        #[arg(long, default_value = DEFAULT_INDEX_FILE)]
+        output: String,
+        /// Model used to embed the chunks
+        #[arg(long, default_value = DEFAULT_EMBEDDING_MODEL)]
+        embedding_model: String,
+        /// Size of the embedding vectors
+        #[arg(long, default_value_t = DEFAULT_EMBEDDING_DIMENSIONS)]
+        dimensions: u32,
+        /// Maximum number of lines per chunk
+        #[arg(long, default_value_t = DEFAULT_CHUNK_LINES)]
+        chunk_lines: usize,
+    },
+    /// Serve scripted Gemini API responses locally for end-to-end tests
+    MockServer {
+        /// JSON or YAML (`.yaml`, `.yml`) file with the scenario steps to serve in order
+        #[arg(long)]
+        scenario: String,
+        /// Address to listen on
+        #[arg(long, default_value = DEFAULT_MOCK_SERVER_ADDRESS)]
+        address: String,
+    },
+}
+
+/// Actions of the `cache` subcommand
+#[derive(Debug, clap::Subcommand)]
+enum CacheAction {
+    /// Cache project files together with the system instruction and tools of a subcommand
+    Create {
+        /// File or directory to cache; directories are read recursively (can be repeated)
+        #[arg(long, required = true)]
+        path: Vec<String>,
+        /// Time to live in seconds
+        #[arg(long, default_value_t = DEFAULT_CACHE_TTL_SECS)]
+        ttl: u64,
+        /// Name shown when listing caches
+        #[arg(long)]
+        display_name: Option<String>,
+        /// Subcommand the cache will be used with
+        #[arg(long, value_enum, default_value_t = CacheTarget::Chat)]
+        target: CacheTarget,
+        /// Command mode of the chat sessions using the cache
+        #[arg(long, value_enum, default_value_t = CommandMode::Functions)]
+        command_mode: CommandMode,
+    },
+    /// List the cached contents
+    List,
+    /// Delete a cached content
+    Delete {
+        /// Name of the cache, e.g. cachedContents/abc123
+        name: String,
+    },
+    /// Set a new time to live for a cached content
+    Extend {
+        /// Name of the cache, e.g. cachedContents/abc123
+        name: String,
+        /// Time to live in seconds, counted from now
+        #[arg(long, default_value_t = DEFAULT_CACHE_TTL_SECS)]
+        ttl: u64,
+    },
+}
+
+/// Actions of the `models` subcommand
+#[derive(Debug, clap::Subcommand)]
+enum ModelsAction {
+    /// List the models available to the API key
+    List,
+    /// Show the limits and capabilities of a model
+    Show {
+        /// Name of the model, e.g. gemini-2.5-flash
+        name: String,
     },
 }
 
+/// Subcommand a cached content is created for
+///
+/// A request that uses a cache cannot set a system instruction or tools, so the
+/// cache has to contain the ones of the subcommand it is used with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum CacheTarget {
+    Chat,
+    Execute,
+    CreateCodebase,
+}
+
+/// How chat mode asks Gemini for commands
+///
+/// - Functions: Commands are declared as functions and returned as `functionCall` parts
+/// - Json: Commands are described in the prompt and returned as JSON in the response text
+/// - Schema: Like Json, but the reply is constrained by a `responseSchema` derived from `GeminiResponse`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum CommandMode {
+    Functions,
+    Json,
+    Schema,
+}
+
 /// Response structure from the Gemini API
 /// 
 /// Contains the response data from the Gemini API, including
 /// candidates, prompt feedback, and usage metadata.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct GeminiApiResponse {
     candidates: Option<Vec<Candidate>>,
     prompt_feedback: Option<PromptFeedback>,
+    usage_metadata: Option<UsageMetadata>,
+    model_version: Option<String>,
+    response_id: Option<String>,
+}
+
+impl GeminiApiResponse {
+    /// Merges a chunk of a streamed response into this response
+    ///
+    /// Parts are appended to the candidate with the same index, with adjacent
+    /// text parts joined, and the latest finish reason, feedback and metadata
+    /// are kept.
+    fn merge_chunk(&mut self, chunk: GeminiApiResponse) {
+        if chunk.prompt_feedback.is_some() {
+            self.prompt_feedback = chunk.prompt_feedback;
+        }
+        // Usage metadata in a stream is cumulative, so the last chunk wins
+        if chunk.usage_metadata.is_some() {
+            self.usage_metadata = chunk.usage_metadata;
+        }
+        if chunk.model_version.is_some() {
+            self.model_version = chunk.model_version;
+        }
+        if chunk.response_id.is_some() {
+            self.response_id = chunk.response_id;
+        }
+
+        let candidates = self.candidates.get_or_insert_with(Vec::new);
+        for candidate in chunk.candidates.unwrap_or_default() {
+            let index = candidate.index.unwrap_or(0);
+            let Some(existing) = candidates.iter_mut().find(|c| c.index.unwrap_or(0) == index) else {
+                candidates.push(candidate);
+                continue;
+            };
+
+            if candidate.finish_reason.is_some() {
+                existing.finish_reason = candidate.finish_reason;
+            }
+            if candidate.safety_ratings.is_some() {
+                existing.safety_ratings = candidate.safety_ratings;
+            }
+            if candidate.citation_metadata.is_some() {
+                existing.citation_metadata = candidate.citation_metadata;
+            }
+            if candidate.grounding_metadata.is_some() {
+                existing.grounding_metadata = candidate.grounding_metadata;
+            }
+            if candidate.avg_logprobs.is_some() {
+                existing.avg_logprobs = candidate.avg_logprobs;
+            }
+            if existing.content.role.is_none() {
+                existing.content.role = candidate.content.role;
+            }
+            for part in candidate.content.parts {
+                match (existing.content.parts.last_mut(), part) {
+                    // The signature arrives with the last chunk of a part, often with empty text
+                    (
+                        Some(Part::Text { text, thought_signature }),
+                        Part::Text { text: more, thought_signature: signature },
+                    )
+                    | (
+                        Some(Part::Thought { text, thought_signature, .. }),
+                        Part::Thought { text: more, thought_signature: signature, .. },
+                    ) => {
+                        text.push_str(&more);
+                        if signature.is_some() {
+                            *thought_signature = signature;
+                        }
+                    }
+                    (_, part) => existing.content.parts.push(part),
+                }
+            }
+        }
+    }
 }
 
 /// Candidate in the Gemini API response
 /// 
 /// Represents a single response candidate from the Gemini API.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct Candidate {
+    #[serde(default)]
     content: Content,
-    #[allow(dead_code)]
     finish_reason: Option<String>,
-    #[allow(dead_code)]
     index: Option<i32>,
-    #[allow(dead_code)]
     safety_ratings: Option<Vec<SafetyRating>>,
+    citation_metadata: Option<CitationMetadata>,
+    grounding_metadata: Option<GroundingMetadata>,
+    avg_logprobs: Option<f64>,
+    token_count: Option<u32>,
+}
+
+impl Candidate {
+    /// Creates a candidate from model parts, e.g. when translating another API's response
+    fn from_parts(index: i32, parts: Vec<Part>, finish_reason: Option<String>) -> Self {
+        Candidate {
+            content: Content {
+                role: Some("model".to_string()),
+                parts,
+            },
+            finish_reason,
+            index: Some(index),
+            safety_ratings: None,
+            citation_metadata: None,
+            grounding_metadata: None,
+            avg_logprobs: None,
+            token_count: None,
+        }
+    }
 }
 
 /// Content of a candidate in the Gemini API response
 /// 
-/// Contains the parts of the response content.
-#[derive(Debug, Deserialize)]
+/// Contains the parts of the response content. Also used to build the
+/// `contents` of a request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct Content {
-    #[allow(dead_code)]
+    #[serde(skip_serializing_if = "Option::is_none")]
     role: Option<String>,
+    #[serde(default)]
     parts: Vec<Part>,
 }
 
+impl Content {
+    /// Creates a user turn with the attached files followed by the text
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The user's text
+    /// * `attachments` - `inlineData` or `fileData` parts to send along
+    fn user(text: &str, attachments: &[Part]) -> Self {
+        let mut parts = attachments.to_vec();
+        parts.push(Part::text(text));
+        Content {
+            role: Some("user".to_string()),
+            parts,
+        }
+    }
+
+    /// Creates a content entry with a single text part
+    ///
+    /// # Arguments
+    ///
+    /// * `role` - The role of the author (`user` or `model`), if any
+    /// * `text` - The text of the part
+    fn text(role: Option<&str>, text: impl Into<String>) -> Self {
+        Content {
+            role: role.map(str::to_string),
+            parts: vec![Part::text(text)],
+        }
+    }
+}
+
 /// Part of the content in a Gemini API response
 /// 
 /// Can be one of several types:
 /// - Text: Plain text response
 /// - ExecutableCode: Code that can be executed
 /// - CodeExecutionResult: Result of code execution
-#[derive(Debug, Deserialize)]
+/// - FunctionCall: A call to one of the declared functions
+/// - FunctionResponse: The result of a function call, sent back to the model
+/// - InlineData: A file sent inline as base64, e.g. an attached image
+/// - FileData: A file uploaded through the Files API, referenced by URI
+/// - Thought: A summary of the model's reasoning, marked with `thought: true`
+/// - Other: Any part type this client does not model yet, kept as raw JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 enum Part {
+    FunctionCall {
+        #[serde(rename = "functionCall")]
+        function_call: FunctionCall,
+        /// Opaque signature of the model's reasoning, sent back unchanged in later turns
+        #[serde(rename = "thoughtSignature", default, skip_serializing_if = "Option::is_none")]
+        thought_signature: Option<String>,
+    },
+    FunctionResponse {
+        #[serde(rename = "functionResponse")]
+        function_response: FunctionResponse,
+    },
     ExecutableCode {
+        #[serde(rename = "executableCode")]
         executable_code: ExecutableCode,
     },
     CodeExecutionResult {
+        #[serde(rename = "codeExecutionResult")]
         code_execution_result: CodeExecutionResult,
     },
+    InlineData {
+        #[serde(rename = "inlineData")]
+        inline_data: Blob,
+    },
+    FileData {
+        #[serde(rename = "fileData")]
+        file_data: FileData,
+    },
+    Thought {
+        text: String,
+        #[serde(deserialize_with = "deserialize_true")]
+        thought: bool,
+        #[serde(rename = "thoughtSignature", default, skip_serializing_if = "Option::is_none")]
+        thought_signature: Option<String>,
+    },
     Text {
         text: String,
+        #[serde(rename = "thoughtSignature", default, skip_serializing_if = "Option::is_none")]
+        thought_signature: Option<String>,
     },
+    Other(serde_json::Value),
+}
+
+impl Part {
+    /// Creates a text part without a thought signature
+    fn text(text: impl Into<String>) -> Self {
+        Part::Text {
+            text: text.into(),
+            thought_signature: None,
+        }
+    }
+}
+
+/// Deserializes a flag that must be `true`
+///
+/// Lets the untagged [`Part`] enum treat `thought: false` parts as plain text.
+fn deserialize_true<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    if bool::deserialize(deserializer)? {
+        Ok(true)
+    } else {
+        Err(serde::de::Error::custom("expected `true`"))
+    }
 }
 
 /// Executable code part in a Gemini API response
 /// 
 /// Contains code that can be executed and its language.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct ExecutableCode {
     language: String,
     code: String,
 }
 
+/// Function call in a Gemini API response
+/// 
+/// Contains the name of the declared function and its arguments.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FunctionCall {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    name: String,
+    #[serde(default)]
+    args: serde_json::Value,
+}
+
+/// Inline file data in a request or response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Blob {
+    mime_type: String,
+    /// Base64-encoded file content
+    data: String,
+}
+
+/// Reference to a file uploaded through the Files API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileData {
+    mime_type: String,
+    file_uri: String,
+}
+
+/// Function response sent back to the Gemini API
+/// 
+/// Contains the result of executing a function call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FunctionResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    name: String,
+    response: serde_json::Value,
+}
+
 /// Code execution result in a Gemini API response
 /// 
 /// Contains the result of executing code.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct CodeExecutionResult {
     outcome: String,
+    #[serde(default)]
     output: String,
 }
 
 /// Prompt feedback in a Gemini API response
 /// 
 /// Contains feedback about the prompt, such as whether it was blocked.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct PromptFeedback {
     block_reason: Option<String>,
-    #[allow(dead_code)]
     safety_ratings: Option<Vec<SafetyRating>>,
 }
 
 /// Safety rating in a Gemini API response
 /// 
 /// Contains safety information about the response.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct SafetyRating {
-    #[allow(dead_code)]
     category: String,
-    #[allow(dead_code)]
-    probability: String,
+    probability: Option<String>,
+    blocked: Option<bool>,
+    /// Vertex AI only: the harm probability between 0.0 and 1.0
+    probability_score: Option<f64>,
+    /// Vertex AI only: the harm severity, e.g. `HARM_SEVERITY_LOW`
+    severity: Option<String>,
+    /// Vertex AI only: the harm severity between 0.0 and 1.0
+    severity_score: Option<f64>,
+}
+
+/// Token usage metadata in a Gemini API response
+/// 
+/// Contains the number of tokens consumed by the prompt and the response.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    prompt_token_count: Option<u32>,
+    candidates_token_count: Option<u32>,
+    total_token_count: Option<u32>,
+    cached_content_token_count: Option<u32>,
+    thoughts_token_count: Option<u32>,
+    tool_use_prompt_token_count: Option<u32>,
+}
+
+impl UsageMetadata {
+    /// Adds the token counts of another request, e.g. a continuation
+    fn add(&mut self, other: &UsageMetadata) {
+        let sum = |a: Option<u32>, b: Option<u32>| match (a, b) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+        };
+        self.prompt_token_count = sum(self.prompt_token_count, other.prompt_token_count);
+        self.candidates_token_count = sum(self.candidates_token_count, other.candidates_token_count);
+        self.total_token_count = sum(self.total_token_count, other.total_token_count);
+        self.cached_content_token_count = sum(self.cached_content_token_count, other.cached_content_token_count);
+        self.thoughts_token_count = sum(self.thoughts_token_count, other.thoughts_token_count);
+        self.tool_use_prompt_token_count = sum(self.tool_use_prompt_token_count, other.tool_use_prompt_token_count);
+    }
+}
+
+/// Citation metadata in a Gemini API response
+/// 
+/// Lists the sources a candidate recited content from.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CitationMetadata {
+    #[serde(default, alias = "citations")]
+    citation_sources: Vec<CitationSource>,
+}
+
+/// Citation source in a Gemini API response
+/// 
+/// Contains the attributed span of the candidate and where it came from.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CitationSource {
+    start_index: Option<u32>,
+    end_index: Option<u32>,
+    uri: Option<String>,
+    license: Option<String>,
+}
+
+/// Grounding metadata in a Gemini API response
+/// 
+/// Contains the search queries and web sources used to ground a candidate.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GroundingMetadata {
+    #[serde(default)]
+    web_search_queries: Vec<String>,
+    #[serde(default)]
+    grounding_chunks: Vec<GroundingChunk>,
+    #[serde(default)]
+    grounding_supports: Vec<GroundingSupport>,
+}
+
+/// Grounding chunk in a Gemini API response
+/// 
+/// A source the grounded answer was built from.
+#[derive(Debug, Serialize, Deserialize)]
+struct GroundingChunk {
+    web: Option<WebSource>,
+}
+
+/// Web source of a grounding chunk
+#[derive(Debug, Serialize, Deserialize)]
+struct WebSource {
+    uri: Option<String>,
+    title: Option<String>,
+}
+
+/// Grounding support in a Gemini API response
+/// 
+/// Links a segment of the answer to the grounding chunks supporting it.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GroundingSupport {
+    segment: Option<Segment>,
+    #[serde(default)]
+    grounding_chunk_indices: Vec<usize>,
+}
+
+/// Segment of a candidate's text referenced by grounding support
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Segment {
+    /// Index of the part holding the segment
+    #[serde(default)]
+    part_index: usize,
+    /// Byte offset in the part's text where the segment ends, exclusive
+    end_index: Option<usize>,
 }
 
 /// Response structure for the Gemini chat mode
 /// 
 /// Contains the user message and commands to execute.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 struct GeminiResponse {
+    /// Commands to execute, in order
     #[serde(default)]
     commands: Vec<GeminiCommand>,
+    /// Message shown to the user after the commands have been executed
     #[serde(default)]
     user_message: String,
 }
@@ -205,13 +818,35 @@ struct GeminiResponse {
 /// - CreateFile: Create a file with content
 /// - ExecuteCommand: Execute a shell command
 /// - WriteCodeToFile: Write code to a file (similar to CreateFile but with a different field name)
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum GeminiCommand {
-    CreateFolder { path: String },
-    CreateFile { path: String, content: String },
-    WriteCodeToFile { path: String, code: String },
-    ExecuteCommand { command: String, args: Vec<String> },
+    /// Create a directory, including any missing parent directories.
+    CreateFolder {
+        /// Relative path of the folder
+        path: String,
+    },
+    /// Create a file with the given content, replacing it if it exists.
+    CreateFile {
+        /// Relative path of the file
+        path: String,
+        /// Full content of the file
+        content: String,
+    },
+    /// Write source code to a file, replacing it if it exists.
+    WriteCodeToFile {
+        /// Relative path of the file
+        path: String,
+        /// Full source code of the file
+        code: String,
+    },
+    /// Execute a program with arguments on the user's machine and return its output.
+    ExecuteCommand {
+        /// The program to run
+        command: String,
+        /// Arguments passed to the program
+        args: Vec<String>,
+    },
 }
 
 /// Status of a command execution
@@ -234,1020 +869,7141 @@ struct CommandFeedback {
     message: String,
 }
 
-/// Gets the Gemini model name from environment variable or uses the default
+/// Converts a JSON schema generated from a Rust type into Gemini's schema format
 ///
-/// # Returns
+/// Gemini accepts a subset of OpenAPI: `$ref`s are inlined, `oneOf` becomes
+/// `anyOf`, `const` becomes a single-value `enum`, and unsupported keywords such
+/// as `$schema`, `title` and `default` are dropped. Property order follows the
+/// `required` list so the model writes fields in declaration order.
 ///
-/// * `String` - The Gemini model name
-fn get_gemini_model() -> String {
-    env::var(GEMINI_MODEL_ENV_VAR)
-        .unwrap_or_else(|_| DEFAULT_GEMINI_MODEL.to_string())
-}
-
-/// Gets the Gemini API endpoint from environment variable or constructs it from the model name
+/// # Arguments
+///
+/// * `schema` - The schema generated by `schemars`
 ///
 /// # Returns
 ///
-/// * `String` - The Gemini API endpoint URL
-fn get_gemini_api_endpoint() -> String {
-    env::var(GEMINI_API_ENDPOINT_ENV_VAR)
-        .unwrap_or_else(|_| {
-            let model = get_gemini_model();
-            format!("{}/{model}:generateContent", GEMINI_API_BASE_URL)
-        })
+/// * `serde_json::Value` - The schema in Gemini's format
+fn gemini_schema_for(schema: schemars::Schema) -> serde_json::Value {
+    let schema = schema.to_value();
+    let definitions = schema.get("$defs").cloned().unwrap_or_default();
+    convert_schema(&schema, &definitions)
 }
 
-/// Communicates with the Gemini API in chat mode
+/// Recursively converts one schema node, see [`gemini_schema_for`]
+fn convert_schema(node: &serde_json::Value, definitions: &serde_json::Value) -> serde_json::Value {
+    let Some(object) = node.as_object() else {
+        return node.clone();
+    };
+
+    if let Some(reference) = object.get("$ref").and_then(|r| r.as_str()) {
+        let name = reference.trim_start_matches("#/$defs/");
+        let mut resolved = definitions.get(name).cloned().unwrap_or_default();
+        // Keep a description given next to the reference
+        if let (Some(description), Some(target)) = (object.get("description"), resolved.as_object_mut()) {
+            target.insert("description".to_string(), description.clone());
+        }
+        return convert_schema(&resolved, definitions);
+    }
+
+    let mut converted = serde_json::Map::new();
+    for (key, value) in object {
+        match key.as_str() {
+            "type" => match value {
+                // ["string", "null"] is expressed with `nullable` in Gemini's format
+                serde_json::Value::Array(types) => {
+                    if let Some(first) = types.iter().find(|t| *t != "null") {
+                        converted.insert("type".to_string(), first.clone());
+                    }
+                    if types.iter().any(|t| t == "null") {
+                        converted.insert("nullable".to_string(), json!(true));
+                    }
+                }
+                _ => {
+                    converted.insert("type".to_string(), value.clone());
+                }
+            },
+            "const" => {
+                converted.insert("enum".to_string(), json!([value]));
+            }
+            "oneOf" | "anyOf" => {
+                let variants = value
+                    .as_array()
+                    .map(|variants| variants.iter().map(|v| convert_schema(v, definitions)).collect())
+                    .unwrap_or_default();
+                converted.insert("anyOf".to_string(), serde_json::Value::Array(variants));
+            }
+            "properties" => {
+                let properties = value
+                    .as_object()
+                    .map(|properties| {
+                        properties
+                            .iter()
+                            .map(|(name, property)| (name.clone(), convert_schema(property, definitions)))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                converted.insert("properties".to_string(), serde_json::Value::Object(properties));
+            }
+            "items" => {
+                converted.insert("items".to_string(), convert_schema(value, definitions));
+            }
+            "description" | "enum" | "required" | "format" | "minItems" | "maxItems" => {
+                converted.insert(key.clone(), value.clone());
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(properties) = converted.get("properties").and_then(|p| p.as_object()) {
+        let required: Vec<&str> = converted
+            .get("required")
+            .and_then(|r| r.as_array())
+            .map(|r| r.iter().filter_map(|n| n.as_str()).collect())
+            .unwrap_or_default();
+        let mut ordering: Vec<serde_json::Value> = required.iter().map(|n| json!(n)).collect();
+        ordering.extend(
+            properties
+                .keys()
+                .filter(|name| !required.contains(&name.as_str()))
+                .map(|name| json!(name)),
+        );
+        converted.insert("propertyOrdering".to_string(), serde_json::Value::Array(ordering));
+    }
+
+    serde_json::Value::Object(converted)
+}
+
+/// Converts a schema in Gemini's format back into plain JSON Schema
 ///
-/// Sends a query to the Gemini 2.0 Flash Thinking model and returns the response.
+/// Chat completions APIs expect standard JSON Schema, so Gemini's
+/// `propertyOrdering` is dropped and a `nullable` type becomes a
+/// `["type", "null"]` union again.
 ///
 /// # Arguments
 ///
-/// * `query` - The user's query to send to Gemini
-/// * `system_info` - System information to include in the prompt
-/// * `api_key` - The Gemini API key
-/// * `feedback` - Feedback from previous command executions
+/// * `schema` - The schema built by [`gemini_schema_for`]
 ///
 /// # Returns
 ///
-/// * `Result<GeminiApiResponse, AppError>` - The API response or an error
-async fn chat_with_gemini(
-    query: &str,
-    system_info: &str,
-    api_key: &str,
-    feedback: &str,
-) -> Result<GeminiApiResponse, AppError> {
-    let client = Client::new();
-    let gemini_api_endpoint = get_gemini_api_endpoint();
+/// * `serde_json::Value` - The schema as plain JSON Schema
+fn plain_json_schema(schema: &serde_json::Value) -> serde_json::Value {
+    match schema {
+        serde_json::Value::Object(object) => {
+            let mut converted = serde_json::Map::new();
+            for (key, value) in object {
+                match key.as_str() {
+                    "propertyOrdering" | "nullable" => {}
+                    // Property names are kept as they are, only their schemas are converted
+                    "properties" => {
+                        let properties = value
+                            .as_object()
+                            .map(|properties| {
+                                properties
+                                    .iter()
+                                    .map(|(name, property)| (name.clone(), plain_json_schema(property)))
+                                    .collect()
+                            })
+                            .unwrap_or_default();
+                        converted.insert(key.clone(), serde_json::Value::Object(properties));
+                    }
+                    _ => {
+                        converted.insert(key.clone(), plain_json_schema(value));
+                    }
+                }
+            }
+            if object.get("nullable") == Some(&json!(true)) {
+                if let Some(schema_type) = converted.get("type").cloned() {
+                    converted.insert("type".to_string(), json!([schema_type, "null"]));
+                }
+            }
+            serde_json::Value::Object(converted)
+        }
+        serde_json::Value::Array(items) => serde_json::Value::Array(items.iter().map(plain_json_schema).collect()),
+        other => other.clone(),
+    }
+}
 
-    let prompt_content = format!(
-        "You are a helpful coding assistant. You will receive system information and user queries. Respond with a JSON object containing 'commands' and 'user_message'. 'commands' is an array of command objects, each with a 'type' and command-specific fields. Supported commands:\n- 'create_folder': {{ \"type\": \"create_folder\", \"path\": \"<folder_path>\" }}\n- 'create_file': {{ \"type\": \"create_file\", \"path\": \"<file_path>\" }}\n- 'write_code_to_file': {{ \"type\": \"write_code_to_file\", \"path\": \"<file_path>\", \"code\": \"<code_string>\" }}\n- 'execute_command': {{ \"type\": \"execute_command\", \"command\": \"<command_string>\" }}\n'user_message' is a string for user feedback after execution.\n\n**Feedback Loop:** After I execute your commands, I will provide feedback on their success or failure in subsequent queries. Use this feedback to improve your command generation. If a command fails, try to correct it or adjust your approach in the next turn.\n\nExample response for 'please build a hello-world python app for me':\n{{\n  \"commands\": [\n    {{\"type\": \"create_folder\", \"path\": \"user_projects\"}},\n    {{\"type\": \"create_file\", \"path\": \"user_projects/hello_world.py\"}},\n    {{\"type\": \"write_code_to_file\", \"path\": \"user_projects/hello_world.py\", \"code\": \"print('Hello, World!')\"}},\n    {{\"type\": \"execute_command\", \"command\": \"python user_projects/hello_world.py\"}}\n  ],\n  \"user_message\": \"Here is a hello-world Python app in 'user_projects'. It has been created and executed.\" \n}}\n\nSystem Information:\n{}\n\nPrevious Command Feedback (if any):\n{}\n\nUser Query:\n{}",
-        system_info, feedback, query
-    );
+impl GeminiResponse {
+    /// Gets the schema Gemini must follow when asked for structured chat output
+    fn response_schema() -> serde_json::Value {
+        gemini_schema_for(schemars::schema_for!(GeminiResponse))
+    }
+}
 
-    let request_body = json!({
-        "contents": [{
-            "parts": [{"text": prompt_content}]
-        }]
-    });
+impl GeminiCommand {
+    /// Declares every command as a function Gemini can call
+    ///
+    /// The declarations are derived from the variants' schema, so the names and
+    /// arguments always match what [`GeminiCommand::from_function_call`] accepts.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<FunctionDeclaration>` - One declaration per command variant
+    fn function_declarations() -> Vec<FunctionDeclaration> {
+        let schema = gemini_schema_for(schemars::schema_for!(GeminiCommand));
+        let variants = schema
+            .get("anyOf")
+            .and_then(|variants| variants.as_array())
+            .cloned()
+            .unwrap_or_default();
+
+        variants
+            .into_iter()
+            .filter_map(|mut variant| {
+                let name = variant
+                    .pointer("/properties/type/enum/0")?
+                    .as_str()?
+                    .to_string();
+                let description = variant
+                    .get("description")
+                    .and_then(|d| d.as_str())
+                    .unwrap_or_default()
+                    .to_string();
+
+                // The tag becomes the function name, the remaining fields its parameters
+                let object = variant.as_object_mut()?;
+                object.remove("description");
+                if let Some(properties) = object.get_mut("properties").and_then(|p| p.as_object_mut()) {
+                    properties.remove("type");
+                }
+                for key in ["required", "propertyOrdering"] {
+                    if let Some(names) = object.get_mut(key).and_then(|r| r.as_array_mut()) {
+                        names.retain(|n| n != "type");
+                    }
+                }
+
+                Some(FunctionDeclaration {
+                    name,
+                    description,
+                    parameters: variant,
+                })
+            })
+            .collect()
+    }
 
-    info!("Sending request to Gemini Pro API...");
+    /// Describes the commands for the JSON prompt, one line per command
+    ///
+    /// # Returns
+    ///
+    /// * `String` - Lines like `- 'create_folder': { "type": "create_folder", "path": "<path>" }`
+    fn prompt_spec() -> String {
+        GeminiCommand::function_declarations()
+            .iter()
+            .map(|declaration| {
+                let mut fields = vec![format!("\"type\": \"{}\"", declaration.name)];
+                let properties = declaration.parameters.get("properties").and_then(|p| p.as_object());
+                let ordering = declaration
+                    .parameters
+                    .get("propertyOrdering")
+                    .and_then(|o| o.as_array())
+                    .cloned()
+                    .unwrap_or_default();
 
-    let response = client
-        .post(gemini_api_endpoint)
-        .header("Content-Type", "application/json")
-        .query(&[("key", api_key)])
-        .json(&request_body)
-        .send()
-        .await?;
+                for name in ordering.iter().filter_map(|n| n.as_str()) {
+                    let is_array = properties
+                        .and_then(|p| p.get(name))
+                        .and_then(|p| p.get("type"))
+                        .is_some_and(|t| t == "array");
+                    if is_array {
+                        fields.push(format!("\"{}\": [\"<{}>\"]", name, name));
+                    } else {
+                        fields.push(format!("\"{}\": \"<{}>\"", name, name));
+                    }
+                }
 
-    let status = response.status();
-    let response_text = response.text().await?;
+                format!(
+                    "- '{}': {{ {} }} ({})",
+                    declaration.name,
+                    fields.join(", "),
+                    declaration.description.trim_end_matches('.')
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
 
-    info!("API Response Status: {}", status);
+    /// Converts a function call from Gemini into a command
+    ///
+    /// # Arguments
+    ///
+    /// * `call` - The function call
+    ///
+    /// # Returns
+    ///
+    /// * `Result<GeminiCommand, AppError>` - The command or an error for unknown functions and invalid arguments
+    fn from_function_call(call: &FunctionCall) -> Result<Self, AppError> {
+        let mut object = match &call.args {
+            serde_json::Value::Object(map) => map.clone(),
+            serde_json::Value::Null => serde_json::Map::new(),
+            other => {
+                return Err(AppError::ResponseError(format!(
+                    "Arguments of function {} are not an object: {}",
+                    call.name, other
+                )))
+            }
+        };
+        object.insert("type".to_string(), serde_json::Value::String(call.name.clone()));
 
-    if !status.is_success() {
-        error!("API Error Response: {}", response_text);
-        return Err(AppError::ApiError(format!(
-            "API request failed with status {}: {}",
-            status, response_text
-        )));
+        serde_json::from_value(serde_json::Value::Object(object)).map_err(|e| {
+            AppError::ResponseError(format!("Invalid call to function {}: {}", call.name, e))
+        })
     }
+}
 
-    match serde_json::from_str::<GeminiApiResponse>(&response_text) {
-        Ok(api_response) => Ok(api_response),
-        Err(e) => {
-            error!("Failed to parse API response: {}", e);
-            error!("Response text: {}", response_text);
-            Err(AppError::JsonParseError(e))
+impl CommandFeedback {
+    /// Wraps the feedback as the `functionResponse` part answering a function call
+    ///
+    /// # Arguments
+    ///
+    /// * `call` - The function call this feedback belongs to
+    ///
+    /// # Returns
+    ///
+    /// * `Part` - The function response part
+    fn to_function_response(&self, call: &FunctionCall) -> Part {
+        Part::FunctionResponse {
+            function_response: FunctionResponse {
+                id: call.id.clone(),
+                name: call.name.clone(),
+                response: json!({
+                    "status": self.status,
+                    "details": self.command_details,
+                    "message": self.message,
+                }),
+            },
         }
     }
 }
 
-/// Communicates with the Gemini API in execute mode
+/// Gets the Gemini model name from environment variable or uses the default
+///
+/// # Returns
+///
+/// * `String` - The Gemini model name
+fn get_gemini_model() -> String {
+    env::var(GEMINI_MODEL_ENV_VAR)
+        .unwrap_or_else(|_| DEFAULT_GEMINI_MODEL.to_string())
+}
+
+/// Splits an API endpoint into its root URL and the model it names, if any
 ///
-/// Sends a query to the Gemini 2.0 Flash Thinking model for code execution.
+/// Accepts a root such as `https://host/v1beta` as well as a full
+/// `https://host/v1beta/models/<model>:generateContent` URL.
 ///
 /// # Arguments
 ///
-/// * `query` - The user's query to send to Gemini
-/// * `api_key` - The Gemini API key
+/// * `endpoint` - The endpoint URL
 ///
 /// # Returns
 ///
-/// * `Result<GeminiApiResponse, AppError>` - The API response or an error
-async fn execute_with_gemini(
-    query: &str,
-    api_key: &str,
-) -> Result<GeminiApiResponse, AppError> {
-    let client = Client::new();
-    let gemini_api_endpoint = get_gemini_api_endpoint();
-
-    let request_body = json!({
-        "tools": [{"code_execution": {}}],
-        "contents": [
-            {
-                "role": "user",
-                "parts": [{"text": query}]
-            }
-        ]
-    });
-
-    info!("Sending request to Gemini API...");
-
-    let response = client
-        .post(gemini_api_endpoint)
-        .header("Content-Type", "application/json")
-        .query(&[("key", api_key)])
-        .json(&request_body)
-        .send()
-        .await?;
-
-    let status = response.status();
-    let response_text = response.text().await?;
-
-    info!("API Response Status: {}", status);
-
-    if !status.is_success() {
-        error!("API Error Response: {}", response_text);
-        return Err(AppError::ApiError(format!(
-            "API request failed with status {}: {}",
-            status, response_text
-        )));
+/// * `(String, Option<String>)` - The root URL and the model name
+fn parse_api_endpoint(endpoint: &str) -> (String, Option<String>) {
+    match endpoint.split_once("/models/") {
+        Some((root, model)) => {
+            let model = model.split(':').next().unwrap_or_default();
+            (root.to_string(), Some(model.to_string()).filter(|model| !model.is_empty()))
+        }
+        None => (endpoint.trim_end_matches('/').to_string(), None),
     }
+}
 
-    info!("API Response received. Processing...");
-
-    match serde_json::from_str::<GeminiApiResponse>(&response_text) {
-        Ok(api_response) => Ok(api_response),
-        Err(e) => {
-            error!("Failed to parse API response: {}", e);
-            error!("Response text: {}", response_text);
-            Err(AppError::JsonParseError(e))
+/// Gets the API root URL and model from the `GEMINI_API_ENDPOINT` environment variable
+///
+/// # Returns
+///
+/// * `(Option<String>, Option<String>)` - The root URL and the model named in it, if set
+fn get_gemini_api_endpoint() -> (Option<String>, Option<String>) {
+    match env::var(GEMINI_API_ENDPOINT_ENV_VAR) {
+        Ok(endpoint) => {
+            let (root, model) = parse_api_endpoint(&endpoint);
+            (Some(root), model)
         }
+        Err(_) => (None, None),
     }
 }
 
-/// Creates a codebase using the Gemini API
-///
-/// Sends a description to the Gemini 2.0 Flash Thinking model to generate a complete codebase.
+/// Gets the Vertex AI API root for a location
 ///
 /// # Arguments
 ///
-/// * `description` - Description of the codebase to create
-/// * `output_dir` - Directory where the codebase will be created
-/// * `api_key` - The Gemini API key
+/// * `location` - A region such as `us-central1`, or `global`
 ///
 /// # Returns
 ///
-/// * `Result<GeminiApiResponse, AppError>` - The API response or an error
-async fn create_codebase_with_gemini(
-    description: &str,
+/// * `String` - The API root URL, e.g. `https://us-central1-aiplatform.googleapis.com/v1`
+fn vertex_api_root(location: &str) -> String {
+    match location {
+        "global" => format!("https://aiplatform.googleapis.com/{}", VERTEX_API_VERSION),
+        region => format!("https://{}-aiplatform.googleapis.com/{}", region, VERTEX_API_VERSION),
+    }
+}
+
+/// Gets the Gemini API key from the environment
+///
+/// # Returns
+///
+/// * `Result<String, AppError>` - The API key or an error if it is not set
+fn get_gemini_api_key() -> Result<String, AppError> {
+    env::var(GEMINI_API_KEY_ENV_VAR).map_err(|_| {
+        error!("{} environment variable not set", GEMINI_API_KEY_ENV_VAR);
+        AppError::EnvError(format!(
+            "{} environment variable not set. Please set it with: export {}=your_api_key_here",
+            GEMINI_API_KEY_ENV_VAR, GEMINI_API_KEY_ENV_VAR
+        ))
+    })
+}
+
+/// Generation settings sent with a request
+///
+/// Unset fields are omitted from the request body so the API defaults apply.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    candidate_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_schema: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking_config: Option<ThinkingConfig>,
+}
+
+/// Settings for the model's internal reasoning
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ThinkingConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking_budget: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    include_thoughts: Option<bool>,
+}
+
+impl GenerationConfig {
+    /// Fills the unset fields of this config from `defaults`
+    ///
+    /// # Arguments
+    ///
+    /// * `defaults` - The settings to fall back to
+    ///
+    /// # Returns
+    ///
+    /// * `GenerationConfig` - The merged settings
+    fn or(self, defaults: &GenerationConfig) -> GenerationConfig {
+        GenerationConfig {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            top_k: self.top_k.or(defaults.top_k),
+            max_output_tokens: self.max_output_tokens.or(defaults.max_output_tokens),
+            candidate_count: self.candidate_count.or(defaults.candidate_count),
+            stop_sequences: self.stop_sequences.or_else(|| defaults.stop_sequences.clone()),
+            seed: self.seed.or(defaults.seed),
+            response_mime_type: self.response_mime_type.or_else(|| defaults.response_mime_type.clone()),
+            response_schema: self.response_schema.or_else(|| defaults.response_schema.clone()),
+            thinking_config: self.thinking_config.or_else(|| defaults.thinking_config.clone()),
+        }
+    }
+}
+
+/// User-facing generation settings, set with CLI flags or in the configuration file
+///
+/// Flags take precedence over the configuration file; settings left unset in
+/// both are not sent, so the API defaults apply.
+#[derive(Debug, Clone, Default, PartialEq, clap::Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct GenerationSettings {
+    /// Sampling temperature between 0.0 and 2.0
+    #[arg(long, global = true)]
+    temperature: Option<f64>,
+    /// Nucleus sampling probability between 0.0 and 1.0
+    #[arg(long, global = true)]
+    top_p: Option<f64>,
+    /// Number of most likely tokens considered when sampling
+    #[arg(long, global = true)]
+    top_k: Option<u32>,
+    /// Maximum number of tokens in a response
+    #[arg(long, global = true)]
+    max_output_tokens: Option<u32>,
+    /// Number of response candidates to generate, see --select
+    #[arg(long, global = true, visible_alias = "candidates")]
+    candidate_count: Option<u32>,
+    /// Stop generating at this sequence (repeat for up to 5 sequences)
+    #[arg(long = "stop-sequence", global = true)]
+    stop_sequences: Option<Vec<String>>,
+    /// Seed for more reproducible sampling
+    #[arg(long, global = true)]
+    seed: Option<i64>,
+    /// Token budget for thinking models (0 disables thinking, -1 lets the model decide)
+    #[arg(long, global = true, allow_negative_numbers = true)]
+    thinking_budget: Option<i32>,
+    /// Ask thinking models to return summaries of their thoughts
+    #[arg(long, global = true, num_args = 0..=1, default_missing_value = "true")]
+    include_thoughts: Option<bool>,
+}
+
+impl GenerationSettings {
+    /// Fills the unset settings from `defaults`
+    ///
+    /// # Arguments
+    ///
+    /// * `defaults` - The settings to fall back to, usually from the configuration file
+    ///
+    /// # Returns
+    ///
+    /// * `GenerationSettings` - The merged settings
+    fn or(self, defaults: &GenerationSettings) -> GenerationSettings {
+        GenerationSettings {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            top_k: self.top_k.or(defaults.top_k),
+            max_output_tokens: self.max_output_tokens.or(defaults.max_output_tokens),
+            candidate_count: self.candidate_count.or(defaults.candidate_count),
+            stop_sequences: self.stop_sequences.or_else(|| defaults.stop_sequences.clone()),
+            seed: self.seed.or(defaults.seed),
+            thinking_budget: self.thinking_budget.or(defaults.thinking_budget),
+            include_thoughts: self.include_thoughts.or(defaults.include_thoughts),
+        }
+    }
+
+    /// Checks that the settings are within the ranges accepted by the API
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), AppError>` - An error describing the first invalid setting
+    fn validate(&self) -> Result<(), AppError> {
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                return Err(AppError::ConfigError(format!("temperature must be between 0.0 and 2.0, got {}", temperature)));
+            }
+        }
+        if let Some(top_p) = self.top_p {
+            if !(0.0..=1.0).contains(&top_p) {
+                return Err(AppError::ConfigError(format!("top_p must be between 0.0 and 1.0, got {}", top_p)));
+            }
+        }
+        if self.top_k == Some(0) {
+            return Err(AppError::ConfigError("top_k must be at least 1".to_string()));
+        }
+        if self.max_output_tokens == Some(0) {
+            return Err(AppError::ConfigError("max_output_tokens must be at least 1".to_string()));
+        }
+        if self.candidate_count == Some(0) {
+            return Err(AppError::ConfigError("candidate_count must be at least 1".to_string()));
+        }
+        if let Some(stop_sequences) = &self.stop_sequences {
+            if stop_sequences.len() > 5 {
+                return Err(AppError::ConfigError(format!("at most 5 stop sequences are allowed, got {}", stop_sequences.len())));
+            }
+        }
+        if let Some(thinking_budget) = self.thinking_budget {
+            if thinking_budget < -1 {
+                return Err(AppError::ConfigError(format!("thinking_budget must be -1 or more, got {}", thinking_budget)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Converts the settings into the generation config sent to the API
+    fn to_generation_config(&self) -> GenerationConfig {
+        GenerationConfig {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            max_output_tokens: self.max_output_tokens,
+            candidate_count: self.candidate_count,
+            stop_sequences: self.stop_sequences.clone(),
+            seed: self.seed,
+            thinking_config: (self.thinking_budget.is_some() || self.include_thoughts.is_some()).then_some(
+                ThinkingConfig {
+                    thinking_budget: self.thinking_budget,
+                    include_thoughts: self.include_thoughts,
+                },
+            ),
+            ..Default::default()
+        }
+    }
+}
+
+/// Contents of the JSON configuration file
+///
+/// Every section is optional; CLI flags override the values set here.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    /// Default generation settings for every request
+    generation: GenerationSettings,
+    /// Maximum number of input tokens the model accepts
+    input_token_limit: Option<u32>,
+    /// API backend and its credentials
+    backend: BackendConfig,
+    /// Timeouts, proxy and TLS settings of the HTTP client
+    http: HttpConfig,
+    /// Client-side limits for requests and tokens per minute
+    rate_limits: RateLimitConfig,
+}
+
+/// Client-side rate limits, e.g. to stay within the free tier quota
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RateLimitConfig {
+    /// Limits by model name; `*` applies to models without their own entry
+    models: HashMap<String, ModelRateLimit>,
+    /// Share the limits with other processes through a state file
+    shared: bool,
+    /// Directory of the shared state file, defaults to the user's cache directory
+    state_dir: Option<String>,
+}
+
+/// Requests and input tokens per minute allowed for a model
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModelRateLimit {
+    requests_per_minute: Option<u32>,
+    tokens_per_minute: Option<u32>,
+}
+
+/// HTTP transport settings shared by every API call
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HttpConfig {
+    /// Seconds to wait for a connection, including the TLS handshake [default: 30]
+    connect_timeout_secs: Option<u64>,
+    /// Seconds to wait for a response to start and between chunks of a stream [default: 600]
+    read_timeout_secs: Option<u64>,
+    /// Seconds a whole request may take, including reading the response
+    timeout_secs: Option<u64>,
+    /// Proxy for all requests; without it `HTTPS_PROXY`, `HTTP_PROXY` and `ALL_PROXY` are used
+    proxy: Option<String>,
+    /// Comma-separated hosts that bypass `proxy`, defaults to `NO_PROXY`
+    no_proxy: Option<String>,
+    /// PEM file with additional CA certificates to trust, e.g. of a TLS-intercepting proxy
+    ca_bundle: Option<String>,
+    /// User agent sent with every request
+    user_agent: Option<String>,
+}
+
+/// API backend selected in the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum BackendConfig {
+    /// Gemini Developer API with the key from `GEMINI_API_KEY`
+    #[default]
+    Gemini,
+    /// Vertex AI with OAuth access tokens
+    Vertex {
+        /// Google Cloud project, defaults to `GOOGLE_CLOUD_PROJECT` or the credentials' project
+        project: Option<String>,
+        /// Region such as `us-central1`, or `global`
+        location: Option<String>,
+        /// Path to a service account key or `gcloud` application default credentials file
+        credentials: Option<String>,
+    },
+    /// OpenAI or another server with an OpenAI-compatible chat completions API, e.g. llama.cpp
+    #[serde(rename = "openai")]
+    OpenAi {
+        /// API root, defaults to `https://api.openai.com/v1`
+        base_url: Option<String>,
+        /// Model to use, defaults to `GEMINI_MODEL`
+        model: Option<String>,
+        /// Environment variable holding the API key, defaults to `OPENAI_API_KEY`
+        api_key_env: Option<String>,
+    },
+    /// Local Ollama server through its OpenAI-compatible API
+    Ollama {
+        /// API root, defaults to `http://localhost:11434/v1`
+        base_url: Option<String>,
+        /// Model to use, e.g. `qwen2.5-coder`; defaults to `GEMINI_MODEL`
+        model: Option<String>,
+    },
+}
+
+impl HttpConfig {
+    /// Checks that the timeouts are usable
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), AppError>` - An error naming the invalid setting
+    fn validate(&self) -> Result<(), AppError> {
+        for (name, value) in [
+            ("connect_timeout_secs", self.connect_timeout_secs),
+            ("read_timeout_secs", self.read_timeout_secs),
+            ("timeout_secs", self.timeout_secs),
+        ] {
+            if value == Some(0) {
+                return Err(AppError::ConfigError(format!("http.{} must be at least 1", name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the longest time to wait for data from the server
+    fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout_secs.unwrap_or(DEFAULT_READ_TIMEOUT_SECS))
+    }
+
+    /// Builds the HTTP client used for every API call
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Client, AppError>` - The client or an error for invalid proxy or certificate settings
+    fn client(&self) -> Result<Client, AppError> {
+        self.validate()?;
+
+        let mut builder = Client::builder()
+            .user_agent(self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT))
+            .connect_timeout(Duration::from_secs(
+                self.connect_timeout_secs.unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS),
+            ));
+        if let Some(seconds) = self.timeout_secs {
+            builder = builder.timeout(Duration::from_secs(seconds));
+        }
+
+        if let Some(url) = &self.proxy {
+            let no_proxy = match &self.no_proxy {
+                Some(hosts) => NoProxy::from_string(hosts),
+                None => NoProxy::from_env(),
+            };
+            let proxy = Proxy::all(url)
+                .map_err(|e| AppError::ConfigError(format!("Invalid proxy {}: {}", url, e)))?
+                .no_proxy(no_proxy);
+            debug!("Sending requests through proxy {}", url);
+            builder = builder.proxy(proxy);
+        }
+
+        if let Some(path) = &self.ca_bundle {
+            let pem = fs::read(path)
+                .map_err(|e| AppError::ConfigError(format!("Failed to read CA bundle {}: {}", path, e)))?;
+            let certificates = Certificate::from_pem_bundle(&pem)
+                .map_err(|e| AppError::ConfigError(format!("Invalid CA bundle {}: {}", path, e)))?;
+            if certificates.is_empty() {
+                return Err(AppError::ConfigError(format!("CA bundle {} contains no certificates", path)));
+            }
+            debug!("Trusting {} additional CA certificates from {}", certificates.len(), path);
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        builder
+            .build()
+            .map_err(|e| AppError::ConfigError(format!("Failed to create the HTTP client: {}", e)))
+    }
+}
+
+impl BackendConfig {
+    /// Gets the API keys of this backend found in the environment
+    ///
+    /// They are redacted from recorded interactions.
+    fn secrets(&self) -> Vec<String> {
+        let mut names = vec![GEMINI_API_KEY_ENV_VAR];
+        if let BackendConfig::OpenAi { api_key_env, .. } = self {
+            names.push(api_key_env.as_deref().unwrap_or(OPENAI_API_KEY_ENV_VAR));
+        }
+        names
+            .into_iter()
+            .filter_map(|name| env::var(name).ok())
+            .filter(|secret| !secret.is_empty())
+            .collect()
+    }
+}
+
+impl Config {
+    /// Loads the configuration from a JSON file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the JSON file
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Config, AppError>` - The configuration or an error
+    fn load(path: &str) -> Result<Self, AppError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| AppError::ConfigError(format!("Failed to read {}: {}", path, e)))?;
+        let config: Config = serde_json::from_str(&contents)
+            .map_err(|e| AppError::ConfigError(format!("Invalid configuration in {}: {}", path, e)))?;
+        debug!("Loaded configuration from {}", path);
+        Ok(config)
+    }
+
+    /// Loads the configuration from the given path or the `GEMINI_CONFIG` environment variable
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path given on the command line, if any
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Config, AppError>` - The configuration, empty if no file was given
+    fn resolve(path: Option<&str>) -> Result<Self, AppError> {
+        match path.map(str::to_string).or_else(|| env::var(GEMINI_CONFIG_ENV_VAR).ok()) {
+            Some(path) => Config::load(&path),
+            None => Ok(Config::default()),
+        }
+    }
+}
+
+/// Tool declaration in a Gemini API request
+///
+/// Either a built-in tool (code execution, Google Search) or a set of function declarations.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Tool {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code_execution: Option<CodeExecutionTool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    google_search: Option<GoogleSearchTool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    function_declarations: Vec<FunctionDeclaration>,
+}
+
+/// Declaration of a function the model may call
+///
+/// `parameters` is an OpenAPI schema object describing the arguments.
+#[derive(Debug, Clone, Serialize)]
+struct FunctionDeclaration {
+    name: String,
+    description: String,
+    parameters: serde_json::Value,
+}
+
+/// Marker for the built-in code execution tool (serialized as `{}`)
+#[derive(Debug, Clone, Default, Serialize)]
+struct CodeExecutionTool {}
+
+/// Marker for the built-in Google Search grounding tool (serialized as `{}`)
+#[derive(Debug, Clone, Default, Serialize)]
+struct GoogleSearchTool {}
+
+impl Tool {
+    /// Creates a tool entry enabling server-side code execution
+    fn code_execution() -> Self {
+        Tool {
+            code_execution: Some(CodeExecutionTool {}),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a tool entry grounding answers with Google Search
+    fn google_search() -> Self {
+        Tool {
+            google_search: Some(GoogleSearchTool {}),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a tool entry declaring the given functions
+    fn functions(function_declarations: Vec<FunctionDeclaration>) -> Self {
+        Tool {
+            function_declarations,
+            ..Default::default()
+        }
+    }
+}
+
+/// Request body for the `generateContent` endpoint
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateContentRequest {
+    contents: Vec<Content>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generation_config: Option<GenerationConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<Content>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cached_content: Option<String>,
+}
+
+/// Builds the `systemInstruction` of a request
+///
+/// # Arguments
+///
+/// * `persona` - Replaceable instructions describing the assistant, if any
+/// * `protocol` - Instructions the response parsing depends on, empty if none
+///
+/// # Returns
+///
+/// * `Option<Content>` - The system instruction, or `None` if both parts are empty
+fn system_instruction(persona: Option<&str>, protocol: &str) -> Option<Content> {
+    let text = [persona.unwrap_or_default().trim(), protocol.trim()]
+        .into_iter()
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    (!text.is_empty()).then(|| Content::text(None, text))
+}
+
+/// Retry policy for failed API requests
+///
+/// Rate limit (429) and server (5xx) errors as well as timeouts, failed
+/// connections and reset connections are retried with exponential backoff. A
+/// delay requested by the server through the `Retry-After` header or a
+/// `RetryInfo` error detail takes precedence; if it is longer than `max_delay`
+/// the request fails instead of waiting.
+#[derive(Debug, Clone)]
+struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+    /// Longest wait for a response to start, a timed out attempt is retried
+    read_timeout: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_RETRY_MAX_ATTEMPTS,
+            base_delay: Duration::from_millis(DEFAULT_RETRY_BASE_DELAY_MS),
+            max_delay: Duration::from_millis(DEFAULT_RETRY_MAX_DELAY_MS),
+            jitter: true,
+            read_timeout: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Computes the delay before the given retry
+    ///
+    /// # Arguments
+    ///
+    /// * `attempt` - The attempt that just failed, starting at 1
+    /// * `server_delay` - The delay requested by the server, if any
+    ///
+    /// # Returns
+    ///
+    /// * `Duration` - How long to wait before the next attempt
+    fn delay_for(&self, attempt: u32, server_delay: Option<Duration>) -> Duration {
+        if let Some(delay) = server_delay {
+            return delay;
+        }
+
+        let exponent = attempt.saturating_sub(1).min(16);
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+
+        if self.jitter {
+            // Full jitter in the upper half keeps concurrent clients from retrying in lockstep
+            let random = RandomState::new().build_hasher().finish();
+            let fraction = 0.5 + (random % 1000) as f64 / 2000.0;
+            delay.mul_f64(fraction)
+        } else {
+            delay
+        }
+    }
+
+    /// Sends a request and retries it on rate limits, transient server errors and connection problems
+    ///
+    /// # Arguments
+    ///
+    /// * `build` - Builds a fresh request for every attempt
+    ///
+    /// # Returns
+    ///
+    /// * `Result<reqwest::Response, AppError>` - The successful response or an error
+    async fn send<F, Fut>(&self, build: F) -> Result<reqwest::Response, AppError>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Result<reqwest::RequestBuilder, AppError>>,
+    {
+        self.send_accepting(build, &[]).await
+    }
+
+    /// Sends a request like [`RetryPolicy::send`], returning the given error statuses as responses
+    ///
+    /// # Arguments
+    ///
+    /// * `build` - Builds a fresh request for every attempt
+    /// * `accepted` - Error statuses the caller handles itself, e.g. 404 for a lookup
+    ///
+    /// # Returns
+    ///
+    /// * `Result<reqwest::Response, AppError>` - The successful or accepted response, or an error
+    async fn send_accepting<F, Fut>(&self, mut build: F, accepted: &[StatusCode]) -> Result<reqwest::Response, AppError>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Result<reqwest::RequestBuilder, AppError>>,
+    {
+        let max_attempts = self.max_attempts.max(1);
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+
+            let request = build().await?;
+            let result = match self.read_timeout {
+                Some(read_timeout) => match tokio::time::timeout(read_timeout, request.send()).await {
+                    Ok(result) => result,
+                    Err(_) if attempt < max_attempts => {
+                        let delay = self.delay_for(attempt, None);
+                        warn!(
+                            "No response within {}s (attempt {}/{}). Retrying in {:.1}s",
+                            read_timeout.as_secs_f64(), attempt, max_attempts, delay.as_secs_f64()
+                        );
+                        tokio::time::sleep(delay).await;
+                        continue;
+                    }
+                    Err(_) => {
+                        return Err(AppError::TimeoutError(format!(
+                            "No response within {}s after {} attempt(s)",
+                            read_timeout.as_secs_f64(), attempt
+                        )))
+                    }
+                },
+                None => request.send().await,
+            };
+
+            let response = match result {
+                Ok(response) => response,
+                Err(e) if attempt < max_attempts && is_retryable_transport_error(&e) => {
+                    let delay = self.delay_for(attempt, None);
+                    warn!(
+                        "Request failed (attempt {}/{}): {}. Retrying in {:.1}s",
+                        attempt, max_attempts, e, delay.as_secs_f64()
+                    );
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+                Err(e) => return Err(AppError::HttpError(e)),
+            };
+
+            let status = response.status();
+            info!("API Response Status: {}", status);
+
+            if status.is_success() || accepted.contains(&status) {
+                return Ok(response);
+            }
+
+            let retry_after = parse_retry_after(response.headers());
+            let response_text = response.text().await?;
+
+            if attempt < max_attempts && is_retryable_status(status) {
+                let server_delay = retry_after.or_else(|| parse_retry_info(&response_text));
+                if let Some(server_delay) = server_delay.filter(|delay| *delay > self.max_delay) {
+                    error!("API Error Response: {}", response_text);
+                    return Err(AppError::ApiError(format!(
+                        "API request failed with status {} and the server asked to retry in {:.0}s, longer than the maximum retry delay of {:.0}s: {}",
+                        status,
+                        server_delay.as_secs_f64(),
+                        self.max_delay.as_secs_f64(),
+                        response_text
+                    )));
+                }
+                let delay = self.delay_for(attempt, server_delay);
+                warn!(
+                    "API request failed with status {} (attempt {}/{}). Retrying in {:.1}s{}",
+                    status,
+                    attempt,
+                    max_attempts,
+                    delay.as_secs_f64(),
+                    if server_delay.is_some() { " as requested by the server" } else { "" }
+                );
+                debug!("Retryable API Error Response: {}", response_text);
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+
+            error!("API Error Response: {}", response_text);
+            return Err(AppError::ApiError(format!(
+                "API request failed with status {} after {} attempt(s): {}",
+                status, attempt, response_text
+            )));
+        }
+    }
+}
+
+/// Checks whether a failed request with the given status should be retried
+///
+/// # Arguments
+///
+/// * `status` - The HTTP status of the response
+///
+/// # Returns
+///
+/// * `bool` - True for rate limiting and transient server errors
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Checks whether a transport error should be retried
+///
+/// # Arguments
+///
+/// * `error` - The error returned by the HTTP client
+///
+/// # Returns
+///
+/// * `bool` - True for timeouts, failed connections and connections reset by the server
+fn is_retryable_transport_error(error: &reqwest::Error) -> bool {
+    error.is_timeout() || error.is_connect() || is_connection_reset(error)
+}
+
+/// Checks whether an error was caused by the connection being reset or closed mid-request
+///
+/// # Arguments
+///
+/// * `error` - The error, whose sources are searched for an I/O error
+///
+/// # Returns
+///
+/// * `bool` - True if the connection was reset, aborted or closed unexpectedly
+fn is_connection_reset(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<std::io::Error>() {
+            if matches!(
+                error.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::UnexpectedEof
+            ) {
+                return true;
+            }
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Reads from a response, failing when no data arrives within the read timeout
+///
+/// # Arguments
+///
+/// * `read` - The read, e.g. the next chunk of a stream
+/// * `read_timeout` - The longest time to wait, if any
+///
+/// # Returns
+///
+/// * `Result<T, AppError>` - The data read or an error
+async fn read_with_timeout<T>(
+    read: impl std::future::Future<Output = Result<T, reqwest::Error>>,
+    read_timeout: Option<Duration>,
+) -> Result<T, AppError> {
+    match read_timeout {
+        Some(read_timeout) => tokio::time::timeout(read_timeout, read)
+            .await
+            .map_err(|_| AppError::TimeoutError(format!("No data received for {}s", read_timeout.as_secs_f64())))?
+            .map_err(AppError::HttpError),
+        None => read.await.map_err(AppError::HttpError),
+    }
+}
+
+/// Parses a `Retry-After` header given in seconds
+///
+/// The HTTP-date form is not supported; it is logged and ignored, so the
+/// regular backoff applies.
+///
+/// # Arguments
+///
+/// * `headers` - The response headers
+///
+/// # Returns
+///
+/// * `Option<Duration>` - The requested delay, if present
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            warn!("Ignoring Retry-After header {:?}, only delays in seconds are supported", value);
+            None
+        }
+    }
+}
+
+/// Extracts the `retryDelay` of a `google.rpc.RetryInfo` detail from an error body
+///
+/// Gemini reports quota errors as `{"error": {"details": [{"@type": ".../google.rpc.RetryInfo", "retryDelay": "34s"}]}}`.
+///
+/// # Arguments
+///
+/// * `body` - The error response body
+///
+/// # Returns
+///
+/// * `Option<Duration>` - The requested delay, if present
+fn parse_retry_info(body: &str) -> Option<Duration> {
+    let value = serde_json::from_str::<serde_json::Value>(body).ok()?;
+    value
+        .pointer("/error/details")?
+        .as_array()?
+        .iter()
+        .filter(|detail| {
+            detail
+                .get("@type")
+                .and_then(|t| t.as_str())
+                .is_some_and(|t| t.ends_with("google.rpc.RetryInfo"))
+        })
+        .find_map(|detail| detail.get("retryDelay")?.as_str())
+        .and_then(|delay| delay.strip_suffix('s')?.parse::<f64>().ok())
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// Google credentials file, as written by the Cloud console or `gcloud auth application-default login`
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum GoogleCredentials {
+    /// Service account key, exchanged for tokens with a signed JWT
+    ServiceAccount {
+        client_email: String,
+        private_key: String,
+        token_uri: Option<String>,
+        project_id: Option<String>,
+    },
+    /// User credentials with a refresh token
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+        quota_project_id: Option<String>,
+    },
+}
+
+impl GoogleCredentials {
+    /// Loads credentials from a JSON file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the credentials file
+    ///
+    /// # Returns
+    ///
+    /// * `Result<GoogleCredentials, AppError>` - The credentials or an error
+    fn load(path: &str) -> Result<Self, AppError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| AppError::AuthError(format!("Failed to read credentials file {}: {}", path, e)))?;
+        serde_json::from_str(&contents).map_err(|e| {
+            AppError::AuthError(format!(
+                "Unsupported credentials in {}: {}. Expected a service account key or authorized user credentials",
+                path, e
+            ))
+        })
+    }
+
+    /// Gets the project the credentials belong to, if they name one
+    fn project(&self) -> Option<&str> {
+        match self {
+            GoogleCredentials::ServiceAccount { project_id, .. } => project_id.as_deref(),
+            GoogleCredentials::AuthorizedUser { quota_project_id, .. } => quota_project_id.as_deref(),
+        }
+    }
+}
+
+/// Finds the credentials file for Vertex AI
+///
+/// Uses the configured path, then `GOOGLE_APPLICATION_CREDENTIALS`, then the
+/// application default credentials written by `gcloud`.
+///
+/// # Arguments
+///
+/// * `configured` - Path from the configuration file, if any
+///
+/// # Returns
+///
+/// * `Result<String, AppError>` - The path or an error if no credentials were found
+fn find_google_credentials(configured: Option<&str>) -> Result<String, AppError> {
+    if let Some(path) = configured
+        .map(str::to_string)
+        .or_else(|| env::var(GOOGLE_APPLICATION_CREDENTIALS_ENV_VAR).ok())
+    {
+        return Ok(path);
+    }
+
+    let gcloud_dir = env::var("CLOUDSDK_CONFIG").ok().or_else(|| {
+        env::var("APPDATA")
+            .map(|dir| format!("{}/gcloud", dir))
+            .or_else(|_| env::var("HOME").map(|home| format!("{}/.config/gcloud", home)))
+            .ok()
+    });
+    gcloud_dir
+        .map(|dir| format!("{}/application_default_credentials.json", dir))
+        .filter(|path| Path::new(path).exists())
+        .ok_or_else(|| {
+            AppError::AuthError(format!(
+                "No Google credentials found. Set {} or run `gcloud auth application-default login`",
+                GOOGLE_APPLICATION_CREDENTIALS_ENV_VAR
+            ))
+        })
+}
+
+/// OAuth access token with its expiry
+#[derive(Debug, Clone)]
+struct AccessToken {
+    token: String,
+    expires_at: Instant,
+}
+
+/// Response of the OAuth token endpoint
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+/// Source of OAuth access tokens for Vertex AI
+///
+/// Tokens are cached and refreshed shortly before they expire. Clones of the
+/// client share the same cache.
+struct TokenSource {
+    credentials: GoogleCredentials,
+    cached: tokio::sync::Mutex<Option<AccessToken>>,
+}
+
+impl std::fmt::Debug for TokenSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.credentials {
+            GoogleCredentials::ServiceAccount { .. } => "service_account",
+            GoogleCredentials::AuthorizedUser { .. } => "authorized_user",
+        };
+        f.debug_struct("TokenSource").field("credentials", &kind).finish_non_exhaustive()
+    }
+}
+
+impl TokenSource {
+    /// Creates a token source for the given credentials
+    fn new(credentials: GoogleCredentials) -> Self {
+        TokenSource {
+            credentials,
+            cached: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Gets a valid access token, requesting a new one when the cached one is about to expire
+    ///
+    /// # Arguments
+    ///
+    /// * `http` - The HTTP client to reach the token endpoint with
+    ///
+    /// # Returns
+    ///
+    /// * `Result<String, AppError>` - The access token or an error
+    async fn access_token(&self, http: &Client) -> Result<String, AppError> {
+        let mut cached = self.cached.lock().await;
+        let refresh_at = Instant::now() + Duration::from_secs(ACCESS_TOKEN_REFRESH_MARGIN_SECS);
+        if let Some(token) = cached.as_ref().filter(|token| token.expires_at > refresh_at) {
+            return Ok(token.token.clone());
+        }
+
+        let (token_uri, form) = match &self.credentials {
+            GoogleCredentials::ServiceAccount { client_email, private_key, token_uri, .. } => {
+                let token_uri = token_uri.as_deref().unwrap_or(GOOGLE_OAUTH_TOKEN_URL);
+                let assertion = service_account_assertion(client_email, private_key, token_uri, unix_time())?;
+                (
+                    token_uri,
+                    vec![
+                        ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer".to_string()),
+                        ("assertion", assertion),
+                    ],
+                )
+            }
+            GoogleCredentials::AuthorizedUser { client_id, client_secret, refresh_token, .. } => (
+                GOOGLE_OAUTH_TOKEN_URL,
+                vec![
+                    ("grant_type", "refresh_token".to_string()),
+                    ("client_id", client_id.clone()),
+                    ("client_secret", client_secret.clone()),
+                    ("refresh_token", refresh_token.clone()),
+                ],
+            ),
+        };
+
+        debug!("Requesting an access token from {}", token_uri);
+        let response = http.post(token_uri).form(&form).send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            return Err(AppError::AuthError(format!(
+                "Token request failed with status {}: {}",
+                status,
+                response.text().await?
+            )));
+        }
+        let response: TokenResponse = response.json().await?;
+
+        let lifetime = Duration::from_secs(response.expires_in.unwrap_or(ACCESS_TOKEN_LIFETIME_SECS));
+        info!("Obtained an access token valid for {} s", lifetime.as_secs());
+        *cached = Some(AccessToken {
+            token: response.access_token.clone(),
+            expires_at: Instant::now() + lifetime,
+        });
+        Ok(response.access_token)
+    }
+}
+
+/// Gets the current time in seconds since the Unix epoch
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default()
+}
+
+/// Signs the JWT a service account exchanges for an access token
+///
+/// # Arguments
+///
+/// * `client_email` - The service account's email address
+/// * `private_key` - The service account's PEM encoded RSA key
+/// * `token_uri` - The token endpoint, which is the JWT's audience
+/// * `now` - The current time in seconds since the Unix epoch
+///
+/// # Returns
+///
+/// * `Result<String, AppError>` - The signed JWT or an error
+fn service_account_assertion(client_email: &str, private_key: &str, token_uri: &str, now: u64) -> Result<String, AppError> {
+    let claims = json!({
+        "iss": client_email,
+        "scope": CLOUD_PLATFORM_SCOPE,
+        "aud": token_uri,
+        "iat": now,
+        "exp": now + ACCESS_TOKEN_LIFETIME_SECS,
+    });
+    let key = jsonwebtoken::EncodingKey::from_rsa_pem(private_key.as_bytes())
+        .map_err(|e| AppError::AuthError(format!("Invalid service account private key: {}", e)))?;
+    jsonwebtoken::encode(&jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256), &claims, &key)
+        .map_err(|e| AppError::AuthError(format!("Failed to sign the service account JWT: {}", e)))
+}
+
+/// API backend a client talks to
+///
+/// The backends share request and response formats but differ in URL layout,
+/// resource names and authentication.
+#[derive(Debug, Clone)]
+enum Backend {
+    /// Gemini Developer API, authenticated with the `x-goog-api-key` header
+    GeminiApi {
+        root: String,
+        api_key: String,
+    },
+    /// Vertex AI, authenticated with OAuth bearer tokens
+    Vertex {
+        root: String,
+        project: String,
+        location: String,
+        tokens: Arc<TokenSource>,
+    },
+}
+
+/// Client for the Gemini API
+///
+/// Owns a pooled HTTP client together with the model, backend, credentials and
+/// default generation settings, so every subcommand goes through the same
+/// request and error handling path.
+#[derive(Debug, Clone)]
+struct GeminiClient {
+    http: Client,
+    model: String,
+    backend: Backend,
+    generation_config: GenerationConfig,
+    retry_policy: RetryPolicy,
+    input_token_limit: u32,
+    cached_content: Option<String>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl GeminiClient {
+    /// Creates a Gemini API client for the configured model and endpoint
+    ///
+    /// # Arguments
+    ///
+    /// * `api_key` - The Gemini API key
+    fn new(api_key: String) -> Self {
+        let (root, endpoint_model) = get_gemini_api_endpoint();
+        GeminiClient::with_backend(
+            Backend::GeminiApi {
+                root: root.unwrap_or_else(|| GEMINI_API_BASE_URL.to_string()),
+                api_key,
+            },
+            endpoint_model,
+        )
+    }
+
+    /// Creates a client for a backend
+    ///
+    /// # Arguments
+    ///
+    /// * `backend` - The backend to send requests to
+    /// * `endpoint_model` - Model named in `GEMINI_API_ENDPOINT`, used when `GEMINI_MODEL` is not set
+    fn with_backend(backend: Backend, endpoint_model: Option<String>) -> Self {
+        let model = match (env::var(GEMINI_MODEL_ENV_VAR), endpoint_model) {
+            (Err(_), Some(model)) => model,
+            _ => get_gemini_model(),
+        };
+        GeminiClient {
+            http: Client::new(),
+            model,
+            backend,
+            generation_config: GenerationConfig::default(),
+            retry_policy: RetryPolicy::default(),
+            input_token_limit: DEFAULT_INPUT_TOKEN_LIMIT,
+            cached_content: None,
+            rate_limiter: None,
+        }
+    }
+
+    /// Creates a client using the API key from the environment
+    ///
+    /// # Returns
+    ///
+    /// * `Result<GeminiClient, AppError>` - The client or an error if the API key is not set
+    fn from_env() -> Result<Self, AppError> {
+        Ok(GeminiClient::new(get_gemini_api_key()?))
+    }
+
+    /// Creates a client for the Gemini backend selected in the configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The backend configuration
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<GeminiClient>, AppError>` - The client, `None` for other backends, or an error if credentials are missing
+    fn from_config(config: &BackendConfig) -> Result<Option<Self>, AppError> {
+        let (project, location, credentials) = match config {
+            BackendConfig::Gemini => return GeminiClient::from_env().map(Some),
+            BackendConfig::Vertex { project, location, credentials } => (project, location, credentials),
+            BackendConfig::OpenAi { .. } | BackendConfig::Ollama { .. } => return Ok(None),
+        };
+
+        let path = find_google_credentials(credentials.as_deref())?;
+        let credentials = GoogleCredentials::load(&path)?;
+        debug!("Using Google credentials from {}", path);
+
+        let project = project
+            .clone()
+            .or_else(|| env::var(GOOGLE_CLOUD_PROJECT_ENV_VAR).ok())
+            .or_else(|| credentials.project().map(str::to_string))
+            .ok_or_else(|| {
+                AppError::ConfigError(format!(
+                    "The Vertex AI backend needs a project: set backend.project or {}",
+                    GOOGLE_CLOUD_PROJECT_ENV_VAR
+                ))
+            })?;
+        let location = location
+            .clone()
+            .or_else(|| env::var(GOOGLE_CLOUD_LOCATION_ENV_VAR).ok())
+            .unwrap_or_else(|| DEFAULT_VERTEX_LOCATION.to_string());
+
+        let (root, endpoint_model) = get_gemini_api_endpoint();
+        let root = root.unwrap_or_else(|| vertex_api_root(&location));
+        info!("Using Vertex AI in project {} and location {}", project, location);
+
+        Ok(Some(GeminiClient::with_backend(
+            Backend::Vertex {
+                root,
+                project,
+                location,
+                tokens: Arc::new(TokenSource::new(credentials)),
+            },
+            endpoint_model,
+        )))
+    }
+
+    /// Sets the default generation settings used when a request does not specify its own
+    fn with_generation_config(mut self, generation_config: GenerationConfig) -> Self {
+        self.generation_config = generation_config;
+        self
+    }
+
+    /// Sets the maximum number of input tokens checked before sending a request
+    fn with_input_token_limit(mut self, input_token_limit: u32) -> Self {
+        self.input_token_limit = input_token_limit;
+        self
+    }
+
+    /// References a cached content in every request
+    ///
+    /// The cache holds the system instruction and tools, so requests must not set them again.
+    fn with_cached_content(mut self, name: String) -> Self {
+        self.cached_content = Some(name);
+        self
+    }
+
+    /// Sets the retry policy for failed requests
+    fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets the HTTP client, e.g. one built from the `http` configuration
+    fn with_http_client(mut self, http: Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Makes every request wait for the rate limits of the model it addresses
+    fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Gets the model an API URL addresses, the configured model for other resources
+    ///
+    /// E.g. `gemini-embedding-001` for `.../models/gemini-embedding-001:batchEmbedContents`.
+    fn rate_limit_model(&self, url: &str) -> String {
+        url.split_once("/models/")
+            .and_then(|(_, rest)| rest.split([':', '/', '?']).next())
+            .filter(|model| !model.is_empty())
+            .unwrap_or_else(|| self.model.trim_start_matches("models/"))
+            .to_string()
+    }
+
+    /// Waits until the rate limits allow a request to a URL
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The endpoint URL
+    /// * `tokens` - The estimated input tokens of the request
+    async fn throttle(&self, url: &str, tokens: u32) -> Result<(), AppError> {
+        match &self.rate_limiter {
+            Some(limiter) => limiter.acquire(&self.rate_limit_model(url), tokens).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Corrects the drawn tokens of a generation request once its usage is known
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The endpoint URL
+    /// * `estimated` - The tokens drawn before sending
+    /// * `usage` - The usage reported by the API, if any
+    async fn settle_rate_limit(&self, url: &str, estimated: u32, usage: Option<&UsageMetadata>) -> Result<(), AppError> {
+        match (&self.rate_limiter, usage.and_then(|usage| usage.prompt_token_count)) {
+            (Some(limiter), Some(actual)) => limiter.settle(&self.rate_limit_model(url), estimated, actual).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Fills in the client's default generation settings the request does not set itself
+    fn prepare_request(&self, request: &GenerateContentRequest) -> GenerateContentRequest {
+        let mut request = self.apply_cached_content(request);
+        let generation_config = request.generation_config.take().unwrap_or_default();
+        let generation_config = generation_config.or(&self.generation_config);
+        debug!(
+            "Generation config: {}",
+            serde_json::to_string(&generation_config).unwrap_or_default()
+        );
+        request.generation_config = Some(generation_config);
+        request
+    }
+
+    /// References the client's cached content in a request, if any
+    ///
+    /// The system instruction and tools are removed because the API rejects
+    /// them next to a cache, which already contains them.
+    fn apply_cached_content(&self, request: &GenerateContentRequest) -> GenerateContentRequest {
+        let mut request = request.clone();
+        if let Some(name) = &self.cached_content {
+            request.cached_content = Some(name.clone());
+            request.system_instruction = None;
+            request.tools.clear();
+        }
+        request
+    }
+
+    /// Gets the API root, e.g. `https://generativelanguage.googleapis.com/v1beta`
+    fn api_root(&self) -> &str {
+        match &self.backend {
+            Backend::GeminiApi { root, .. } | Backend::Vertex { root, .. } => root,
+        }
+    }
+
+    /// Gets the parent of the backend's resources, e.g. `projects/p/locations/l` on Vertex AI
+    fn resource_parent(&self) -> Option<String> {
+        match &self.backend {
+            Backend::GeminiApi { .. } => None,
+            Backend::Vertex { project, location, .. } => Some(format!("projects/{}/locations/{}", project, location)),
+        }
+    }
+
+    /// Gets the URL of a resource or collection below the backend's parent, e.g. `cachedContents`
+    fn resource_url(&self, path: &str) -> String {
+        match self.resource_parent() {
+            Some(parent) if !path.starts_with("projects/") => format!("{}/{}/{}", self.api_root(), parent, path),
+            _ => format!("{}/{}", self.api_root(), path),
+        }
+    }
+
+    /// Gets the resource name of the configured model
+    ///
+    /// `models/gemini-2.5-flash` on the Gemini API and
+    /// `projects/p/locations/l/publishers/google/models/gemini-2.5-flash` on Vertex AI.
+    fn model_resource(&self) -> String {
+        let model = self.model.trim_start_matches("models/");
+        match self.resource_parent() {
+            Some(parent) => format!("{}/publishers/google/models/{}", parent, model),
+            None => format!("models/{}", model),
+        }
+    }
+
+    /// Gets the URL of a method of the configured model, e.g. `generateContent` or `countTokens`
+    fn model_url(&self, method: &str) -> String {
+        format!("{}/{}:{}", self.api_root(), self.model_resource(), method)
+    }
+
+    /// Checks whether the client talks to Vertex AI
+    fn is_vertex(&self) -> bool {
+        matches!(self.backend, Backend::Vertex { .. })
+    }
+
+    /// Adds the backend's credentials to a request
+    ///
+    /// The API key goes in a header rather than the URL, so it does not show
+    /// up in proxy logs or error messages.
+    ///
+    /// # Arguments
+    ///
+    /// * `builder` - The request to authenticate
+    ///
+    /// # Returns
+    ///
+    /// * `Result<reqwest::RequestBuilder, AppError>` - The authenticated request or an error
+    async fn authorize(&self, builder: reqwest::RequestBuilder) -> Result<reqwest::RequestBuilder, AppError> {
+        match &self.backend {
+            Backend::GeminiApi { api_key, .. } => Ok(builder.header("x-goog-api-key", api_key)),
+            Backend::Vertex { tokens, .. } => Ok(builder.bearer_auth(tokens.access_token(&self.http).await?)),
+        }
+    }
+
+    /// Posts a JSON body to the API and checks the response status
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The endpoint URL
+    /// * `query` - Extra query parameters
+    /// * `body` - The request body
+    /// * `tokens` - Estimated input tokens drawn from the rate limit, 0 for calls without model input
+    ///
+    /// # Returns
+    ///
+    /// * `Result<reqwest::Response, AppError>` - The successful response or an error
+    async fn post<T: Serialize>(
+        &self,
+        url: &str,
+        query: &[(&str, &str)],
+        body: &T,
+        tokens: u32,
+    ) -> Result<reqwest::Response, AppError> {
+        self.call(Method::POST, url, query, Some(body), tokens).await
+    }
+
+    /// Sends a request to the API and checks the response status
+    ///
+    /// Every attempt waits for the rate limits of the addressed model; the tokens
+    /// are only drawn for the first one. Retryable failures are retried according
+    /// to the client's [`RetryPolicy`].
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - The HTTP method
+    /// * `url` - The endpoint URL
+    /// * `query` - Extra query parameters
+    /// * `body` - The JSON request body, if any
+    /// * `tokens` - Estimated input tokens drawn from the rate limit, 0 for calls without model input
+    ///
+    /// # Returns
+    ///
+    /// * `Result<reqwest::Response, AppError>` - The successful response or an error
+    async fn call<T: Serialize>(
+        &self,
+        method: Method,
+        url: &str,
+        query: &[(&str, &str)],
+        body: Option<&T>,
+        tokens: u32,
+    ) -> Result<reqwest::Response, AppError> {
+        self.call_accepting(method, url, query, body, tokens, &[]).await
+    }
+
+    /// Sends a request like [`GeminiClient::call`], returning the given error statuses as responses
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - The HTTP method
+    /// * `url` - The endpoint URL
+    /// * `query` - Extra query parameters
+    /// * `body` - The JSON request body, if any
+    /// * `tokens` - Estimated input tokens drawn from the rate limit, 0 for calls without model input
+    /// * `accepted` - Error statuses the caller handles itself
+    ///
+    /// # Returns
+    ///
+    /// * `Result<reqwest::Response, AppError>` - The successful or accepted response, or an error
+    async fn call_accepting<T: Serialize>(
+        &self,
+        method: Method,
+        url: &str,
+        query: &[(&str, &str)],
+        body: Option<&T>,
+        tokens: u32,
+        accepted: &[StatusCode],
+    ) -> Result<reqwest::Response, AppError> {
+        let tokens = Cell::new(tokens);
+        let build = || async {
+            self.throttle(url, tokens.take()).await?;
+            let mut builder = self.authorize(self.http.request(method.clone(), url).query(query)).await?;
+            if let Some(body) = body {
+                builder = builder.header("Content-Type", "application/json").json(body);
+            }
+            Ok(builder)
+        };
+        self.retry_policy.send_accepting(build, accepted).await
+    }
+
+    /// Sends a `generateContent` request and parses the response
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request body to send
+    ///
+    /// # Returns
+    ///
+    /// * `Result<GeminiApiResponse, AppError>` - The API response or an error
+    async fn generate_content(
+        &self,
+        request: &GenerateContentRequest,
+    ) -> Result<GeminiApiResponse, AppError> {
+        let request = self.prepare_request(request);
+
+        debug!("Sending generateContent request for model {}", self.model);
+        trace!("Request body: {}", serde_json::to_string(&request)?);
+
+        let url = self.model_url("generateContent");
+        let tokens = estimate_tokens(&request);
+        let response = self.post(&url, &[], &request, tokens).await?;
+        let response_text = response.text().await?;
+
+        info!("API Response received. Processing...");
+
+        let response = serde_json::from_str::<GeminiApiResponse>(&response_text).map_err(|e| {
+            error!("Failed to parse API response: {}", e);
+            error!("Response text: {}", response_text);
+            AppError::JsonParseError(e)
+        })?;
+        self.settle_rate_limit(&url, tokens, response.usage_metadata.as_ref()).await?;
+        Ok(response)
+    }
+
+    /// Sends a `streamGenerateContent` request and reads the server-sent events
+    ///
+    /// Each part is passed to `on_part` as soon as its chunk arrives. The chunks
+    /// are merged into a single response, so callers can process the complete
+    /// text exactly as with [`GeminiClient::generate_content`].
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request body to send
+    /// * `on_part` - Callback invoked for every part of every streamed chunk
+    ///
+    /// # Returns
+    ///
+    /// * `Result<GeminiApiResponse, AppError>` - The merged API response or an error
+    async fn stream_generate_content(
+        &self,
+        request: &GenerateContentRequest,
+        on_part: &mut dyn FnMut(&Part),
+    ) -> Result<GeminiApiResponse, AppError> {
+        let request = self.prepare_request(request);
+
+        debug!("Sending streamGenerateContent request for model {}", self.model);
+        trace!("Request body: {}", serde_json::to_string(&request)?);
+
+        let url = self.model_url("streamGenerateContent");
+        let tokens = estimate_tokens(&request);
+        let mut response = self.post(&url, &[("alt", "sse")], &request, tokens).await?;
+
+        let mut events = SseBuffer::default();
+        let mut merged = GeminiApiResponse::default();
+        let mut chunk_count = 0;
+
+        while let Some(bytes) = read_with_timeout(response.chunk(), self.retry_policy.read_timeout).await? {
+            events.extend(&bytes);
+
+            while let Some(data) = events.next_data() {
+                let chunk = serde_json::from_str::<GeminiApiResponse>(&data).map_err(|e| {
+                    error!("Failed to parse streamed chunk: {}", e);
+                    error!("Chunk text: {}", data);
+                    AppError::JsonParseError(e)
+                })?;
+                chunk_count += 1;
+                trace!("Received streamed chunk {}", chunk_count);
+
+                for candidate in chunk.candidates.iter().flatten() {
+                    candidate.content.parts.iter().for_each(&mut *on_part);
+                }
+                merged.merge_chunk(chunk);
+            }
+        }
+
+        info!("Stream finished after {} chunks", chunk_count);
+        self.settle_rate_limit(&url, tokens, merged.usage_metadata.as_ref()).await?;
+        Ok(merged)
+    }
+
+    /// Creates a cached content holding project files, system instruction and tools
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The contents to cache and their time to live
+    ///
+    /// # Returns
+    ///
+    /// * `Result<CachedContent, AppError>` - The created cache or an error
+    async fn create_cached_content(&self, request: &CreateCachedContentRequest) -> Result<CachedContent, AppError> {
+        let url = self.resource_url("cachedContents");
+        Ok(self.post(&url, &[], request, 0).await?.json().await?)
+    }
+
+    /// Lists the cached contents of the project
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<CachedContent>, AppError>` - All caches, across pages, or an error
+    async fn list_cached_contents(&self) -> Result<Vec<CachedContent>, AppError> {
+        let url = self.resource_url("cachedContents");
+        let mut caches = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let query: Vec<(&str, &str)> = page_token.iter().map(|token| ("pageToken", token.as_str())).collect();
+            let page: CachedContentList = self.call::<()>(Method::GET, &url, &query, None, 0).await?.json().await?;
+            caches.extend(page.cached_contents);
+            match page.next_page_token.filter(|token| !token.is_empty()) {
+                Some(token) => page_token = Some(token),
+                None => return Ok(caches),
+            }
+        }
+    }
+
+    /// Gets a cached content by name
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The cache name, with or without the `cachedContents/` prefix
+    ///
+    /// # Returns
+    ///
+    /// * `Result<CachedContent, AppError>` - The cache or an error
+    async fn get_cached_content(&self, name: &str) -> Result<CachedContent, AppError> {
+        let url = self.resource_url(&cache_resource_name(name));
+        Ok(self.call::<()>(Method::GET, &url, &[], None, 0).await?.json().await?)
+    }
+
+    /// Sets a new time to live for a cached content
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The cache name
+    /// * `ttl` - The new time to live, counted from now
+    ///
+    /// # Returns
+    ///
+    /// * `Result<CachedContent, AppError>` - The updated cache or an error
+    async fn update_cached_content_ttl(&self, name: &str, ttl: Duration) -> Result<CachedContent, AppError> {
+        let url = self.resource_url(&cache_resource_name(name));
+        let body = json!({"ttl": format!("{}s", ttl.as_secs())});
+        Ok(self
+            .call(Method::PATCH, &url, &[("updateMask", "ttl")], Some(&body), 0)
+            .await?
+            .json()
+            .await?)
+    }
+
+    /// Deletes a cached content
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The cache name
+    async fn delete_cached_content(&self, name: &str) -> Result<(), AppError> {
+        let url = self.resource_url(&cache_resource_name(name));
+        self.call::<()>(Method::DELETE, &url, &[], None, 0).await?;
+        Ok(())
+    }
+
+    /// Embeds a single text with the `embedContent` method of an embedding model
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The text, model and task to embed for
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<f32>, AppError>` - The embedding vector or an error
+    async fn embed_content(&self, request: &EmbedContentRequest) -> Result<Vec<f32>, AppError> {
+        self.require_embeddings_endpoint()?;
+        let url = format!("{}/{}:embedContent", self.api_root(), request.model);
+        let tokens = estimate_text_tokens(&request.content);
+        let response: EmbedContentResponse = self.post(&url, &[], request, tokens).await?.json().await?;
+        Ok(response.embedding.values)
+    }
+
+    /// Embeds several texts in one `batchEmbedContents` request
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The embedding model resource, e.g. `models/gemini-embedding-001`
+    /// * `requests` - One request per text, all for `model`
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<Vec<f32>>, AppError>` - The embedding vectors in request order, or an error
+    async fn batch_embed_contents(&self, model: &str, requests: Vec<EmbedContentRequest>) -> Result<Vec<Vec<f32>>, AppError> {
+        self.require_embeddings_endpoint()?;
+        let url = format!("{}/{}:batchEmbedContents", self.api_root(), model);
+        let count = requests.len();
+        let tokens = requests.iter().map(|request| estimate_text_tokens(&request.content)).sum();
+        let response: BatchEmbedContentsResponse = self
+            .post(&url, &[], &BatchEmbedContentsRequest { requests }, tokens)
+            .await?
+            .json()
+            .await?;
+        if response.embeddings.len() != count {
+            return Err(AppError::ResponseError(format!(
+                "Expected {} embeddings but received {}",
+                count,
+                response.embeddings.len()
+            )));
+        }
+        Ok(response.embeddings.into_iter().map(|embedding| embedding.values).collect())
+    }
+
+    /// Lists the models available to the API key
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<ModelInfo>, AppError>` - All models, across pages, or an error
+    async fn list_models(&self) -> Result<Vec<ModelInfo>, AppError> {
+        self.require_models_endpoint()?;
+        let url = format!("{}/models", self.api_root());
+        let mut models = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let query: Vec<(&str, &str)> = page_token.iter().map(|token| ("pageToken", token.as_str())).collect();
+            let page: ModelList = self.call::<()>(Method::GET, &url, &query, None, 0).await?.json().await?;
+            models.extend(page.models);
+            match page.next_page_token.filter(|token| !token.is_empty()) {
+                Some(token) => page_token = Some(token),
+                None => return Ok(models),
+            }
+        }
+    }
+
+    /// Gets the description of a model
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The model name, with or without the `models/` prefix
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<ModelInfo>, AppError>` - The model, `None` if it does not exist, or an error
+    async fn get_model(&self, name: &str) -> Result<Option<ModelInfo>, AppError> {
+        self.require_models_endpoint()?;
+        let url = format!("{}/models/{}", self.api_root(), name.trim_start_matches("models/"));
+        let response = self
+            .call_accepting::<()>(Method::GET, &url, &[], None, 0, &[StatusCode::NOT_FOUND])
+            .await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Ok(Some(response.json().await?)),
+        }
+    }
+
+    /// Fails on backends without the Gemini API's embedding methods
+    fn require_embeddings_endpoint(&self) -> Result<(), AppError> {
+        if self.is_vertex() {
+            return Err(AppError::ConfigError(
+                "Embedding project files is only supported by the Gemini API backend".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fails on backends without the Gemini API's `models` endpoint
+    fn require_models_endpoint(&self) -> Result<(), AppError> {
+        if self.is_vertex() {
+            return Err(AppError::ConfigError(
+                "Listing and inspecting models is only supported by the Gemini API backend".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Model provider the chat, execute and create-codebase flows talk to
+///
+/// Requests and responses use the Gemini format. Providers with another API
+/// translate them, so the flows work the same against every backend.
+#[async_trait::async_trait(?Send)]
+trait LlmBackend {
+    /// Gets the name of the model requests are sent to
+    fn model(&self) -> &str;
+
+    /// Gets the maximum number of input tokens the model accepts
+    fn input_token_limit(&self) -> u32;
+
+    /// Counts the input tokens of a request
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request that is about to be sent
+    ///
+    /// # Returns
+    ///
+    /// * `TokenCount` - The number of input tokens, possibly estimated
+    async fn count_tokens(&self, request: &GenerateContentRequest) -> TokenCount;
+
+    /// Sends a request, streaming it when a part callback is given
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request body to send
+    /// * `on_part` - Optional callback for incremental output
+    ///
+    /// # Returns
+    ///
+    /// * `Result<GeminiApiResponse, AppError>` - The response or an error
+    async fn send(
+        &self,
+        request: &GenerateContentRequest,
+        on_part: Option<&mut dyn for<'p> FnMut(&'p Part)>,
+    ) -> Result<GeminiApiResponse, AppError>;
+
+    /// Uploads a file that is too large to send inline
+    ///
+    /// # Arguments
+    ///
+    /// * `display_name` - The name shown for the file
+    /// * `mime_type` - The file's MIME type
+    /// * `bytes` - The file content
+    ///
+    /// # Returns
+    ///
+    /// * `Result<FileData, AppError>` - A reference to the uploaded file or an error
+    async fn upload_file(&self, display_name: &str, _mime_type: &str, _bytes: Vec<u8>) -> Result<FileData, AppError> {
+        Err(AppError::AttachmentError(format!(
+            "{} is too large to send inline, and model {} has no file upload API",
+            display_name,
+            self.model()
+        )))
+    }
+
+    /// Checks a token count against the model's input limit
+    ///
+    /// Prints a warning once the request uses most of the limit.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - The token count of the request
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), AppError>` - An error if the request would exceed the limit
+    fn check_input_size(&self, count: &TokenCount) -> Result<(), AppError> {
+        let approximately = if count.estimated { "about " } else { "" };
+
+        if count.tokens > self.input_token_limit() {
+            return Err(AppError::InputTooLarge(format!(
+                "Request has {}{} input tokens, more than the limit of {} tokens for model {}",
+                approximately, count.tokens, self.input_token_limit(), self.model()
+            )));
+        }
+
+        if count.exceeds(self.warning_threshold()) {
+            warn!(
+                "Request has {}{} input tokens, close to the limit of {}",
+                approximately, count.tokens, self.input_token_limit()
+            );
+            println!(
+                "Warning: the request uses {}{} of the model's {} input tokens.",
+                approximately, count.tokens, self.input_token_limit()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Gets the number of input tokens above which requests are reported as nearing the limit
+    fn warning_threshold(&self) -> u32 {
+        (self.input_token_limit() as f64 * INPUT_TOKEN_WARNING_RATIO) as u32
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl LlmBackend for GeminiClient {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn input_token_limit(&self) -> u32 {
+        self.input_token_limit
+    }
+
+    /// Counts the input tokens of a request with the `countTokens` endpoint
+    ///
+    /// Falls back to [`estimate_tokens`] when the endpoint cannot be reached or
+    /// returns an error, e.g. when offline. The call is not retried so a slow
+    /// network does not delay the actual request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request that is about to be sent
+    ///
+    /// # Returns
+    ///
+    /// * `TokenCount` - The number of input tokens
+    async fn count_tokens(&self, request: &GenerateContentRequest) -> TokenCount {
+        let request = &self.apply_cached_content(request);
+        let counted = CountedRequest {
+            model: self.model_resource(),
+            contents: &request.contents,
+            tools: &request.tools,
+            system_instruction: request.system_instruction.as_ref(),
+            cached_content: request.cached_content.as_deref(),
+        };
+        let body = if self.is_vertex() {
+            CountTokensRequest::Flat(counted)
+        } else {
+            CountTokensRequest::Wrapped {
+                generate_content_request: counted,
+            }
+        };
+
+        let single_attempt = self.clone().with_retry_policy(RetryPolicy {
+            max_attempts: 1,
+            ..self.retry_policy.clone()
+        });
+        let result = match single_attempt.post(&self.model_url("countTokens"), &[], &body, 0).await {
+            Ok(response) => response.json::<CountTokensResponse>().await.map_err(AppError::HttpError),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(response) => {
+                debug!("countTokens reported {} input tokens", response.total_tokens);
+                TokenCount {
+                    tokens: response.total_tokens,
+                    estimated: false,
+                }
+            }
+            Err(e) => {
+                let tokens = estimate_tokens(request);
+                warn!("Could not count tokens ({}), estimated {} input tokens locally", e, tokens);
+                TokenCount {
+                    tokens,
+                    estimated: true,
+                }
+            }
+        }
+    }
+
+    /// Sends a request, streaming it when a part callback is given
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request body to send
+    /// * `on_part` - Optional callback for incremental output
+    ///
+    /// # Returns
+    ///
+    /// * `Result<GeminiApiResponse, AppError>` - The API response or an error
+    async fn send(
+        &self,
+        request: &GenerateContentRequest,
+        on_part: Option<&mut dyn for<'p> FnMut(&'p Part)>,
+    ) -> Result<GeminiApiResponse, AppError> {
+        match on_part {
+            Some(on_part) => self.stream_generate_content(request, on_part).await,
+            None => self.generate_content(request).await,
+        }
+    }
+
+    /// Uploads a file with the Files API's resumable upload flow
+    ///
+    /// Waits until the file has been processed, which can take a while for
+    /// videos and large documents.
+    ///
+    /// # Arguments
+    ///
+    /// * `display_name` - The name shown for the file
+    /// * `mime_type` - The file's MIME type
+    /// * `bytes` - The file content
+    ///
+    /// # Returns
+    ///
+    /// * `Result<FileData, AppError>` - A reference to the uploaded file or an error
+    async fn upload_file(&self, display_name: &str, mime_type: &str, bytes: Vec<u8>) -> Result<FileData, AppError> {
+        if self.is_vertex() {
+            return Err(AppError::AttachmentError(format!(
+                "{} is too large to send inline, and Vertex AI has no Files API. Upload it to Cloud Storage instead",
+                display_name
+            )));
+        }
+        let root = self.api_root();
+        let (host, version) = root.rsplit_once('/').ok_or_else(|| {
+            AppError::AttachmentError(format!("Cannot derive the upload URL from endpoint {}", root))
+        })?;
+
+        let start_url = format!("{}/upload/{}/files", host, version);
+        self.throttle(&start_url, 0).await?;
+        let start = self
+            .authorize(self.http.post(&start_url))
+            .await?
+            .header("X-Goog-Upload-Protocol", "resumable")
+            .header("X-Goog-Upload-Command", "start")
+            .header("X-Goog-Upload-Header-Content-Length", bytes.len())
+            .header("X-Goog-Upload-Header-Content-Type", mime_type)
+            .json(&json!({"file": {"displayName": display_name}}))
+            .send();
+        let start = read_with_timeout(start, self.retry_policy.read_timeout).await?;
+        if !start.status().is_success() {
+            let status = start.status();
+            return Err(AppError::AttachmentError(format!(
+                "Starting the upload of {} failed with status {}: {}",
+                display_name,
+                status,
+                start.text().await?
+            )));
+        }
+        let upload_url = start
+            .headers()
+            .get("x-goog-upload-url")
+            .and_then(|url| url.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| AppError::AttachmentError("Upload response did not include an upload URL".to_string()))?;
+
+        debug!("Uploading {} bytes of {} to the Files API", bytes.len(), display_name);
+        self.throttle(&upload_url, 0).await?;
+        let response = self
+            .http
+            .post(upload_url)
+            .header("X-Goog-Upload-Offset", "0")
+            .header("X-Goog-Upload-Command", "upload, finalize")
+            .body(bytes)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            return Err(AppError::AttachmentError(format!(
+                "Uploading {} failed with status {}: {}",
+                display_name,
+                status,
+                response.text().await?
+            )));
+        }
+        let mut file = response.json::<UploadFileResponse>().await?.file;
+
+        for _ in 0..FILE_PROCESSING_MAX_POLLS {
+            match file.state.as_deref() {
+                Some("PROCESSING") => {
+                    debug!("Waiting for {} to be processed", file.name);
+                    tokio::time::sleep(Duration::from_millis(FILE_PROCESSING_POLL_INTERVAL_MS)).await;
+                    let poll_url = format!("{}/{}", root, file.name);
+                    self.throttle(&poll_url, 0).await?;
+                    let poll = self.authorize(self.http.get(&poll_url)).await?.send();
+                    file = read_with_timeout(poll, self.retry_policy.read_timeout)
+                        .await?
+                        .error_for_status()?
+                        .json::<UploadedFile>()
+                        .await?;
+                }
+                Some("FAILED") => {
+                    return Err(AppError::AttachmentError(format!("Processing of {} failed", display_name)));
+                }
+                _ => {
+                    info!("Uploaded {} as {}", display_name, file.uri);
+                    return Ok(FileData {
+                        mime_type: file.mime_type.unwrap_or_else(|| mime_type.to_string()),
+                        file_uri: file.uri,
+                    });
+                }
+            }
+        }
+
+        Err(AppError::AttachmentError(format!(
+            "{} was still being processed after {} seconds",
+            display_name,
+            FILE_PROCESSING_MAX_POLLS as u64 * FILE_PROCESSING_POLL_INTERVAL_MS / 1000
+        )))
+    }
+}
+
+/// Client for OpenAI-compatible chat completions APIs
+///
+/// Works with OpenAI itself and with local servers such as Ollama or
+/// llama.cpp. Gemini requests are translated to chat completions and the
+/// replies back, so the flows do not need to know which API they talk to.
+#[derive(Debug, Clone)]
+struct OpenAiClient {
+    http: Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+    generation_config: GenerationConfig,
+    retry_policy: RetryPolicy,
+    input_token_limit: u32,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl OpenAiClient {
+    /// Creates a client for a chat completions API
+    ///
+    /// # Arguments
+    ///
+    /// * `base_url` - The API root, e.g. `http://localhost:11434/v1`
+    /// * `model` - The model to request
+    /// * `api_key` - The key sent as bearer token, if the server needs one
+    fn new(base_url: &str, model: String, api_key: Option<String>) -> Self {
+        OpenAiClient {
+            http: Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            model,
+            generation_config: GenerationConfig::default(),
+            retry_policy: RetryPolicy::default(),
+            input_token_limit: DEFAULT_INPUT_TOKEN_LIMIT,
+            rate_limiter: None,
+        }
+    }
+
+    /// Creates a client for an `openai` or `ollama` backend from the configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The backend configuration
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<OpenAiClient>, AppError>` - The client, `None` for Gemini backends, or an error
+    fn from_config(config: &BackendConfig) -> Result<Option<Self>, AppError> {
+        let (base_url, model, api_key_env) = match config {
+            BackendConfig::OpenAi { base_url, model, api_key_env } => (
+                base_url.as_deref().unwrap_or(DEFAULT_OPENAI_BASE_URL),
+                model,
+                Some(api_key_env.as_deref().unwrap_or(OPENAI_API_KEY_ENV_VAR)),
+            ),
+            BackendConfig::Ollama { base_url, model } => {
+                (base_url.as_deref().unwrap_or(DEFAULT_OLLAMA_BASE_URL), model, None)
+            }
+            BackendConfig::Gemini | BackendConfig::Vertex { .. } => return Ok(None),
+        };
+
+        let model = model
+            .clone()
+            .or_else(|| env::var(GEMINI_MODEL_ENV_VAR).ok())
+            .ok_or_else(|| {
+                AppError::ConfigError(format!("Set backend.model or {} to the model to use", GEMINI_MODEL_ENV_VAR))
+            })?;
+        let api_key = api_key_env.and_then(|name| env::var(name).ok());
+        info!("Using model {} at {}", model, base_url);
+
+        Ok(Some(OpenAiClient::new(base_url, model, api_key)))
+    }
+
+    /// Sets the default generation settings used when a request does not specify its own
+    fn with_generation_config(mut self, generation_config: GenerationConfig) -> Self {
+        self.generation_config = generation_config;
+        self
+    }
+
+    /// Sets the maximum number of input tokens checked before sending a request
+    fn with_input_token_limit(mut self, input_token_limit: u32) -> Self {
+        self.input_token_limit = input_token_limit;
+        self
+    }
+
+    /// Sets the retry policy for failed requests
+    fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets the HTTP client, e.g. one built from the `http` configuration
+    fn with_http_client(mut self, http: Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Makes every request wait for the rate limits of the client's model
+    fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Corrects the drawn tokens of a request once its usage is known
+    ///
+    /// # Arguments
+    ///
+    /// * `estimated` - The tokens drawn before sending
+    /// * `response` - The converted response
+    async fn settle_rate_limit(&self, estimated: u32, response: &GeminiApiResponse) -> Result<(), AppError> {
+        let actual = response.usage_metadata.as_ref().and_then(|usage| usage.prompt_token_count);
+        match (&self.rate_limiter, actual) {
+            (Some(limiter), Some(actual)) => limiter.settle(&self.model, estimated, actual).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Posts a chat completions request
+    ///
+    /// Every attempt waits for the rate limits of the model; the tokens are only
+    /// drawn for the first one.
+    ///
+    /// # Arguments
+    ///
+    /// * `body` - The request body
+    /// * `tokens` - Estimated input tokens drawn from the rate limit
+    ///
+    /// # Returns
+    ///
+    /// * `Result<reqwest::Response, AppError>` - The successful response or an error
+    async fn post(&self, body: &serde_json::Value, tokens: u32) -> Result<reqwest::Response, AppError> {
+        let url = format!("{}/chat/completions", self.base_url);
+        let tokens = Cell::new(tokens);
+        self.retry_policy
+            .send(|| async {
+                if let Some(limiter) = &self.rate_limiter {
+                    limiter.acquire(&self.model, tokens.take()).await?;
+                }
+                let builder = self.http.post(&url).json(body);
+                Ok(match &self.api_key {
+                    Some(api_key) => builder.bearer_auth(api_key),
+                    None => builder,
+                })
+            })
+            .await
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl LlmBackend for OpenAiClient {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn input_token_limit(&self) -> u32 {
+        self.input_token_limit
+    }
+
+    /// Estimates the input tokens locally; chat completions APIs have no counting endpoint
+    async fn count_tokens(&self, request: &GenerateContentRequest) -> TokenCount {
+        TokenCount {
+            tokens: estimate_tokens(request),
+            estimated: true,
+        }
+    }
+
+    async fn send(
+        &self,
+        request: &GenerateContentRequest,
+        on_part: Option<&mut dyn for<'p> FnMut(&'p Part)>,
+    ) -> Result<GeminiApiResponse, AppError> {
+        let generation_config = request
+            .generation_config
+            .clone()
+            .unwrap_or_default()
+            .or(&self.generation_config);
+        let body = chat_completion_request(&self.model, request, &generation_config, on_part.is_some());
+
+        debug!("Sending chat completions request for model {}", self.model);
+        trace!("Request body: {}", body);
+
+        let tokens = estimate_tokens(request);
+        let mut response = self.post(&body, tokens).await?;
+
+        let Some(on_part) = on_part else {
+            let response_text = response.text().await?;
+            let completion = serde_json::from_str::<ChatCompletion>(&response_text).map_err(|e| {
+                error!("Failed to parse API response: {}", e);
+                error!("Response text: {}", response_text);
+                AppError::JsonParseError(e)
+            })?;
+            let response = completion.into_gemini_response();
+            self.settle_rate_limit(tokens, &response).await?;
+            return Ok(response);
+        };
+
+        let mut events = SseBuffer::default();
+        let mut merged = GeminiApiResponse::default();
+        let mut tool_calls = ToolCallBuffer::default();
+        let mut chunk_count = 0;
+
+        while let Some(bytes) = read_with_timeout(response.chunk(), self.retry_policy.read_timeout).await? {
+            events.extend(&bytes);
+
+            while let Some(data) = events.next_data() {
+                if data == "[DONE]" {
+                    continue;
+                }
+                let mut chunk = serde_json::from_str::<ChatCompletion>(&data).map_err(|e| {
+                    error!("Failed to parse streamed chunk: {}", e);
+                    error!("Chunk text: {}", data);
+                    AppError::JsonParseError(e)
+                })?;
+                chunk_count += 1;
+                trace!("Received streamed chunk {}", chunk_count);
+
+                // Tool call arguments arrive in fragments and are only complete at the end
+                for choice in &mut chunk.choices {
+                    tool_calls.extend(choice.index, std::mem::take(&mut choice.message.tool_calls));
+                }
+                let chunk = chunk.into_gemini_response();
+                for candidate in chunk.candidates.iter().flatten() {
+                    candidate.content.parts.iter().for_each(&mut *on_part);
+                }
+                merged.merge_chunk(chunk);
+            }
+        }
+
+        let candidates = merged.candidates.get_or_insert_with(Vec::new);
+        for (index, part) in tool_calls.into_parts() {
+            on_part(&part);
+            match candidates.iter_mut().find(|c| c.index.unwrap_or(0) == index) {
+                Some(candidate) => candidate.content.parts.push(part),
+                None => candidates.push(Candidate::from_parts(index, vec![part], None)),
+            }
+        }
+
+        info!("Stream finished after {} chunks", chunk_count);
+        self.settle_rate_limit(tokens, &merged).await?;
+        Ok(merged)
+    }
+}
+
+/// Builds a chat completions request from a Gemini request
+///
+/// The system instruction becomes a system message, model turns become
+/// assistant messages and function responses become tool messages. Images are
+/// sent as data URLs and text files inline. The code execution tool and other
+/// file types have no equivalent and are left out with a warning.
+///
+/// # Arguments
+///
+/// * `model` - The model to request
+/// * `request` - The Gemini request
+/// * `generation_config` - The generation settings to translate
+/// * `stream` - Whether to request a server-sent event stream
+///
+/// # Returns
+///
+/// * `serde_json::Value` - The chat completions request body
+fn chat_completion_request(
+    model: &str,
+    request: &GenerateContentRequest,
+    generation_config: &GenerationConfig,
+    stream: bool,
+) -> serde_json::Value {
+    let mut messages = Vec::new();
+    if let Some(system_instruction) = &request.system_instruction {
+        let text = system_instruction
+            .parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Text { text, .. } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        messages.push(json!({"role": "system", "content": text}));
+    }
+
+    // Gemini function calls may come without ids, so ids are made up and matched by order
+    let mut pending_call_ids = std::collections::VecDeque::new();
+    for (turn, content) in request.contents.iter().enumerate() {
+        let mut texts = Vec::new();
+        let mut images = Vec::new();
+        let mut tool_calls = Vec::new();
+
+        for part in &content.parts {
+            match part {
+                Part::Text { text, .. } => texts.push(text.clone()),
+                Part::ExecutableCode { executable_code } => texts.push(format!(
+                    "```{}\n{}\n```",
+                    executable_code.language.to_lowercase(),
+                    executable_code.code
+                )),
+                Part::CodeExecutionResult { code_execution_result } => {
+                    texts.push(format!("Output:\n{}", code_execution_result.output))
+                }
+                Part::InlineData { inline_data } if inline_data.mime_type.starts_with("image/") => {
+                    images.push(json!({
+                        "type": "image_url",
+                        "image_url": {"url": format!("data:{};base64,{}", inline_data.mime_type, inline_data.data)}
+                    }));
+                }
+                Part::InlineData { inline_data } if inline_data.mime_type.starts_with("text/") => {
+                    match BASE64_STANDARD.decode(&inline_data.data).map(String::from_utf8) {
+                        Ok(Ok(text)) => texts.push(text),
+                        _ => warn!("Skipping an attachment that is not valid UTF-8 text"),
+                    }
+                }
+                Part::FunctionCall { function_call, .. } => {
+                    let id = function_call
+                        .id
+                        .clone()
+                        .unwrap_or_else(|| format!("call_{}_{}", turn, tool_calls.len()));
+                    pending_call_ids.push_back(id.clone());
+                    tool_calls.push(json!({
+                        "id": id,
+                        "type": "function",
+                        "function": {"name": function_call.name, "arguments": function_call.args.to_string()}
+                    }));
+                }
+                Part::FunctionResponse { function_response } => {
+                    let id = function_response
+                        .id
+                        .clone()
+                        .or_else(|| pending_call_ids.pop_front())
+                        .unwrap_or_else(|| function_response.name.clone());
+                    messages.push(json!({
+                        "role": "tool",
+                        "tool_call_id": id,
+                        "content": function_response.response.to_string()
+                    }));
+                }
+                Part::Thought { .. } => {}
+                Part::InlineData { inline_data } => {
+                    warn!("Skipping a {} attachment, which chat completions APIs do not accept", inline_data.mime_type)
+                }
+                Part::FileData { file_data } => {
+                    warn!("Skipping uploaded file {}, which chat completions APIs cannot read", file_data.file_uri)
+                }
+                Part::Other(value) => debug!("Skipping unsupported part: {}", value),
+            }
+        }
+
+        let role = match content.role.as_deref() {
+            Some("model") => "assistant",
+            _ => "user",
+        };
+        let text = texts.join("\n\n");
+        if !tool_calls.is_empty() {
+            let content = (!text.is_empty()).then_some(text);
+            messages.push(json!({"role": role, "content": content, "tool_calls": tool_calls}));
+        } else if !images.is_empty() {
+            let mut parts = vec![json!({"type": "text", "text": text})];
+            parts.extend(images);
+            messages.push(json!({"role": role, "content": parts}));
+        } else if !text.is_empty() {
+            messages.push(json!({"role": role, "content": text}));
+        }
+    }
+
+    let mut body = json!({"model": model, "messages": messages});
+    let tools: Vec<_> = request
+        .tools
+        .iter()
+        .flat_map(|tool| &tool.function_declarations)
+        .map(|declaration| {
+            json!({
+                "type": "function",
+                "function": {
+                    "name": declaration.name,
+                    "description": declaration.description,
+                    "parameters": plain_json_schema(&declaration.parameters)
+                }
+            })
+        })
+        .collect();
+    if request.tools.iter().any(|tool| tool.code_execution.is_some()) {
+        warn!("Code execution is not available through chat completions APIs; the model's code is not run");
+    }
+    if request.tools.iter().any(|tool| tool.google_search.is_some()) {
+        warn!("Google Search grounding is not available through chat completions APIs; answers are not grounded");
+    }
+    if !tools.is_empty() {
+        body["tools"] = json!(tools);
+    }
+
+    if let Some(temperature) = generation_config.temperature {
+        body["temperature"] = json!(temperature);
+    }
+    if let Some(top_p) = generation_config.top_p {
+        body["top_p"] = json!(top_p);
+    }
+    if let Some(max_output_tokens) = generation_config.max_output_tokens {
+        body["max_tokens"] = json!(max_output_tokens);
+    }
+    if let Some(candidate_count) = generation_config.candidate_count {
+        body["n"] = json!(candidate_count);
+    }
+    if let Some(stop_sequences) = &generation_config.stop_sequences {
+        body["stop"] = json!(stop_sequences);
+    }
+    if let Some(seed) = generation_config.seed {
+        body["seed"] = json!(seed);
+    }
+    match (&generation_config.response_mime_type, &generation_config.response_schema) {
+        (_, Some(schema)) => {
+            body["response_format"] = json!({
+                "type": "json_schema",
+                "json_schema": {"name": "response", "schema": plain_json_schema(schema)}
+            });
+        }
+        (Some(mime_type), None) if mime_type == "application/json" => {
+            body["response_format"] = json!({"type": "json_object"});
+        }
+        _ => {}
+    }
+    if generation_config.top_k.is_some() || generation_config.thinking_config.is_some() {
+        debug!("top_k and thinking settings are not supported by chat completions APIs and are not sent");
+    }
+    if stream {
+        body["stream"] = json!(true);
+        body["stream_options"] = json!({"include_usage": true});
+    }
+
+    body
+}
+
+/// Response of a chat completions API, or one chunk of a streamed response
+#[derive(Debug, Deserialize)]
+struct ChatCompletion {
+    #[serde(default)]
+    choices: Vec<ChatChoice>,
+    usage: Option<ChatUsage>,
+    model: Option<String>,
+    id: Option<String>,
+}
+
+/// One choice of a chat completion
+#[derive(Debug, Deserialize)]
+struct ChatChoice {
+    #[serde(default)]
+    index: i32,
+    /// `message` in a full response, `delta` in a streamed chunk
+    #[serde(default, alias = "delta")]
+    message: ChatMessage,
+    finish_reason: Option<String>,
+}
+
+/// Message or message fragment of a chat completion choice
+#[derive(Debug, Default, Deserialize)]
+struct ChatMessage {
+    content: Option<String>,
+    /// Reasoning of thinking models, as returned by Ollama, llama.cpp and others
+    #[serde(alias = "reasoning")]
+    reasoning_content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ChatToolCall>,
+}
+
+/// Tool call of a chat completion, complete or as a streamed fragment
+#[derive(Debug, Deserialize)]
+struct ChatToolCall {
+    index: Option<usize>,
+    id: Option<String>,
+    #[serde(default)]
+    function: ChatFunctionCall,
+}
+
+/// Function name and JSON encoded arguments of a tool call
+#[derive(Debug, Default, Deserialize)]
+struct ChatFunctionCall {
+    name: Option<String>,
+    #[serde(default)]
+    arguments: String,
+}
+
+/// Token usage of a chat completion
+#[derive(Debug, Deserialize)]
+struct ChatUsage {
+    prompt_tokens: Option<u32>,
+    completion_tokens: Option<u32>,
+    total_tokens: Option<u32>,
+}
+
+impl ChatCompletion {
+    /// Converts the completion into a Gemini response
+    fn into_gemini_response(self) -> GeminiApiResponse {
+        let candidates = self
+            .choices
+            .into_iter()
+            .map(|choice| {
+                let mut parts = Vec::new();
+                if let Some(text) = choice.message.reasoning_content.filter(|text| !text.is_empty()) {
+                    parts.push(Part::Thought {
+                        text,
+                        thought: true,
+                        thought_signature: None,
+                    });
+                }
+                if let Some(text) = choice.message.content.filter(|text| !text.is_empty()) {
+                    parts.push(Part::text(text));
+                }
+                let mut tool_calls = ToolCallBuffer::default();
+                tool_calls.extend(choice.index, choice.message.tool_calls);
+                parts.extend(tool_calls.into_parts().into_iter().map(|(_, part)| part));
+
+                let finish_reason = choice.finish_reason.map(|reason| {
+                    match reason.as_str() {
+                        "stop" | "tool_calls" | "function_call" => "STOP",
+                        "length" => "MAX_TOKENS",
+                        "content_filter" => "SAFETY",
+                        _ => "OTHER",
+                    }
+                    .to_string()
+                });
+                Candidate::from_parts(choice.index, parts, finish_reason)
+            })
+            .collect();
+
+        GeminiApiResponse {
+            candidates: Some(candidates),
+            prompt_feedback: None,
+            usage_metadata: self.usage.map(|usage| UsageMetadata {
+                prompt_token_count: usage.prompt_tokens,
+                candidates_token_count: usage.completion_tokens,
+                total_token_count: usage.total_tokens,
+                ..Default::default()
+            }),
+            model_version: self.model,
+            response_id: self.id,
+        }
+    }
+}
+
+/// Assembles tool calls from complete calls or streamed fragments
+#[derive(Debug, Default)]
+struct ToolCallBuffer {
+    /// Candidate index, call index, id, name and arguments of each call
+    calls: Vec<(i32, usize, Option<String>, String, String)>,
+}
+
+impl ToolCallBuffer {
+    /// Adds tool calls or fragments of a choice
+    fn extend(&mut self, candidate: i32, tool_calls: Vec<ChatToolCall>) {
+        for (position, call) in tool_calls.into_iter().enumerate() {
+            let index = call.index.unwrap_or(position);
+            let existing = self.calls.iter_mut().find(|(c, i, ..)| *c == candidate && *i == index);
+            match existing {
+                Some((_, _, id, name, arguments)) => {
+                    if id.is_none() {
+                        *id = call.id;
+                    }
+                    name.push_str(call.function.name.as_deref().unwrap_or_default());
+                    arguments.push_str(&call.function.arguments);
+                }
+                None => self.calls.push((
+                    candidate,
+                    index,
+                    call.id,
+                    call.function.name.unwrap_or_default(),
+                    call.function.arguments,
+                )),
+            }
+        }
+    }
+
+    /// Converts the assembled calls into function call parts, with their candidate index
+    fn into_parts(self) -> Vec<(i32, Part)> {
+        self.calls
+            .into_iter()
+            .map(|(candidate, _, id, name, arguments)| {
+                let args = if arguments.trim().is_empty() {
+                    json!({})
+                } else {
+                    serde_json::from_str(&arguments).unwrap_or(serde_json::Value::String(arguments))
+                };
+                (
+                    candidate,
+                    Part::FunctionCall {
+                        function_call: FunctionCall { id, name, args },
+                        thought_signature: None,
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+/// Request and token buckets of one model
+///
+/// Both buckets start full and refill continuously, reaching their
+/// per-minute limit after a minute without requests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ModelBuckets {
+    requests: f64,
+    tokens: f64,
+    /// Seconds since the Unix epoch of the last refill
+    updated_at: f64,
+}
+
+impl ModelBuckets {
+    /// Creates full buckets for a limit
+    fn full(limit: &ModelRateLimit, now: f64) -> Self {
+        ModelBuckets {
+            requests: limit.requests_per_minute.map_or(0.0, f64::from),
+            tokens: limit.tokens_per_minute.map_or(0.0, f64::from),
+            updated_at: now,
+        }
+    }
+
+    /// Adds what was refilled since the last update
+    fn refill(&mut self, limit: &ModelRateLimit, now: f64) {
+        let elapsed_minutes = (now - self.updated_at).max(0.0) / 60.0;
+        if let Some(rpm) = limit.requests_per_minute.map(f64::from) {
+            self.requests = (self.requests + elapsed_minutes * rpm).min(rpm);
+        }
+        if let Some(tpm) = limit.tokens_per_minute.map(f64::from) {
+            self.tokens = (self.tokens + elapsed_minutes * tpm).min(tpm);
+        }
+        self.updated_at = now;
+    }
+
+    /// Takes one request with the given number of tokens if the buckets allow it
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The limits of the model
+    /// * `tokens` - The estimated input tokens of the request
+    /// * `now` - The current time in seconds since the Unix epoch
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Duration>` - `None` if the request was taken, otherwise how long to wait
+    fn take(&mut self, limit: &ModelRateLimit, tokens: u32, now: f64) -> Option<Duration> {
+        self.refill(limit, now);
+
+        let mut wait_minutes: f64 = 0.0;
+        if let Some(rpm) = limit.requests_per_minute.map(f64::from) {
+            wait_minutes = wait_minutes.max((1.0 - self.requests) / rpm);
+        }
+        // A request larger than the whole bucket only waits for a full bucket
+        let tokens = f64::from(tokens);
+        if let Some(tpm) = limit.tokens_per_minute.map(f64::from) {
+            wait_minutes = wait_minutes.max((tokens.min(tpm) - self.tokens) / tpm);
+        }
+        if wait_minutes > 0.0 {
+            return Some(Duration::from_secs_f64(wait_minutes * 60.0));
+        }
+
+        if limit.requests_per_minute.is_some() {
+            self.requests -= 1.0;
+        }
+        if limit.tokens_per_minute.is_some() {
+            self.tokens -= tokens;
+        }
+        None
+    }
+}
+
+/// Bucket levels of every model, shared between processes through a state file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RateLimitState {
+    #[serde(default)]
+    models: HashMap<String, ModelBuckets>,
+}
+
+/// Client-side limiter for requests and input tokens per minute, keyed by model
+///
+/// Concurrent tasks share one limiter through an `Arc`. With `shared` set in the
+/// configuration the bucket levels are kept in a state file instead, so all
+/// processes using the same directory draw from the same quota.
+#[derive(Debug)]
+struct RateLimiter {
+    limits: HashMap<String, ModelRateLimit>,
+    state: tokio::sync::Mutex<RateLimitState>,
+    /// State file shared with other processes
+    state_file: Option<PathBuf>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter from the configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The rate limit configuration
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<RateLimiter>, AppError>` - The limiter, `None` if no limits are configured, or an error
+    fn from_config(config: &RateLimitConfig) -> Result<Option<Self>, AppError> {
+        if config.models.is_empty() {
+            return Ok(None);
+        }
+        for (model, limit) in &config.models {
+            if limit.requests_per_minute == Some(0) || limit.tokens_per_minute == Some(0) {
+                return Err(AppError::ConfigError(format!("Rate limits of {} must be at least 1", model)));
+            }
+        }
+
+        let state_file = if config.shared {
+            let dir = config
+                .state_dir
+                .as_ref()
+                .map(PathBuf::from)
+                .or_else(cache_dir)
+                .ok_or_else(|| {
+                    AppError::ConfigError("No cache directory found for shared rate limits, set rate_limits.state_dir".to_string())
+                })?;
+            fs::create_dir_all(&dir)?;
+            Some(dir.join(RATE_LIMIT_STATE_FILE))
+        } else {
+            None
+        };
+        debug!("Using rate limits {:?} with state file {:?}", config.models, state_file);
+
+        Ok(Some(RateLimiter {
+            limits: config.models.clone(),
+            state: tokio::sync::Mutex::new(RateLimitState::default()),
+            state_file,
+        }))
+    }
+
+    /// Gets the limit of a model, falling back to the `*` entry
+    fn limit_for(&self, model: &str) -> Option<&ModelRateLimit> {
+        self.limits.get(model).or_else(|| self.limits.get("*"))
+    }
+
+    /// Applies a change to the bucket levels
+    ///
+    /// With a state file, the levels are read and written back under an exclusive
+    /// lock on the file, on a blocking thread so waiting for another process does
+    /// not stall the runtime.
+    ///
+    /// # Arguments
+    ///
+    /// * `change` - The change to apply
+    ///
+    /// # Returns
+    ///
+    /// * `Result<T, AppError>` - The result of the change or an error
+    async fn update<T: Send + 'static>(
+        &self,
+        change: impl FnOnce(&mut RateLimitState) -> T + Send + 'static,
+    ) -> Result<T, AppError> {
+        let mut state = self.state.lock().await;
+        let Some(path) = self.state_file.clone() else {
+            return Ok(change(&mut state));
+        };
+
+        tokio::task::spawn_blocking(move || update_state_file(&path, change))
+            .await
+            .map_err(|e| AppError::IoError(std::io::Error::other(e)))?
+    }
+
+    /// Takes a request from the buckets of a model without waiting
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model the request is sent to
+    /// * `tokens` - The estimated input tokens of the request
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Duration>, AppError>` - `None` if the request may be sent, otherwise how long to wait
+    async fn try_acquire(&self, model: &str, tokens: u32) -> Result<Option<Duration>, AppError> {
+        let Some(limit) = self.limit_for(model).cloned() else {
+            return Ok(None);
+        };
+        let now = unix_time_secs_f64();
+        let model = model.to_string();
+        self.update(move |state| {
+            state
+                .models
+                .entry(model)
+                .or_insert_with(|| ModelBuckets::full(&limit, now))
+                .take(&limit, tokens, now)
+        })
+        .await
+    }
+
+    /// Waits until a request may be sent to a model
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model the request is sent to
+    /// * `tokens` - The estimated input tokens of the request
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), AppError>` - Success once the request was taken, or an error
+    async fn acquire(&self, model: &str, tokens: u32) -> Result<(), AppError> {
+        while let Some(wait) = self.try_acquire(model, tokens).await? {
+            info!("Rate limit of model {} reached, waiting {:.1}s", model, wait.as_secs_f64());
+            println!("Rate limit of {} reached, waiting {:.1}s...", model, wait.as_secs_f64());
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+
+    /// Corrects the token bucket once the actual input tokens of a request are known
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model the request was sent to
+    /// * `estimated` - The tokens taken before sending
+    /// * `actual` - The input tokens reported by the API
+    async fn settle(&self, model: &str, estimated: u32, actual: u32) -> Result<(), AppError> {
+        if estimated == actual || self.limit_for(model).is_none_or(|limit| limit.tokens_per_minute.is_none()) {
+            return Ok(());
+        }
+        let model = model.to_string();
+        self.update(move |state| {
+            if let Some(buckets) = state.models.get_mut(&model) {
+                buckets.tokens -= f64::from(actual) - f64::from(estimated);
+            }
+        })
+        .await
+    }
+}
+
+/// Applies a change to the rate limit state file while holding an exclusive lock on it
+///
+/// The advisory lock blocks until other processes release it and is released
+/// when the file is closed, also if the process holding it dies.
+///
+/// # Arguments
+///
+/// * `path` - Path of the state file, created if missing
+/// * `change` - The change to apply
+///
+/// # Returns
+///
+/// * `Result<T, AppError>` - The result of the change or an error
+fn update_state_file<T>(path: &Path, change: impl FnOnce(&mut RateLimitState) -> T) -> Result<T, AppError> {
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    file.lock()?;
+
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+    let mut state = if text.trim().is_empty() {
+        RateLimitState::default()
+    } else {
+        serde_json::from_str(&text).unwrap_or_else(|e| {
+            warn!("Resetting invalid rate limit state {}: {}", path.display(), e);
+            RateLimitState::default()
+        })
+    };
+    let result = change(&mut state);
+
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(serde_json::to_string(&state)?.as_bytes())?;
+    Ok(result)
+}
+
+/// Gets the current time in seconds since the Unix epoch, with fractions
+fn unix_time_secs_f64() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs_f64()).unwrap_or_default()
+}
+
+/// Gets the per-user cache directory of the application
+fn cache_dir() -> Option<PathBuf> {
+    env::var("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| env::var("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|_| env::var("HOME").map(|home| Path::new(&home).join(".cache")))
+        .ok()
+        .map(|dir| dir.join("gemini-codemaker"))
+}
+
+/// One backend call stored as a JSON file in a cassette directory
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Interaction {
+    model: String,
+    /// SHA-256 of the call kind, the model and the request, used to find the reply on replay
+    request_hash: String,
+    request: serde_json::Value,
+    #[serde(flatten)]
+    exchange: Exchange,
+}
+
+/// Kind of a recorded backend call and its outcome
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Exchange {
+    GenerateContent {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        response: Option<serde_json::Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    CountTokens {
+        tokens: u32,
+        estimated: bool,
+    },
+    UploadFile {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        file: Option<FileData>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+impl Exchange {
+    /// Gets the name of the call kind, as used in file names and request hashes
+    fn kind(&self) -> &'static str {
+        match self {
+            Exchange::GenerateContent { .. } => "generate_content",
+            Exchange::CountTokens { .. } => "count_tokens",
+            Exchange::UploadFile { .. } => "upload_file",
+        }
+    }
+}
+
+/// Replaces every occurrence of the given secrets in a text
+fn redact(text: &str, secrets: &[String]) -> String {
+    secrets
+        .iter()
+        .filter(|secret| !secret.is_empty())
+        .fold(text.to_string(), |text, secret| text.replace(secret.as_str(), REDACTED))
+}
+
+/// Hashes a call so a replay can find the recorded reply to the same request
+///
+/// # Arguments
+///
+/// * `kind` - The call kind, see `Exchange::kind`
+/// * `model` - The model the request is sent to
+/// * `request` - The redacted request serialized as JSON
+///
+/// # Returns
+///
+/// * `String` - The hex encoded SHA-256 digest
+fn request_hash(kind: &str, model: &str, request: &str) -> String {
+    // The system information sent with chat requests names the machine and working
+    // directory, which must not keep a cassette from replaying elsewhere
+    let request = match serde_json::from_str::<serde_json::Value>(request) {
+        Ok(mut value) => {
+            mask_system_info(&mut value);
+            value.to_string()
+        }
+        Err(_) => request.to_string(),
+    };
+    let digest = Sha256::new()
+        .chain_update(kind)
+        .chain_update([0])
+        .chain_update(model)
+        .chain_update([0])
+        .chain_update(request)
+        .finalize();
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Replaces the machine-specific lines of system information texts in a request
+///
+/// # Arguments
+///
+/// * `value` - The request, changed in place
+fn mask_system_info(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::String(text) if text.starts_with(SYSTEM_INFO_HEADER) => {
+            *text = text
+                .split('\n')
+                .map(|line| match VOLATILE_SYSTEM_INFO.iter().find(|prefix| line.starts_with(*prefix)) {
+                    Some(prefix) => format!("{}*", prefix),
+                    None => line.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(mask_system_info),
+        serde_json::Value::Object(map) => map.values_mut().for_each(mask_system_info),
+        _ => {}
+    }
+}
+
+/// Serialized request of an upload, with the file content replaced by its hash
+fn upload_request(display_name: &str, mime_type: &str, bytes: &[u8]) -> String {
+    let digest = Sha256::digest(bytes);
+    json!({
+        "displayName": display_name,
+        "mimeType": mime_type,
+        "sizeBytes": bytes.len(),
+        "sha256": digest.iter().map(|byte| format!("{:02x}", byte)).collect::<String>(),
+    })
+    .to_string()
+}
+
+/// Backend that forwards every call and saves it to a cassette directory
+///
+/// Each call is written to its own file, numbered in call order, so a later
+/// run with `--replay` can answer the same requests without the network.
+/// Secrets such as API keys are replaced before anything is written.
+struct Recorder<'a> {
+    inner: &'a dyn LlmBackend,
+    dir: PathBuf,
+    secrets: Vec<String>,
+    recorded: Cell<usize>,
+}
+
+impl<'a> Recorder<'a> {
+    /// Creates a recorder writing to a directory, which is created if needed
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The backend the calls are forwarded to
+    /// * `dir` - The cassette directory
+    /// * `secrets` - Values that must not appear in the recording
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Recorder, AppError>` - The recorder or an error
+    fn new(inner: &'a dyn LlmBackend, dir: &Path, secrets: Vec<String>) -> Result<Self, AppError> {
+        fs::create_dir_all(dir)?;
+        // Continue the numbering of an earlier recording into the same directory
+        let recorded = cassette_files(dir)?.len();
+        info!("Recording interactions to {}", dir.display());
+
+        Ok(Recorder {
+            inner,
+            dir: dir.to_path_buf(),
+            secrets,
+            recorded: Cell::new(recorded),
+        })
+    }
+
+    /// Writes one interaction to the cassette directory
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request serialized as JSON
+    /// * `exchange` - The call kind and its outcome
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), AppError>` - Success or an error
+    fn save(&self, request: &str, exchange: Exchange) -> Result<(), AppError> {
+        let request = redact(request, &self.secrets);
+        let request_hash = request_hash(exchange.kind(), self.inner.model(), &request);
+        let number = self.recorded.get() + 1;
+        self.recorded.set(number);
+
+        let path = self
+            .dir
+            .join(format!("{:04}-{}-{}.json", number, exchange.kind(), &request_hash[..12]));
+        let interaction = Interaction {
+            model: self.inner.model().to_string(),
+            request_hash,
+            request: serde_json::from_str(&request)?,
+            exchange,
+        };
+        fs::write(&path, redact(&serde_json::to_string_pretty(&interaction)?, &self.secrets))?;
+        debug!("Recorded interaction {}", path.display());
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl LlmBackend for Recorder<'_> {
+    fn model(&self) -> &str {
+        self.inner.model()
+    }
+
+    fn input_token_limit(&self) -> u32 {
+        self.inner.input_token_limit()
+    }
+
+    async fn count_tokens(&self, request: &GenerateContentRequest) -> TokenCount {
+        let count = self.inner.count_tokens(request).await;
+        let exchange = Exchange::CountTokens {
+            tokens: count.tokens,
+            estimated: count.estimated,
+        };
+        match serde_json::to_string(request) {
+            Ok(body) => {
+                if let Err(e) = self.save(&body, exchange) {
+                    warn!("Failed to record token count: {}", e);
+                }
+            }
+            Err(e) => warn!("Failed to record token count: {}", e),
+        }
+        count
+    }
+
+    async fn send(
+        &self,
+        request: &GenerateContentRequest,
+        on_part: Option<&mut dyn for<'p> FnMut(&'p Part)>,
+    ) -> Result<GeminiApiResponse, AppError> {
+        let result = self.inner.send(request, on_part).await;
+        // The response is already billed, so a cassette that cannot be written
+        // must not turn it into an error
+        let saved = (|| {
+            let exchange = match &result {
+                Ok(response) => Exchange::GenerateContent {
+                    response: Some(serde_json::to_value(response)?),
+                    error: None,
+                },
+                Err(e) => Exchange::GenerateContent {
+                    response: None,
+                    error: Some(e.to_string()),
+                },
+            };
+            self.save(&serde_json::to_string(request)?, exchange)
+        })();
+        if let Err(e) = saved {
+            warn!("Failed to record response: {}", e);
+        }
+        result
+    }
+
+    async fn upload_file(&self, display_name: &str, mime_type: &str, bytes: Vec<u8>) -> Result<FileData, AppError> {
+        let request = upload_request(display_name, mime_type, &bytes);
+        let result = self.inner.upload_file(display_name, mime_type, bytes).await;
+        let exchange = match &result {
+            Ok(file) => Exchange::UploadFile {
+                file: Some(file.clone()),
+                error: None,
+            },
+            Err(e) => Exchange::UploadFile {
+                file: None,
+                error: Some(e.to_string()),
+            },
+        };
+        if let Err(e) = self.save(&request, exchange) {
+            warn!("Failed to record upload: {}", e);
+        }
+        result
+    }
+}
+
+/// Backend that answers every call from a cassette directory written with `--record`
+///
+/// A call gets the reply recorded for the same request hash, and a request that
+/// differs from the recording fails. In lenient mode such a request gets the
+/// next unplayed reply of the same kind in recorded order instead.
+struct Replayer {
+    dir: PathBuf,
+    model: String,
+    input_token_limit: u32,
+    secrets: Vec<String>,
+    lenient: bool,
+    interactions: Vec<Interaction>,
+    played: RefCell<Vec<bool>>,
+}
+
+impl Replayer {
+    /// Loads all interactions of a cassette directory
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The cassette directory
+    /// * `secrets` - Values redacted from requests before hashing, as when recording
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Replayer, AppError>` - The replayer or an error
+    fn load(dir: &Path, secrets: Vec<String>) -> Result<Self, AppError> {
+        let files = cassette_files(dir)
+            .map_err(|e| AppError::ConfigError(format!("Failed to read cassette directory {}: {}", dir.display(), e)))?;
+
+        let mut interactions = Vec::new();
+        for path in &files {
+            let text = fs::read_to_string(path)?;
+            let interaction = serde_json::from_str::<Interaction>(&text).map_err(|e| {
+                AppError::ConfigError(format!("Invalid recorded interaction {}: {}", path.display(), e))
+            })?;
+            interactions.push(interaction);
+        }
+
+        let model = interactions
+            .first()
+            .map(|interaction| interaction.model.clone())
+            .ok_or_else(|| AppError::ConfigError(format!("No recorded interactions in {}", dir.display())))?;
+        info!("Replaying {} interactions of model {} from {}", interactions.len(), model, dir.display());
+
+        Ok(Replayer {
+            dir: dir.to_path_buf(),
+            model,
+            input_token_limit: DEFAULT_INPUT_TOKEN_LIMIT,
+            secrets,
+            lenient: false,
+            played: RefCell::new(vec![false; interactions.len()]),
+            interactions,
+        })
+    }
+
+    /// Sets the maximum number of input tokens checked before sending a request
+    fn with_input_token_limit(mut self, input_token_limit: u32) -> Self {
+        self.input_token_limit = input_token_limit;
+        self
+    }
+
+    /// Replays the next unplayed reply of the same kind when no recorded request matches
+    fn with_lenient_matching(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Finds the recorded reply to a call
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - The call kind, see `Exchange::kind`
+    /// * `request` - The request serialized as JSON
+    ///
+    /// # Returns
+    ///
+    /// * `Result<&Exchange, AppError>` - The recorded outcome or an error if none is left
+    fn replay(&self, kind: &str, request: &str) -> Result<&Exchange, AppError> {
+        let request_hash = request_hash(kind, &self.model, &redact(request, &self.secrets));
+        let mut played = self.played.borrow_mut();
+        let of_kind = |index: &usize| self.interactions[*index].exchange.kind() == kind;
+        let matches = |index: &usize| self.interactions[*index].request_hash == request_hash;
+
+        // Prefer an unplayed match so identical requests get their replies in order
+        let all = 0..self.interactions.len();
+        let index = match all.clone().filter(of_kind).filter(matches).find(|index| !played[*index]) {
+            Some(index) => index,
+            None => match all.clone().filter(of_kind).find(matches) {
+                Some(index) => index,
+                None if !self.lenient => {
+                    return Err(AppError::ApiError(format!(
+                        "No recorded {} interaction in {} matches request {}; record it again or replay with --replay-lenient",
+                        kind,
+                        self.dir.display(),
+                        request_hash
+                    )));
+                }
+                None => {
+                    let index = all.filter(of_kind).find(|index| !played[*index]).ok_or_else(|| {
+                        AppError::ApiError(format!("No recorded {} interaction left in {}", kind, self.dir.display()))
+                    })?;
+                    warn!(
+                        "No recorded {} interaction matches request {}, replaying interaction {} in recorded order",
+                        kind,
+                        request_hash,
+                        index + 1
+                    );
+                    index
+                }
+            },
+        };
+
+        played[index] = true;
+        debug!("Replaying interaction {} for {} request {}", index + 1, kind, request_hash);
+        Ok(&self.interactions[index].exchange)
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl LlmBackend for Replayer {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn input_token_limit(&self) -> u32 {
+        self.input_token_limit
+    }
+
+    async fn count_tokens(&self, request: &GenerateContentRequest) -> TokenCount {
+        let replayed = serde_json::to_string(request)
+            .map_err(AppError::from)
+            .and_then(|body| self.replay("count_tokens", &body));
+        match replayed {
+            Ok(Exchange::CountTokens { tokens, estimated }) => TokenCount {
+                tokens: *tokens,
+                estimated: *estimated,
+            },
+            Ok(_) => unreachable!("interactions are looked up by kind"),
+            Err(e) => {
+                warn!("Estimating tokens locally: {}", e);
+                TokenCount {
+                    tokens: estimate_tokens(request),
+                    estimated: true,
+                }
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        request: &GenerateContentRequest,
+        on_part: Option<&mut dyn for<'p> FnMut(&'p Part)>,
+    ) -> Result<GeminiApiResponse, AppError> {
+        let Exchange::GenerateContent { response, error } =
+            self.replay("generate_content", &serde_json::to_string(request)?)?
+        else {
+            unreachable!("interactions are looked up by kind");
+        };
+        if let Some(error) = error {
+            return Err(AppError::ApiError(format!("Recorded request failed: {}", error)));
+        }
+
+        let response = serde_json::from_value::<GeminiApiResponse>(response.clone().unwrap_or_else(|| json!({})))?;
+        if let Some(on_part) = on_part {
+            for candidate in response.candidates.iter().flatten() {
+                candidate.content.parts.iter().for_each(&mut *on_part);
+            }
+        }
+        Ok(response)
+    }
+
+    async fn upload_file(&self, display_name: &str, mime_type: &str, bytes: Vec<u8>) -> Result<FileData, AppError> {
+        let Exchange::UploadFile { file, error } =
+            self.replay("upload_file", &upload_request(display_name, mime_type, &bytes))?
+        else {
+            unreachable!("interactions are looked up by kind");
+        };
+        match (file, error) {
+            (Some(file), _) => Ok(file.clone()),
+            (None, error) => Err(AppError::AttachmentError(format!(
+                "Recorded upload failed: {}",
+                error.as_deref().unwrap_or("no file was recorded")
+            ))),
+        }
+    }
+}
+
+/// Lists the interaction files of a cassette directory in recorded order
+fn cassette_files(dir: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut files = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.retain(|path| path.extension().is_some_and(|extension| extension == "json"));
+    files.sort();
+    Ok(files)
+}
+
+/// Scripted conversation served by the `mock-server` subcommand
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MockScenario {
+    /// Replies to `generateContent` and `streamGenerateContent` requests, in order
+    steps: Vec<MockStep>,
+    /// Start over at the first step once every step was served
+    #[serde(default)]
+    repeat: bool,
+}
+
+/// One scripted reply of the mock server
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum MockStep {
+    /// Model answer with text, chat commands or both
+    Reply {
+        /// Text of the answer; defaults to the commands as a JSON block
+        text: Option<String>,
+        /// Chat commands such as `{"type": "create_folder", "path": "hello"}`, sent as
+        /// function calls when the request declares functions
+        #[serde(default)]
+        commands: Vec<serde_json::Value>,
+        /// Message to the user sent along with the commands
+        user_message: Option<String>,
+        /// Cut the text after this many characters and finish with `MAX_TOKENS`
+        truncate_at: Option<usize>,
+        /// Finish reason, defaults to `STOP` or `MAX_TOKENS` for truncated answers
+        finish_reason: Option<String>,
+    },
+    /// HTTP error such as 429 or 503 in the API's error format
+    Error {
+        status: u16,
+        message: Option<String>,
+        /// Delay in seconds sent as `RetryInfo`
+        retry_after: Option<u64>,
+    },
+    /// Prompt rejected by the safety filters
+    Blocked {
+        /// Block reason, defaults to `SAFETY`
+        block_reason: Option<String>,
+    },
+    /// Response body sent as it is
+    Response { body: serde_json::Value },
+}
+
+impl MockScenario {
+    /// Loads a scenario from a JSON file, or a YAML file with a `.yaml` or `.yml` extension
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the scenario file
+    ///
+    /// # Returns
+    ///
+    /// * `Result<MockScenario, AppError>` - The scenario or an error
+    fn load(path: &str) -> Result<Self, AppError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| AppError::ConfigError(format!("Failed to read {}: {}", path, e)))?;
+        let invalid = |e: &dyn std::fmt::Display| AppError::ConfigError(format!("Invalid scenario in {}: {}", path, e));
+        let is_yaml = Path::new(path)
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("yaml") || extension.eq_ignore_ascii_case("yml"));
+        let scenario: MockScenario = if is_yaml {
+            serde_norway::from_str(&contents).map_err(|e| invalid(&e))?
+        } else {
+            serde_json::from_str(&contents).map_err(|e| invalid(&e))?
+        };
+        if scenario.steps.is_empty() {
+            return Err(AppError::ConfigError(format!("Scenario {} has no steps", path)));
+        }
+        debug!("Loaded scenario with {} steps from {}", scenario.steps.len(), path);
+        Ok(scenario)
+    }
+}
+
+/// Response of the mock server
+#[derive(Debug)]
+struct MockResponse {
+    status: u16,
+    /// Body, or the chunks sent as server-sent events for a streamed request
+    chunks: Vec<serde_json::Value>,
+    stream: bool,
+}
+
+impl MockResponse {
+    /// Creates a JSON response
+    fn json(status: u16, body: serde_json::Value) -> Self {
+        MockResponse {
+            status,
+            chunks: vec![body],
+            stream: false,
+        }
+    }
+
+    /// Creates a successful model response, streamed as events if requested
+    fn model(chunks: Vec<serde_json::Value>, stream: bool) -> Self {
+        MockResponse { status: 200, chunks, stream }
+    }
+
+    /// Creates an error in the API's error format
+    fn error(status: u16, message: &str, retry_after: Option<u64>) -> Self {
+        let code = match status {
+            400 => "INVALID_ARGUMENT",
+            401 => "UNAUTHENTICATED",
+            403 => "PERMISSION_DENIED",
+            404 => "NOT_FOUND",
+            429 => "RESOURCE_EXHAUSTED",
+            500 => "INTERNAL",
+            503 => "UNAVAILABLE",
+            504 => "DEADLINE_EXCEEDED",
+            _ => "UNKNOWN",
+        };
+        let details = retry_after
+            .map(|seconds| {
+                vec![json!({
+                    "@type": "type.googleapis.com/google.rpc.RetryInfo",
+                    "retryDelay": format!("{}s", seconds),
+                })]
+            })
+            .unwrap_or_default();
+        MockResponse::json(
+            status,
+            json!({"error": {"code": status, "message": message, "status": code, "details": details}}),
+        )
+    }
+}
+
+/// Local server answering Gemini API requests from a scenario
+struct MockServer {
+    scenario: MockScenario,
+    next_step: usize,
+}
+
+impl MockServer {
+    /// Creates a server for a scenario
+    fn new(scenario: MockScenario) -> Self {
+        MockServer {
+            scenario,
+            next_step: 0,
+        }
+    }
+
+    /// Answers requests until the listener fails
+    ///
+    /// Connections are handled one at a time so the steps are served in order.
+    ///
+    /// # Arguments
+    ///
+    /// * `listener` - The bound listener
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), AppError>` - An error if accepting a connection fails
+    async fn serve(mut self, listener: tokio::net::TcpListener) -> Result<(), AppError> {
+        loop {
+            let (mut socket, peer) = listener.accept().await?;
+            if let Err(e) = self.handle(&mut socket).await {
+                warn!("Mock server failed to answer {}: {}", peer, e);
+            }
+        }
+    }
+
+    /// Reads one request from a connection and writes the response
+    async fn handle(&mut self, socket: &mut tokio::net::TcpStream) -> Result<(), AppError> {
+        use tokio::io::AsyncWriteExt;
+
+        let (method, path, body) = read_http_request(socket).await?;
+        let response = self.respond(&method, &path, &body);
+        println!("{} {} -> {}", method, path, response.status);
+
+        let reason = StatusCode::from_u16(response.status)
+            .ok()
+            .and_then(|status| status.canonical_reason())
+            .unwrap_or("Unknown");
+        if response.stream && response.status == 200 {
+            let head = format!(
+                "HTTP/1.1 200 {}\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n",
+                reason
+            );
+            socket.write_all(head.as_bytes()).await?;
+            for chunk in &response.chunks {
+                socket.write_all(format!("data: {}\r\n\r\n", chunk).as_bytes()).await?;
+                socket.flush().await?;
+            }
+        } else {
+            let body = response.chunks.first().map(|chunk| chunk.to_string()).unwrap_or_default();
+            let head = format!(
+                "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                response.status,
+                reason,
+                body.len()
+            );
+            socket.write_all(head.as_bytes()).await?;
+            socket.write_all(body.as_bytes()).await?;
+        }
+        socket.shutdown().await?;
+        Ok(())
+    }
+
+    /// Routes a request to its response
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - The HTTP method
+    /// * `path` - The request path, possibly with a query
+    /// * `body` - The request body
+    ///
+    /// # Returns
+    ///
+    /// * `MockResponse` - The response to send
+    fn respond(&mut self, method: &str, path: &str, body: &str) -> MockResponse {
+        let path = path.split('?').next().unwrap_or_default();
+        let Some((_, resource)) = path.split_once("/models") else {
+            return MockResponse::error(404, &format!("{} is not served by the mock server", path), None);
+        };
+        let resource = resource.trim_start_matches('/');
+
+        match (method, resource.split_once(':')) {
+            ("GET", None) if resource.is_empty() => {
+                MockResponse::json(200, json!({"models": [mock_model_info(DEFAULT_GEMINI_MODEL)]}))
+            }
+            ("GET", None) => MockResponse::json(200, mock_model_info(resource)),
+            ("POST", Some((model, "countTokens"))) => {
+                debug!("Counting tokens for model {}", model);
+                MockResponse::json(200, json!({"totalTokens": mock_token_count(body)}))
+            }
+            ("POST", Some((model, "embedContent"))) => {
+                debug!("Embedding a text with model {}", model);
+                let request = serde_json::from_str::<serde_json::Value>(body).unwrap_or_default();
+                MockResponse::json(200, json!({"embedding": mock_embedding(&request)}))
+            }
+            ("POST", Some((model, "batchEmbedContents"))) => {
+                let request = serde_json::from_str::<serde_json::Value>(body).unwrap_or_default();
+                let requests = request["requests"].as_array().cloned().unwrap_or_default();
+                debug!("Embedding {} texts with model {}", requests.len(), model);
+                let embeddings: Vec<_> = requests.iter().map(mock_embedding).collect();
+                MockResponse::json(200, json!({"embeddings": embeddings}))
+            }
+            ("POST", Some((model, method @ ("generateContent" | "streamGenerateContent")))) => {
+                let request = serde_json::from_str::<serde_json::Value>(body).unwrap_or_default();
+                match self.next_step() {
+                    Some(step) => mock_step_response(&step, &request, model, method == "streamGenerateContent"),
+                    None => MockResponse::error(400, "The mock scenario has no steps left", None),
+                }
+            }
+            _ => MockResponse::error(404, &format!("{} {} is not served by the mock server", method, path), None),
+        }
+    }
+
+    /// Takes the next step of the scenario, or `None` when all were served
+    fn next_step(&mut self) -> Option<MockStep> {
+        if self.next_step == self.scenario.steps.len() && self.scenario.repeat {
+            self.next_step = 0;
+        }
+        let step = self.scenario.steps.get(self.next_step)?.clone();
+        self.next_step += 1;
+        info!("Serving step {} of the scenario", self.next_step);
+        Some(step)
+    }
+}
+
+/// Reads an HTTP/1.1 request with a `Content-Length` body
+///
+/// # Arguments
+///
+/// * `socket` - The connection to read from
+///
+/// # Returns
+///
+/// * `Result<(String, String, String), AppError>` - The method, path and body, or an error
+async fn read_http_request(socket: &mut tokio::net::TcpStream) -> Result<(String, String, String), AppError> {
+    use tokio::io::AsyncReadExt;
+
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 8192];
+    let header_end = loop {
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+        let read = socket.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(AppError::ResponseError("Connection closed before the request was complete".to_string()));
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+
+    while buffer.len() < header_end + content_length {
+        let read = socket.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(AppError::ResponseError("Connection closed before the body was complete".to_string()));
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+    let body = String::from_utf8_lossy(&buffer[header_end..header_end + content_length]).to_string();
+    Ok((method, path, body))
+}
+
+/// Describes a mock model that supports everything the subcommands need
+fn mock_model_info(name: &str) -> serde_json::Value {
+    let id = name.trim_start_matches("models/");
+    json!({
+        "name": format!("models/{}", id),
+        "displayName": format!("Mock {}", id),
+        "inputTokenLimit": DEFAULT_INPUT_TOKEN_LIMIT,
+        "outputTokenLimit": 65536,
+        "supportedGenerationMethods": ["generateContent", "countTokens"],
+        "thinking": true,
+    })
+}
+
+/// Roughly counts tokens the way the API would, at four characters per token
+fn mock_token_count(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Embeds the text of an `embedContent` request as a normalized bag of words
+///
+/// Every word is hashed into one of `outputDimensionality` buckets (768 if not set),
+/// so texts sharing words get similar vectors.
+///
+/// # Arguments
+///
+/// * `request` - The `embedContent` request body, or one entry of a batch
+///
+/// # Returns
+///
+/// * `serde_json::Value` - The `ContentEmbedding` with its `values`
+fn mock_embedding(request: &serde_json::Value) -> serde_json::Value {
+    let dimensions = request["outputDimensionality"]
+        .as_u64()
+        .unwrap_or(u64::from(DEFAULT_EMBEDDING_DIMENSIONS))
+        .max(1) as usize;
+    let text: String = request["content"]["parts"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|part| part["text"].as_str())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let mut values = vec![0.0f32; dimensions];
+    for word in text.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()) {
+        let digest = Sha256::digest(word.to_lowercase());
+        let bucket = u64::from_le_bytes(digest[..8].try_into().unwrap_or_default()) as usize % dimensions;
+        values[bucket] += 1.0;
+    }
+    let norm = values.iter().map(|value| value * value).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        values.iter_mut().for_each(|value| *value /= norm);
+    }
+
+    json!({"values": values})
+}
+
+/// Builds the response for a scenario step
+///
+/// # Arguments
+///
+/// * `step` - The scenario step
+/// * `request` - The `generateContent` request body
+/// * `model` - The model named in the request path
+/// * `stream` - Whether the request asked for a stream
+///
+/// # Returns
+///
+/// * `MockResponse` - The response to send
+fn mock_step_response(step: &MockStep, request: &serde_json::Value, model: &str, stream: bool) -> MockResponse {
+    let prompt_tokens = mock_token_count(&request.to_string());
+
+    match step {
+        MockStep::Reply { text, commands, user_message, truncate_at, finish_reason } => {
+            // Commands are function calls when the request declares functions, JSON otherwise
+            let declares_functions = request["tools"]
+                .as_array()
+                .is_some_and(|tools| tools.iter().any(|tool| tool.get("functionDeclarations").is_some()));
+            let mut function_calls = Vec::new();
+            let mut text = text.clone();
+            if declares_functions {
+                for command in commands {
+                    let mut args = command.as_object().cloned().unwrap_or_default();
+                    let name = args.remove("type").and_then(|t| t.as_str().map(str::to_string)).unwrap_or_default();
+                    function_calls.push(json!({"functionCall": {"name": name, "args": args}}));
+                }
+                text = text.or_else(|| user_message.clone());
+            } else if text.is_none() && (!commands.is_empty() || user_message.is_some()) {
+                let reply = json!({"commands": commands, "user_message": user_message.clone().unwrap_or_default()});
+                text = Some(format!("```json\n{}\n```", serde_json::to_string_pretty(&reply).unwrap_or_default()));
+            }
+
+            let mut text = text.unwrap_or_default();
+            if let Some(limit) = truncate_at {
+                text = text.chars().take(*limit).collect();
+                function_calls.clear();
+            }
+            let finish_reason = finish_reason
+                .clone()
+                .unwrap_or_else(|| if truncate_at.is_some() { "MAX_TOKENS" } else { "STOP" }.to_string());
+            let candidate_tokens = mock_token_count(&text) + function_calls.len() * 10;
+            let usage = json!({
+                "promptTokenCount": prompt_tokens,
+                "candidatesTokenCount": candidate_tokens,
+                "totalTokenCount": prompt_tokens + candidate_tokens,
+            });
+
+            // A stream sends the text line by line and finishes with the function calls and usage
+            let candidate = |parts: Vec<serde_json::Value>| {
+                json!({
+                    "candidates": [{"content": {"role": "model", "parts": parts}, "index": 0}],
+                    "modelVersion": model,
+                })
+            };
+            let mut chunks = if stream {
+                let mut chunks: Vec<_> = text.split_inclusive('\n').map(|line| candidate(vec![json!({"text": line})])).collect();
+                chunks.push(candidate(function_calls));
+                chunks
+            } else {
+                let mut parts = Vec::new();
+                if !text.is_empty() || function_calls.is_empty() {
+                    parts.push(json!({"text": text}));
+                }
+                parts.extend(function_calls);
+                vec![candidate(parts)]
+            };
+
+            let last = chunks.last_mut().expect("a response has at least one chunk");
+            last["candidates"][0]["finishReason"] = json!(finish_reason);
+            last["usageMetadata"] = usage;
+            MockResponse::model(chunks, stream)
+        }
+        MockStep::Error { status, message, retry_after } => {
+            let message = message.clone().unwrap_or_else(|| match status {
+                429 => "Resource has been exhausted (e.g. check quota).".to_string(),
+                _ => format!("Mock error with status {}", status),
+            });
+            MockResponse::error(*status, &message, *retry_after)
+        }
+        MockStep::Blocked { block_reason } => MockResponse::model(
+            vec![json!({
+                "promptFeedback": {"blockReason": block_reason.as_deref().unwrap_or("SAFETY")},
+                "usageMetadata": {"promptTokenCount": prompt_tokens, "totalTokenCount": prompt_tokens},
+                "modelVersion": model,
+            })],
+            stream,
+        ),
+        MockStep::Response { body } => MockResponse::model(vec![body.clone()], stream),
+    }
+}
+
+/// Starts the mock server and answers requests until it is stopped
+///
+/// # Arguments
+///
+/// * `scenario` - The scenario to serve
+/// * `address` - The address to listen on, e.g. `127.0.0.1:8080`
+///
+/// # Returns
+///
+/// * `Result<(), AppError>` - An error if the server cannot listen or accept connections
+async fn run_mock_server(scenario: MockScenario, address: &str) -> Result<(), AppError> {
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    let address = listener.local_addr()?;
+    println!(
+        "Mock Gemini server with {} scenario steps listening on http://{}",
+        scenario.steps.len(),
+        address
+    );
+    println!("Point the CLI at it with {}=http://{}/v1beta", GEMINI_API_ENDPOINT_ENV_VAR, address);
+    MockServer::new(scenario).serve(listener).await
+}
+
+/// Request body for the `countTokens` endpoint
+///
+/// The Gemini API expects the counted request wrapped, Vertex AI takes its fields directly.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum CountTokensRequest<'a> {
+    #[serde(rename_all = "camelCase")]
+    Wrapped { generate_content_request: CountedRequest<'a> },
+    Flat(CountedRequest<'a>),
+}
+
+/// The parts of a `generateContent` request that count towards the input tokens
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CountedRequest<'a> {
+    model: String,
+    contents: &'a [Content],
+    #[serde(skip_serializing_if = "<[Tool]>::is_empty")]
+    tools: &'a [Tool],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<&'a Content>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cached_content: Option<&'a str>,
+}
+
+/// Response of the `countTokens` endpoint
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CountTokensResponse {
+    total_tokens: u32,
+}
+
+/// Response of the Files API upload
+#[derive(Debug, Deserialize)]
+struct UploadFileResponse {
+    file: UploadedFile,
+}
+
+/// File stored with the Files API
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadedFile {
+    name: String,
+    uri: String,
+    mime_type: Option<String>,
+    /// `PROCESSING`, `ACTIVE` or `FAILED`
+    state: Option<String>,
+}
+
+/// Request body for creating a cached content
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateCachedContentRequest {
+    model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+    contents: Vec<Content>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<Content>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool>,
+    /// Time to live, e.g. `3600s`
+    ttl: String,
+}
+
+/// Cached content stored with the `cachedContents` API
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedContent {
+    name: String,
+    model: Option<String>,
+    display_name: Option<String>,
+    expire_time: Option<String>,
+    usage_metadata: Option<CachedContentUsage>,
+}
+
+/// Size of a cached content
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedContentUsage {
+    total_token_count: Option<u32>,
+}
+
+/// One page of the cached contents list
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct CachedContentList {
+    cached_contents: Vec<CachedContent>,
+    next_page_token: Option<String>,
+}
+
+/// Request body for the `embedContent` endpoint, also one entry of a batch
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EmbedContentRequest {
+    /// Embedding model resource, e.g. `models/gemini-embedding-001`
+    model: String,
+    content: Content,
+    /// `RETRIEVAL_DOCUMENT` for indexed chunks, `RETRIEVAL_QUERY` for queries
+    task_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_dimensionality: Option<u32>,
+}
+
+/// Request body for the `batchEmbedContents` endpoint
+#[derive(Debug, Serialize)]
+struct BatchEmbedContentsRequest {
+    requests: Vec<EmbedContentRequest>,
+}
+
+/// Response of the `embedContent` endpoint
+#[derive(Debug, Deserialize)]
+struct EmbedContentResponse {
+    embedding: ContentEmbedding,
+}
+
+/// Response of the `batchEmbedContents` endpoint
+#[derive(Debug, Deserialize)]
+struct BatchEmbedContentsResponse {
+    #[serde(default)]
+    embeddings: Vec<ContentEmbedding>,
+}
+
+/// Embedding vector of one text
+#[derive(Debug, Deserialize)]
+struct ContentEmbedding {
+    #[serde(default)]
+    values: Vec<f32>,
+}
+
+impl CachedContent {
+    /// Parses the expiry time of the cache
+    fn expires_at(&self) -> Option<jiff::Timestamp> {
+        self.expire_time.as_deref()?.parse().ok()
+    }
+
+    /// Gets the time left until the cache expires, zero once it has expired
+    fn remaining(&self) -> Option<Duration> {
+        let remaining = self.expires_at()?.duration_since(jiff::Timestamp::now());
+        Some(Duration::try_from(remaining).unwrap_or(Duration::ZERO))
+    }
+
+    /// Describes the cache in one line for listings and status messages
+    fn summary(&self) -> String {
+        let tokens = self
+            .usage_metadata
+            .as_ref()
+            .and_then(|usage| usage.total_token_count)
+            .map_or_else(|| "? tokens".to_string(), |tokens| format!("{} tokens", tokens));
+        let expiry = match (self.expire_time.as_deref(), self.remaining()) {
+            (Some(time), Some(remaining)) if remaining.is_zero() => format!("expired at {}", time),
+            (Some(time), Some(remaining)) => format!("expires at {} (in {} min)", time, remaining.as_secs().div_ceil(60)),
+            (Some(time), None) => format!("expires at {}", time),
+            (None, _) => "no expiry".to_string(),
+        };
+        format!(
+            "{}{} [{}] {}, {}",
+            self.name,
+            self.display_name
+                .as_deref()
+                .filter(|name| !name.is_empty())
+                .map(|name| format!(" \"{}\"", name))
+                .unwrap_or_default(),
+            self.model.as_deref().unwrap_or("unknown model"),
+            tokens,
+            expiry
+        )
+    }
+}
+
+/// Normalizes a cache name to its resource name, e.g. `cachedContents/abc`
+///
+/// Full Vertex AI names starting with `projects/` are kept as they are.
+fn cache_resource_name(name: &str) -> String {
+    if name.starts_with("cachedContents/") || name.starts_with("projects/") {
+        name.to_string()
+    } else {
+        format!("cachedContents/{}", name)
+    }
+}
+
+/// Model description returned by the `models` endpoint
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ModelInfo {
+    name: String,
+    display_name: Option<String>,
+    description: Option<String>,
+    version: Option<String>,
+    input_token_limit: Option<u32>,
+    output_token_limit: Option<u32>,
+    #[serde(default)]
+    supported_generation_methods: Vec<String>,
+    temperature: Option<f64>,
+    max_temperature: Option<f64>,
+    top_p: Option<f64>,
+    top_k: Option<u32>,
+    thinking: Option<bool>,
+}
+
+/// One page of the `models` listing
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ModelList {
+    #[serde(default)]
+    models: Vec<ModelInfo>,
+    next_page_token: Option<String>,
+}
+
+impl ModelInfo {
+    /// Gets the model name without the `models/` prefix
+    fn id(&self) -> &str {
+        self.name.trim_start_matches("models/")
+    }
+
+    /// Checks whether the model supports an API method, e.g. `generateContent`
+    fn supports(&self, method: &str) -> bool {
+        self.supported_generation_methods.iter().any(|supported| supported == method)
+    }
+
+    /// Guesses whether the model can run the code execution tool
+    ///
+    /// The `models` endpoint does not list tools, so this goes by model family:
+    /// Gemini 1.5 and later support code execution, while Gemini 1.0, Gemma and
+    /// the speech, image and live variants do not. Aliases and tuned models can
+    /// be guessed wrong, so the result is only used for warnings.
+    fn likely_supports_code_execution(&self) -> bool {
+        let id = self.id();
+        id.starts_with("gemini-")
+            && !id.starts_with("gemini-1.0")
+            && !id.starts_with("gemini-pro")
+            && !["-tts", "-image", "-live", "embedding"].iter().any(|variant| id.contains(variant))
+    }
+
+    /// Describes the model in one line for listings
+    fn summary(&self) -> String {
+        format!(
+            "{:<40} in {:>8} / out {:>6} tokens{}  {}",
+            self.id(),
+            self.input_token_limit.map_or_else(|| "?".to_string(), |limit| limit.to_string()),
+            self.output_token_limit.map_or_else(|| "?".to_string(), |limit| limit.to_string()),
+            if self.thinking == Some(true) { "  thinking" } else { "" },
+            self.supported_generation_methods.join(", ")
+        )
+    }
+
+    /// Describes the model's limits and capabilities over several lines
+    fn details(&self) -> String {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        [
+            format!("Name:                 {}", self.id()),
+            format!("Display name:         {}", optional(self.display_name.clone())),
+            format!("Version:              {}", optional(self.version.clone())),
+            format!("Description:          {}", optional(self.description.clone())),
+            format!("Input token limit:    {}", optional(self.input_token_limit.map(|limit| limit.to_string()))),
+            format!("Output token limit:   {}", optional(self.output_token_limit.map(|limit| limit.to_string()))),
+            format!("Generation methods:   {}", self.supported_generation_methods.join(", ")),
+            format!("Thinking:             {}", if self.thinking == Some(true) { "yes" } else { "no" }),
+            format!(
+                "Code execution:       {} (guessed from the model name)",
+                if self.likely_supports_code_execution() { "likely" } else { "unlikely" }
+            ),
+            format!(
+                "Defaults:             temperature {} (max {}), top_p {}, top_k {}",
+                optional(self.temperature.map(|value| value.to_string())),
+                optional(self.max_temperature.map(|value| value.to_string())),
+                optional(self.top_p.map(|value| value.to_string())),
+                optional(self.top_k.map(|value| value.to_string()))
+            ),
+        ]
+        .join("\n")
+    }
+}
+
+/// Checks that a model can serve a subcommand with the requested settings
+///
+/// # Arguments
+///
+/// * `model` - The model description from the `models` endpoint
+/// * `command` - The subcommand about to run
+/// * `generation` - The generation settings in use
+///
+/// # Returns
+///
+/// * `Result<(), AppError>` - An error naming the missing capability
+fn check_model_capabilities(model: &ModelInfo, command: &Commands, generation: &GenerationSettings) -> Result<(), AppError> {
+    let required_method = match command {
+        Commands::Cache { action: CacheAction::Create { .. } } => Some("createCachedContent"),
+        Commands::Cache { .. } | Commands::Models { .. } | Commands::Index { .. } => None,
+        _ => Some("generateContent"),
+    };
+    if let Some(method) = required_method {
+        if !model.supports(method) {
+            return Err(AppError::ConfigError(format!(
+                "Model {} does not support {} (supported: {}). Run `models list` to pick another model",
+                model.id(),
+                method,
+                model.supported_generation_methods.join(", ")
+            )));
+        }
+    }
+
+    if matches!(command, Commands::Execute { .. }) && !model.likely_supports_code_execution() {
+        warn!("Model {} may not support code execution, which the execute subcommand requires", model.id());
+    }
+
+    if let (Some(requested), Some(limit)) = (generation.max_output_tokens, model.output_token_limit) {
+        if requested > limit {
+            return Err(AppError::ConfigError(format!(
+                "max_output_tokens {} is above the output limit of {} tokens of model {}",
+                requested,
+                limit,
+                model.id()
+            )));
+        }
+    }
+
+    if model.thinking != Some(true) && (generation.thinking_budget.is_some() || generation.include_thoughts == Some(true)) {
+        warn!("Model {} does not report thinking support; thinking settings may be rejected", model.id());
+    }
+
+    Ok(())
+}
+
+/// Number of input tokens of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TokenCount {
+    tokens: u32,
+    /// Whether the count comes from the local estimator instead of the API
+    estimated: bool,
+}
+
+impl TokenCount {
+    /// Checks whether the count is above the given number of tokens
+    fn exceeds(&self, tokens: u32) -> bool {
+        self.tokens > tokens
+    }
+}
+
+/// Estimates the input tokens of a request without calling the API
+///
+/// Assumes about four bytes per token over the serialized contents, system
+/// instruction and tools. The JSON syntax is counted too, so the estimate errs
+/// on the high side. Attached files count as a fixed number of tokens, as an
+/// image does, instead of by their base64 size.
+///
+/// # Arguments
+///
+/// * `request` - The request to estimate
+///
+/// # Returns
+///
+/// * `u32` - The estimated number of input tokens
+fn estimate_tokens(request: &GenerateContentRequest) -> u32 {
+    let mut bytes = serde_json::to_string(&request.tools).map_or(0, |json| json.len())
+        + serde_json::to_string(&request.system_instruction).map_or(0, |json| json.len());
+    let mut attachments = 0;
+    for part in request.contents.iter().flat_map(|content| &content.parts) {
+        match part {
+            Part::InlineData { .. } | Part::FileData { .. } => attachments += 1,
+            part => bytes += serde_json::to_string(part).map_or(0, |json| json.len()),
+        }
+    }
+    (bytes.div_ceil(4) + attachments * ATTACHMENT_TOKEN_ESTIMATE)
+        .try_into()
+        .unwrap_or(u32::MAX)
+}
+
+/// Estimates the input tokens of content sent for embedding, from its serialized size
+fn estimate_text_tokens(content: &Content) -> u32 {
+    serde_json::to_string(content)
+        .map_or(0, |json| json.len().div_ceil(4))
+        .try_into()
+        .unwrap_or(u32::MAX)
+}
+
+/// Buffer splitting a server-sent event stream into `data` payloads
+///
+/// Bytes are buffered until a blank line terminates an event, so events and
+/// multi-byte characters split across network chunks are handled correctly.
+#[derive(Debug, Default)]
+struct SseBuffer {
+    buffer: Vec<u8>,
+}
+
+impl SseBuffer {
+    /// Appends raw bytes received from the network
+    fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend(bytes.iter().filter(|&&b| b != b'\r'));
+    }
+
+    /// Takes the `data` payload of the next complete event, if any
+    fn next_data(&mut self) -> Option<String> {
+        loop {
+            let end = self.buffer.windows(2).position(|w| w == b"\n\n")?;
+            let event: Vec<u8> = self.buffer.drain(..end + 2).collect();
+            let event = String::from_utf8_lossy(&event);
+
+            let data = event
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(str::trim_start)
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            if !data.is_empty() {
+                return Some(data);
+            }
+        }
+    }
+}
+
+/// Token usage accumulated over a session
+///
+/// Sums the `usageMetadata` of every response received during a chat session
+/// or a single execute / create-codebase run.
+#[derive(Debug, Clone, Default)]
+struct TokenUsage {
+    requests: u64,
+    prompt_tokens: u64,
+    candidates_tokens: u64,
+    thoughts_tokens: u64,
+    cached_tokens: u64,
+    total_tokens: u64,
+}
+
+impl TokenUsage {
+    /// Adds the usage reported by a response
+    ///
+    /// # Arguments
+    ///
+    /// * `response` - The API response
+    fn record(&mut self, response: &GeminiApiResponse) {
+        self.requests += 1;
+
+        let Some(usage) = &response.usage_metadata else {
+            debug!("Response did not include usage metadata");
+            return;
+        };
+
+        let prompt = u64::from(usage.prompt_token_count.unwrap_or(0));
+        let candidates = u64::from(usage.candidates_token_count.unwrap_or(0));
+        let thoughts = u64::from(usage.thoughts_token_count.unwrap_or(0));
+        let cached = u64::from(usage.cached_content_token_count.unwrap_or(0));
+        let total = usage
+            .total_token_count
+            .map(u64::from)
+            .unwrap_or(prompt + candidates + thoughts);
+
+        self.prompt_tokens += prompt;
+        self.candidates_tokens += candidates;
+        self.thoughts_tokens += thoughts;
+        self.cached_tokens += cached;
+        self.total_tokens += total;
+
+        debug!(
+            "Request used {} tokens (prompt: {}, candidates: {}, thoughts: {}, cached: {}); session total: {}",
+            total, prompt, candidates, thoughts, cached, self.total_tokens
+        );
+    }
+
+    /// Estimates the cost of the recorded usage
+    ///
+    /// Cached prompt tokens are billed at the cached input price when one is
+    /// given, and thought tokens are billed as output.
+    ///
+    /// # Arguments
+    ///
+    /// * `price` - The price of the model
+    ///
+    /// # Returns
+    ///
+    /// * `f64` - The estimated cost in USD
+    fn estimate_cost(&self, price: &ModelPrice) -> f64 {
+        let cached = self.cached_tokens.min(self.prompt_tokens);
+        let uncached = self.prompt_tokens - cached;
+        let cached_price = price.cached_input.unwrap_or(price.input);
+        let output = self.candidates_tokens + self.thoughts_tokens;
+
+        (uncached as f64 * price.input + cached as f64 * cached_price + output as f64 * price.output)
+            / 1_000_000.0
+    }
+}
+
+/// Price of a model in USD per million tokens
+#[derive(Debug, Clone, Deserialize)]
+struct ModelPrice {
+    input: f64,
+    output: f64,
+    #[serde(default)]
+    cached_input: Option<f64>,
+}
+
+/// Per-model price table loaded from a JSON file
+///
+/// Maps model names to prices, e.g. `{"gemini-2.0-flash": {"input": 0.1, "output": 0.4}}`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+struct PriceTable {
+    models: HashMap<String, ModelPrice>,
+}
+
+impl PriceTable {
+    /// Loads a price table from a JSON file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the JSON file
+    ///
+    /// # Returns
+    ///
+    /// * `Result<PriceTable, AppError>` - The price table or an error
+    fn load(path: &str) -> Result<Self, AppError> {
+        let contents = fs::read_to_string(path)?;
+        let table: PriceTable = serde_json::from_str(&contents)?;
+        debug!("Loaded prices for {} models from {}", table.models.len(), path);
+        Ok(table)
+    }
+
+    /// Looks up the price for a model
+    ///
+    /// Falls back to the longest entry that is a prefix of the model name, so
+    /// `gemini-2.0-flash` also prices `gemini-2.0-flash-001`.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model name
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&ModelPrice>` - The price, if the model is listed
+    fn price_for(&self, model: &str) -> Option<&ModelPrice> {
+        let model = model.trim_start_matches("models/");
+        self.models.get(model).or_else(|| {
+            self.models
+                .iter()
+                .filter(|(name, _)| model.starts_with(name.as_str()))
+                .max_by_key(|(name, _)| name.len())
+                .map(|(_, price)| price)
+        })
+    }
+}
+
+/// Prints a summary of the token usage and, if prices are known, the estimated cost
+///
+/// # Arguments
+///
+/// * `usage` - The accumulated token usage
+/// * `model` - The model the requests were sent to
+/// * `prices` - The optional price table
+fn print_usage_report(usage: &TokenUsage, model: &str, prices: Option<&PriceTable>) {
+    println!("\n--- Token Usage ({}) ---", model);
+    println!("Requests:          {}", usage.requests);
+    println!("Prompt tokens:     {} ({} cached)", usage.prompt_tokens, usage.cached_tokens);
+    println!("Candidates tokens: {}", usage.candidates_tokens);
+    println!("Thoughts tokens:   {}", usage.thoughts_tokens);
+    println!("Total tokens:      {}", usage.total_tokens);
+
+    if let Some(prices) = prices {
+        match prices.price_for(model) {
+            Some(price) => println!("Estimated cost:    ${:.4}", usage.estimate_cost(price)),
+            None => warn!("No price listed for model {}", model),
+        }
+    }
+}
+
+/// Multi-turn conversation history for chat mode
+///
+/// Stores the alternating `user`/`model` turns of a chat session, including the
+/// model's raw replies and the command feedback sent back after each turn.
+/// The history is bounded to the most recent `max_turns` entries.
+#[derive(Debug, Clone)]
+struct ChatHistory {
+    turns: Vec<Content>,
+    max_turns: usize,
+}
+
+impl ChatHistory {
+    /// Creates an empty history keeping at most `max_turns` turns
+    fn new(max_turns: usize) -> Self {
+        ChatHistory {
+            turns: Vec::new(),
+            max_turns,
+        }
+    }
+
+    /// Records a user query together with the files attached to it
+    fn push_user(&mut self, text: &str, attachments: &[Part]) {
+        self.push(Content::user(text, attachments));
+    }
+
+    /// Records a model turn with all its parts, e.g. function calls or signed text
+    ///
+    /// Thought summaries are left out; the model does not need them in the history.
+    fn push_model_content(&mut self, mut content: Content) {
+        content.role = Some("model".to_string());
+        content.parts.retain(|part| !matches!(part, Part::Thought { .. }));
+        self.push(content);
+    }
+
+    /// Records the results of the model's function calls as a user turn
+    fn push_function_responses(&mut self, parts: Vec<Part>) {
+        self.push(Content {
+            role: Some("user".to_string()),
+            parts,
+        });
+    }
+
+    /// Records the feedback from executing the model's commands as its own turn
+    fn push_feedback(&mut self, feedback: &str) {
+        self.push(Content::text(
+            Some("user"),
+            format!("Command Feedback:\n{}", feedback),
+        ));
+    }
+
+    /// Appends a turn and drops the oldest ones once the history is over its limit
+    fn push(&mut self, content: Content) {
+        self.turns.push(content);
+
+        if self.turns.len() > self.max_turns {
+            let excess = self.turns.len() - self.max_turns;
+            self.turns.drain(..excess);
+            // A conversation has to start with a user turn that is not a function response
+            while self.turns.first().is_some_and(|turn| !ChatHistory::starts_exchange(turn)) {
+                self.turns.remove(0);
+            }
+            debug!("Trimmed chat history to {} turns", self.turns.len());
+        }
+    }
+
+    /// Checks whether a turn opens an exchange, i.e. is a user turn other than function responses
+    fn starts_exchange(turn: &Content) -> bool {
+        turn.role.as_deref() == Some("user")
+            && !turn
+                .parts
+                .iter()
+                .all(|part| matches!(part, Part::FunctionResponse { .. }))
+    }
+
+    /// Drops the oldest exchange: a user turn and everything up to the next one
+    ///
+    /// The latest exchange is always kept so the model still sees the current task.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - The number of turns dropped, 0 if only the latest exchange is left
+    fn drop_oldest_exchange(&mut self) -> usize {
+        let next_exchange = self
+            .turns
+            .iter()
+            .enumerate()
+            .skip(1)
+            .find(|(_, turn)| ChatHistory::starts_exchange(turn))
+            .map(|(index, _)| index);
+
+        match next_exchange {
+            Some(index) => {
+                self.turns.drain(..index);
+                index
+            }
+            None => 0,
+        }
+    }
+
+    /// Builds the `contents` array for a request
+    ///
+    /// Consecutive turns with the same role (e.g. command feedback followed by the
+    /// next query) are merged into a single entry so roles strictly alternate.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - Dynamic context sent as the first user part ahead of the history
+    /// * `query` - The new user turn to append after the history, if any
+    fn to_contents(&self, context: &str, query: Option<&Content>) -> Vec<Content> {
+        let mut contents: Vec<Content> = Vec::new();
+
+        for turn in std::iter::once(&Content::text(Some("user"), context))
+            .chain(self.turns.iter())
+            .chain(query)
+        {
+            match contents.last_mut() {
+                Some(last) if last.role == turn.role => {
+                    last.parts.extend(turn.parts.iter().cloned());
+                }
+                _ => contents.push(turn.clone()),
+            }
+        }
+
+        contents
+    }
+}
+
+/// Builds the system instruction and tools of chat requests
+///
+/// # Arguments
+///
+/// * `mode` - Whether commands are requested as function calls, as JSON text or as schema-constrained JSON
+/// * `system_prompt` - Instructions replacing the default assistant persona, if any
+/// * `grounding` - Whether answers are grounded with Google Search
+///
+/// # Returns
+///
+/// * `(Option<Content>, Vec<Tool>)` - The system instruction and the tools to declare
+fn chat_instructions(mode: CommandMode, system_prompt: Option<&str>, grounding: bool) -> (Option<Content>, Vec<Tool>) {
+    let persona = system_prompt.unwrap_or("You are a helpful coding assistant working on the user's machine.");
+    let (protocol, mut tools) = match mode {
+        CommandMode::Functions => (
+            "Use the provided functions to create folders and files, write code and execute commands. After each function call you receive its result; if a call fails, correct it or adjust your approach. When the task is done, or if no action is needed, reply with a short message for the user.".to_string(),
+            vec![Tool::functions(GeminiCommand::function_declarations())],
+        ),
+        CommandMode::Json | CommandMode::Schema => (
+            format!(
+                "You will receive system information and user queries. Respond with a JSON object containing 'commands' and 'user_message'. 'commands' is an array of command objects, each with a 'type' and command-specific fields. Supported commands:\n{}\n'user_message' is a string for user feedback after execution.\n\n**Feedback Loop:** After I execute your commands, I will provide feedback on their success or failure in subsequent queries. Use this feedback to improve your command generation. If a command fails, try to correct it or adjust your approach in the next turn.\n\nExample response for 'please build a hello-world python app for me':\n{{\n  \"commands\": [\n    {{\"type\": \"create_folder\", \"path\": \"user_projects\"}},\n    {{\"type\": \"create_file\", \"path\": \"user_projects/hello_world.py\", \"content\": \"\"}},\n    {{\"type\": \"write_code_to_file\", \"path\": \"user_projects/hello_world.py\", \"code\": \"print('Hello, World!')\"}},\n    {{\"type\": \"execute_command\", \"command\": \"python\", \"args\": [\"user_projects/hello_world.py\"]}}\n  ],\n  \"user_message\": \"Here is a hello-world Python app in 'user_projects'. It has been created and executed.\" \n}}",
+                GeminiCommand::prompt_spec()
+            ),
+            Vec::new(),
+        ),
+    };
+    if grounding {
+        tools.push(Tool::google_search());
+    }
+
+    (system_instruction(Some(persona), &protocol), tools)
+}
+
+/// Gets the command mode chat uses when answers are grounded
+///
+/// The API does not combine Google Search with function calling or a response
+/// schema, so grounded chats ask for commands as JSON text instead.
+///
+/// # Arguments
+///
+/// * `mode` - The requested command mode
+/// * `grounding` - Whether answers are grounded with Google Search
+///
+/// # Returns
+///
+/// * `CommandMode` - The command mode to use
+fn grounded_command_mode(mode: CommandMode, grounding: bool) -> CommandMode {
+    if grounding && mode != CommandMode::Json {
+        let name = format!("{:?}", mode).to_lowercase();
+        info!("Google Search grounding requires JSON commands, using them instead of {}", name);
+        println!("Grounding with Google Search uses JSON commands instead of --command-mode {}.", name);
+        CommandMode::Json
+    } else {
+        mode
+    }
+}
+
+/// Communicates with the Gemini API in chat mode
+///
+/// Sends a query to the selected model, together with the conversation so
+/// far, and returns the response.
+///
+/// # Arguments
+///
+/// * `client` - The model backend
+/// * `history` - The conversation history, including previous command feedback; the oldest
+///   exchanges are dropped if the request would not fit in the model's input limit
+/// * `query` - The user's turn with the query and any attachments, or `None` to continue after function responses
+/// * `system_info` - System information to include in the prompt
+/// * `mode` - Whether commands are requested as function calls, as JSON text or as schema-constrained JSON
+/// * `system_prompt` - Instructions replacing the default assistant persona, if any
+/// * `grounding` - Whether answers are grounded with Google Search
+/// * `on_part` - Callback for streamed parts; streams the response when set
+///
+/// # Returns
+///
+/// * `Result<GeminiApiResponse, AppError>` - The API response or an error
+#[allow(clippy::too_many_arguments)]
+async fn chat_with_gemini(
+    client: &dyn LlmBackend,
+    history: &mut ChatHistory,
+    query: Option<&Content>,
+    system_info: &str,
+    mode: CommandMode,
+    system_prompt: Option<&str>,
+    grounding: bool,
+    on_part: Option<&mut dyn FnMut(&Part)>,
+) -> Result<GeminiApiResponse, AppError> {
+    let (system_instruction, tools) = chat_instructions(mode, system_prompt, grounding);
+
+    // In schema mode Gemini must reply with JSON matching `GeminiResponse`
+    let generation_config = (mode == CommandMode::Schema).then(|| GenerationConfig {
+        response_mime_type: Some("application/json".to_string()),
+        response_schema: Some(GeminiResponse::response_schema()),
+        ..Default::default()
+    });
+
+    let context = format!("{}\n{}", SYSTEM_INFO_HEADER, system_info);
+    let mut request = GenerateContentRequest {
+        contents: history.to_contents(&context, query),
+        tools,
+        generation_config,
+        system_instruction,
+        ..Default::default()
+    };
+
+    // Drop the oldest exchanges until the request fits comfortably in the input limit
+    let mut count = client.count_tokens(&request).await;
+    let original_tokens = count.tokens;
+    let mut dropped_turns = 0;
+    while count.exceeds(client.warning_threshold()) {
+        let dropped = history.drop_oldest_exchange();
+        if dropped == 0 {
+            break;
+        }
+        dropped_turns += dropped;
+        request.contents = history.to_contents(&context, query);
+        count = client.count_tokens(&request).await;
+    }
+    if dropped_turns > 0 {
+        info!(
+            "Dropped {} history turns to fit the input limit ({} -> {} tokens)",
+            dropped_turns, original_tokens, count.tokens
+        );
+        println!(
+            "Dropped the {} oldest conversation turns to stay within the model's input limit ({} -> {} tokens).",
+            dropped_turns, original_tokens, count.tokens
+        );
+    }
+    client.check_input_size(&count)?;
+
+    info!("Sending request to {} with {} history turns...", client.model(), history.turns.len());
+    client.send(&request, on_part).await
+}
+
+/// Builds the system instruction and tools of execute requests
+///
+/// # Arguments
+///
+/// * `system_prompt` - Instructions sent as the system instruction, if any
+/// * `grounding` - Whether answers are grounded with Google Search
+///
+/// # Returns
+///
+/// * `(Option<Content>, Vec<Tool>)` - The system instruction and the code execution tool,
+///   followed by Google Search when grounding
+fn execute_instructions(system_prompt: Option<&str>, grounding: bool) -> (Option<Content>, Vec<Tool>) {
+    let mut tools = vec![Tool::code_execution()];
+    if grounding {
+        tools.push(Tool::google_search());
+    }
+    (system_instruction(system_prompt, ""), tools)
+}
+
+/// Communicates with the Gemini API in execute mode
+///
+/// Sends a query to the selected model for code execution.
+///
+/// # Arguments
+///
+/// * `client` - The model backend
+/// * `query` - The user's query to send to Gemini
+/// * `attachments` - Files to send along with the query
+/// * `system_prompt` - Instructions sent as the system instruction, if any
+/// * `grounding` - Whether answers are grounded with Google Search
+/// * `on_part` - Callback for streamed parts; streams the response when set
+///
+/// # Returns
+///
+/// * `Result<GeminiApiResponse, AppError>` - The API response or an error
+async fn execute_with_gemini(
+    client: &dyn LlmBackend,
+    query: &str,
+    attachments: &[Part],
+    system_prompt: Option<&str>,
+    grounding: bool,
+    on_part: Option<&mut dyn FnMut(&Part)>,
+) -> Result<GeminiApiResponse, AppError> {
+    let (system_instruction, tools) = execute_instructions(system_prompt, grounding);
+    let request = GenerateContentRequest {
+        contents: vec![Content::user(query, attachments)],
+        tools,
+        system_instruction,
+        ..Default::default()
+    };
+    client.check_input_size(&client.count_tokens(&request).await)?;
+
+    info!("Sending request to {}...", client.model());
+    client.send(&request, on_part).await
+}
+
+/// Builds the system instruction and tools of create-codebase requests
+///
+/// # Arguments
+///
+/// * `system_prompt` - Instructions replacing the default persona, if any; the file format instructions are always sent
+///
+/// # Returns
+///
+/// * `(Option<Content>, Vec<Tool>)` - The system instruction and the code execution tool
+fn codebase_instructions(system_prompt: Option<&str>) -> (Option<Content>, Vec<Tool>) {
+    let persona = system_prompt.unwrap_or(
+        "You are an experienced software engineer. Include a README.md with setup instructions, dependencies, and usage examples.\n\
+        Make sure the codebase is well-structured, follows best practices, and is ready to run.",
+    );
+
+    // The file layout is required to extract the files, so it is kept with a custom prompt
+    let protocol = "Generate all necessary files for a working application. For each file:\n\
+        1. Use a clear header with the filename (e.g., '## app.py' or 'File: app.py')\n\
+        2. Provide the complete code content in a markdown code block with the appropriate language\n\
+        3. Briefly explain what the file does after the code block\n\n\
+        IMPORTANT: Make sure to include the actual code in markdown code blocks with the appropriate language tag, not just explanations.\n\
+        For example, for a Python file:\n\
+        ## app.py\n\
+        ```python\n\
+        # Your actual Python code here\n\
+        print('Hello, world!')\n\
+        ```\n\
+        This file is the main entry point of the application.\n\n\
+        Format your response as markdown with code blocks for each file.";
+
+    (system_instruction(Some(persona), protocol), vec![Tool::code_execution()])
+}
+
+/// Creates a codebase using the Gemini API
+///
+/// Sends a description to the selected model to generate a complete codebase.
+///
+/// # Arguments
+///
+/// * `client` - The model backend
+/// * `description` - Description of the codebase to create
+/// * `output_dir` - Directory where the codebase will be created
+/// * `attachments` - Files to send along with the description, e.g. a spec or a mockup
+/// * `system_prompt` - Instructions replacing the default persona, if any; the file format instructions are always sent
+/// * `max_continuations` - Maximum number of follow-up requests for a response cut off at the output token limit
+/// * `on_part` - Callback for streamed parts; streams the response when set
+///
+/// # Returns
+///
+/// * `Result<GeminiApiResponse, AppError>` - The API response, with cut-off candidates continued, or an error
+async fn create_codebase_with_gemini(
+    client: &dyn LlmBackend,
+    description: &str,
+    output_dir: &str,
+    attachments: &[Part],
+    system_prompt: Option<&str>,
+    max_continuations: u32,
+    mut on_part: Option<&mut dyn FnMut(&Part)>,
+) -> Result<GeminiApiResponse, AppError> {
+    // Create the output directory if it doesn't exist
+    let output_path = Path::new(output_dir);
+    if !output_path.exists() {
+        fs::create_dir_all(output_path)?;
+    }
+
+    let (system_instruction, tools) = codebase_instructions(system_prompt);
+
+    // Use the same request format as execute_with_gemini
+    let request = GenerateContentRequest {
+        contents: vec![Content::user(
+            &format!("Create a complete codebase based on this description: {}", description),
+            attachments,
+        )],
+        tools,
+        system_instruction,
+        ..Default::default()
+    };
+    client.check_input_size(&client.count_tokens(&request).await)?;
+
+    info!("Sending request to {} to create codebase...", client.model());
+    let first_part = on_part.as_mut().map(|on_part| &mut **on_part as &mut dyn FnMut(&Part));
+    let mut response = client.send(&request, first_part).await?;
+    continue_truncated_candidates(client, &request, &mut response, max_continuations, on_part).await?;
+    Ok(response)
+}
+
+/// Continues candidates that stopped at the output token limit
+///
+/// A cut-off candidate is sent back as a model turn, followed by a request to
+/// continue. The answer is first cut back to its last closed code block, so the
+/// interrupted file is written again as a whole. If that would not get further
+/// than the previous round, because a single block is longer than one answer,
+/// the continuation picks up inside the open block instead.
+///
+/// # Arguments
+///
+/// * `client` - The model backend
+/// * `request` - The request the response answers
+/// * `response` - The response; continued candidates and the usage are updated in place
+/// * `max_continuations` - Maximum number of follow-up requests per candidate
+/// * `on_part` - Callback for streamed parts; streams the continuations when set
+///
+/// # Returns
+///
+/// * `Result<(), AppError>` - Success, even if candidates are still cut off, or an error
+async fn continue_truncated_candidates(
+    client: &dyn LlmBackend,
+    request: &GenerateContentRequest,
+    response: &mut GeminiApiResponse,
+    max_continuations: u32,
+    mut on_part: Option<&mut dyn FnMut(&Part)>,
+) -> Result<(), AppError> {
+    let Some(candidates) = response.candidates.as_mut() else {
+        return Ok(());
+    };
+
+    for candidate in candidates.iter_mut() {
+        let mut text = candidate_text(candidate);
+        let mut kept = 0;
+        let mut round = 0;
+
+        while candidate.finish_reason.as_deref() == Some("MAX_TOKENS") && round < max_continuations {
+            round += 1;
+            let boundary = fence_boundary(&text);
+            let in_block = boundary < text.len() && boundary <= kept;
+            let prefix = if in_block { text.as_str() } else { &text[..boundary] };
+            kept = prefix.len();
+
+            info!("Response was cut off, requesting continuation {}/{}", round, max_continuations);
+            println!("\n[Response was cut off, continuing ({}/{})]", round, max_continuations);
+
+            let mut contents = request.contents.clone();
+            contents.push(Content {
+                role: Some("model".to_string()),
+                parts: vec![Part::text(prefix)],
+            });
+            contents.push(Content::user(
+                if in_block { CONTINUE_IN_BLOCK_PROMPT } else { CONTINUE_PROMPT },
+                &[],
+            ));
+            let continuation_request = GenerateContentRequest {
+                contents,
+                generation_config: Some(GenerationConfig {
+                    candidate_count: Some(1),
+                    ..request.generation_config.clone().unwrap_or_default()
+                }),
+                ..request.clone()
+            };
+
+            let on_part = on_part.as_mut().map(|on_part| &mut **on_part as &mut dyn FnMut(&Part));
+            let continuation = client.send(&continuation_request, on_part).await?;
+            if let (Some(total), Some(usage)) = (response.usage_metadata.as_mut(), &continuation.usage_metadata) {
+                total.add(usage);
+            }
+            let Some(next) = continuation.candidates.and_then(|candidates| candidates.into_iter().next()) else {
+                warn!("Continuation {} returned no candidates", round);
+                break;
+            };
+
+            text = stitch_continuation(prefix, &candidate_text(&next), in_block);
+            candidate.finish_reason = next.finish_reason;
+        }
+
+        if round > 0 {
+            // Thoughts and other parts are kept, the text parts are replaced by the stitched text
+            candidate.content.parts.retain(|part| !matches!(part, Part::Text { .. }));
+            candidate.content.parts.push(Part::text(text));
+        }
+        if candidate.finish_reason.as_deref() == Some("MAX_TOKENS") && max_continuations > 0 {
+            warn!("Response is still cut off after {} continuations", round);
+            println!("Response is still cut off after {} continuations.", round);
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds where the last complete code block of a cut-off text ends
+///
+/// # Arguments
+///
+/// * `text` - The cut-off text
+///
+/// # Returns
+///
+/// * `usize` - The byte offset after the line closing the last complete block, or the
+///   length of the text if it does not end inside a code block
+fn fence_boundary(text: &str) -> usize {
+    let mut inside = false;
+    let mut boundary = 0;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        offset += line.len();
+        if !inside && line.starts_with("```") {
+            inside = true;
+        } else if inside && line.trim() == "```" {
+            inside = false;
+            boundary = offset;
+        }
+    }
+
+    if inside {
+        boundary
+    } else {
+        text.len()
+    }
+}
+
+/// Joins a continuation to the text it continues
+///
+/// Code fences the model reopens inside an open block are dropped, as is a
+/// repeated tail of the previous text.
+///
+/// # Arguments
+///
+/// * `prefix` - The text the continuation was requested for
+/// * `continuation` - The text of the continuation
+/// * `in_block` - Whether the prefix ends inside an open code block
+///
+/// # Returns
+///
+/// * `String` - The joined text
+fn stitch_continuation(prefix: &str, continuation: &str, in_block: bool) -> String {
+    let mut continuation = continuation;
+    if in_block {
+        let first_line = continuation.lines().next().unwrap_or_default();
+        if first_line.starts_with("```") && first_line.trim() != "```" {
+            debug!("Dropping reopened code fence {:?}", first_line);
+            continuation = continuation[first_line.len()..].trim_start_matches(['\r', '\n']);
+        }
+    }
+
+    // The model sometimes starts by repeating the end of what it already wrote
+    let overlap = (MIN_CONTINUATION_OVERLAP..=continuation.len().min(prefix.len()))
+        .rev()
+        .find(|&length| continuation.is_char_boundary(length) && prefix.ends_with(&continuation[..length]))
+        .unwrap_or(0);
+    if overlap > 0 {
+        debug!("Dropping {} bytes repeated at the start of the continuation", overlap);
+    }
+
+    format!("{}{}", prefix, &continuation[overlap..])
+}
+
+/// Infers a file extension based on the content of the code
+///
+/// Analyzes the content of a file to determine the most appropriate file extension.
+///
+/// # Arguments
+///
+/// * `content` - The content of the file
+///
+/// # Returns
+///
+/// * `String` - The inferred file extension
+fn infer_extension_from_content(content: &str) -> String {
+    if content.contains("<?php") {
+        return "php".to_string();
+    } else if content.contains("<!DOCTYPE html") || content.contains("<html") {
+        return "html".to_string();
+    } else if content.contains("import React") || content.contains("from 'react'") {
+        return "jsx".to_string();
+    } else if content.contains("import ") && content.contains("from '") && content.contains("export ") {
+        return "js".to_string();
+    } else if content.contains("#include <") {
+        if content.contains("iostream") {
+            return "cpp".to_string();
+        } else {
+            return "c".to_string();
+        }
+    } else if content.contains("package ") && content.contains("import ") && content.contains("public class ") {
+        return "java".to_string();
+    } else if content.contains("def ") && content.contains("import ") {
+        return "py".to_string();
+    } else if content.contains("fn ") && content.contains("pub ") && content.contains("use ") {
+        return "rs".to_string();
+    }
+
+    // Default to txt if we can't infer
+    trace!("Could not infer extension from content, defaulting to txt");
+    "txt".to_string()
+}
+
+/// Returns a file extension based on the language name
+///
+/// Maps a programming language name to its standard file extension.
+///
+/// # Arguments
+///
+/// * `language` - The name of the programming language
+///
+/// # Returns
+///
+/// * `String` - The file extension for the language
+fn get_extension_from_language(language: &str) -> String {
+    match language.to_lowercase().as_str() {
+        "python" | "py" => "py",
+        "javascript" | "js" => "js",
+        "typescript" | "ts" => "ts",
+        "jsx" => "jsx",
+        "tsx" => "tsx",
+        "html" => "html",
+        "css" => "css",
+        "rust" | "rs" => "rs",
+        "go" => "go",
+        "java" => "java",
+        "c" => "c",
+        "cpp" | "c++" => "cpp",
+        "csharp" | "cs" => "cs",
+        "php" => "php",
+        "ruby" | "rb" => "rb",
+        "shell" | "sh" | "bash" => "sh",
+        "sql" => "sql",
+        "json" => "json",
+        "yaml" | "yml" => "yml",
+        "markdown" | "md" => "md",
+        "dockerfile" => "Dockerfile",
+        "makefile" => "Makefile",
+        _ => "txt",
+    }.to_string()
+}
+
+/// Extracts files from markdown text using headers and code blocks
+///
+/// Parses markdown text to extract file content and names from code blocks.
+///
+/// # Arguments
+///
+/// * `text` - The markdown text to parse
+///
+/// # Returns
+///
+/// * `Vec<(String, String)>` - A vector of (filename, content) pairs
+fn extract_files_from_markdown(text: &str) -> Vec<(String, String)> {
+    let mut files = Vec::new();
+    let mut current_file = None;
+    let mut current_content = String::new();
+
+    // Split the text into lines for processing
+    let lines: Vec<&str> = text.lines().collect();
+
+    for line in lines.iter() {
+        // Check for file header pattern: "```filename" or "```language:filename"
+        if line.starts_with("```") && !line.trim_start_matches('`').is_empty() {
+            // If we were already collecting a file, save it before starting a new one
+            if let Some(filename) = current_file.take() {
+                debug!("Extracted file from markdown: {}", filename);
+                files.push((filename, current_content.clone()));
+                current_content.clear();
+            }
+
+            let header = line.trim_start_matches('`').trim();
+            
+            // Handle different code block formats
+            if header.contains(':') {
+                // Format: ```language:filename
+                let parts: Vec<&str> = header.splitn(2, ':').collect();
+                if parts.len() == 2 {
+                    let filename = parts[1].trim();
+                    current_file = Some(filename.to_string());
+                    trace!("Found file in markdown with language prefix: {}", filename);
+                }
+            } else if !header.contains(' ') {
+                // Format: ```filename
+                current_file = Some(header.to_string());
+                trace!("Found file in markdown: {}", header);
+            }
+        }
+        // Check for end of code block
+        else if line.trim() == "```" && current_file.is_some() {
+            if let Some(filename) = current_file.take() {
+                debug!("Completed extraction of file: {}", filename);
+                files.push((filename, current_content.clone()));
+                current_content.clear();
+            }
+        }
+        // If we're inside a code block, collect the content
+        else if current_file.is_some() {
+            current_content.push_str(line);
+            current_content.push('\n');
+        }
+    }
+
+    // A last code block without a closing ``` was cut off, so the file is marked as incomplete
+    if let Some(filename) = current_file {
+        warn!("File {} was cut off, saving it as {}{}", filename, filename, INCOMPLETE_FILE_SUFFIX);
+        println!("⚠️ {} was cut off and is saved as {}{}", filename, filename, INCOMPLETE_FILE_SUFFIX);
+        files.push((format!("{}{}", filename, INCOMPLETE_FILE_SUFFIX), current_content));
+    }
+
+    info!("Extracted {} files from markdown", files.len());
+    files
+}
+
+/// Extracts files from code blocks
+///
+/// Parses text to extract code blocks and generates filenames based on the language.
+///
+/// # Arguments
+///
+/// * `text` - The text to parse
+///
+/// # Returns
+///
+/// * `Vec<(String, String)>` - A vector of (filename, content) pairs
+fn extract_files_from_code_blocks(text: &str) -> Vec<(String, String)> {
+    let mut files = Vec::new();
+    let re = regex::Regex::new(r"(?m)^```(\w+)?\s*\n([\s\S]*?)^```").unwrap();
+
+    // Counter for generating unique filenames
+    for (index, cap) in re.captures_iter(text).enumerate() {
+        let counter = index + 1;
+        let language = cap.get(1).map_or("txt", |m| m.as_str());
+        let content = cap.get(2).map_or("", |m| m.as_str());
+
+        // Generate a filename based on the language and counter
+        let extension = get_extension_from_language(language);
+        let filename = format!("file_{}.{}", counter, extension);
+
+        debug!("Extracted code block: {} (language: {})", filename, language);
+        files.push((filename, content.to_string()));
+    }
+
+    info!("Extracted {} files from code blocks", files.len());
+    files
+}
+
+/// Cleans and validates a file path
+///
+/// Ensures a file path is safe and normalized.
+///
+/// # Arguments
+///
+/// * `file_path` - The file path to clean and validate
+///
+/// # Returns
+///
+/// * `Result<String, AppError>` - The cleaned path or an error if the path is suspicious
+fn clean_and_validate_file_path(file_path: &str) -> Result<String, AppError> {
+    let path = file_path.trim();
+    
+    // Check for suspicious paths
+    if path.contains("..") || path.starts_with('/') || path.starts_with('\\') {
+        warn!("Suspicious file path detected: {}", path);
+        return Err(AppError::ResponseError(format!("Suspicious file path: {}", path)));
+    }
+    
+    // Normalize path separators
+    let normalized_path = path.replace('\\', "/");
+    
+    trace!("Normalized file path: {} -> {}", path, normalized_path);
+    Ok(normalized_path)
+}
+
+/// Writes files to disk and returns a list of created file paths
+///
+/// Creates files on disk based on the provided content.
+///
+/// # Arguments
+///
+/// * `files` - A vector of (filename, content) pairs
+/// * `output_dir` - The directory where files should be created
+///
+/// # Returns
+///
+/// * `Result<Vec<String>, AppError>` - A list of created file paths or an error
+fn write_files_to_disk(
+    files: Vec<(String, String)>,
     output_dir: &str,
-    api_key: &str,
-) -> Result<GeminiApiResponse, AppError> {
-    let client = Client::new();
-    let gemini_api_endpoint = get_gemini_api_endpoint();
+) -> Result<Vec<String>, AppError> {
+    let mut created_files = Vec::new();
+    let mut file_counter = 0;
+
+    for (file_path, content) in files {
+        file_counter += 1;
+        
+        // Clean and validate the file path
+        let clean_path = clean_and_validate_file_path(&file_path)?;
+        
+        // If the file doesn't have an extension, try to infer one from the content
+        let final_path = if !clean_path.contains('.') {
+            let extension = infer_extension_from_content(&content);
+            format!("{}.{}", clean_path, extension)
+        } else {
+            clean_path
+        };
+        
+        // Create the full path
+        let full_path = Path::new(output_dir).join(&final_path);
+        
+        // Create parent directories if they don't exist
+        if let Some(parent) = full_path.parent() {
+            if !parent.exists() {
+                debug!("Creating parent directory: {}", parent.display());
+                if let Err(e) = fs::create_dir_all(parent) {
+                    error!("Failed to create parent directory {}: {}", parent.display(), e);
+                    return Err(AppError::IoError(e));
+                }
+            }
+        }
+        
+        // Write the file
+        fs::write(&full_path, content)?;
+        info!("Created file: {}", full_path.display());
+        created_files.push(full_path.to_string_lossy().to_string());
+    }
+
+    info!("Successfully created {} files", file_counter);
+    Ok(created_files)
+}
+
+/// Creates files from a Gemini API response
+///
+/// Extracts file information from the API response and creates the files on disk.
+///
+/// # Arguments
+///
+/// * `text` - The text response from Gemini
+/// * `output_dir` - The directory where files should be created
+///
+/// # Returns
+///
+/// * `Result<Vec<String>, AppError>` - A list of created file paths or an error
+fn create_files_from_response(
+    text: &str,
+    output_dir: &str,
+) -> Result<Vec<String>, AppError> {
+    // First, try to extract files based on markdown patterns
+    let mut files = extract_files_from_markdown(text);
+
+    // If no files were found using markdown pattern, try to extract code blocks
+    if files.is_empty() {
+        debug!("No files found using markdown pattern, trying code blocks extraction");
+        files = extract_files_from_code_blocks(text);
+    }
+
+    // If still no files, treat the entire response as a single file
+    if files.is_empty() {
+        warn!("No files found in structured format, treating entire response as a single file");
+        files.push(("README.md".to_string(), text.to_string()));
+    }
+
+    write_files_to_disk(
+        files,
+        output_dir
+    )
+}
+
+/// Executes a shell command
+///
+/// Runs a command in the shell and returns the output.
+///
+/// # Arguments
+///
+/// * `command` - The command to execute
+///
+/// # Returns
+///
+/// * `Result<String, AppError>` - The command output or an error
+async fn execute_command(command: &str) -> Result<String, AppError> {
+    let parts: Vec<&str> = command.split_whitespace().collect();
+    if parts.is_empty() {
+        error!("Empty command provided");
+        return Err(AppError::CommandError("Empty command".to_string()));
+    }
+    let cmd = parts[0];
+    let args = &parts[1..];
+    
+    debug!("Executing command: {} with args: {:?}", cmd, args);
+    
+    let output = ProcessCommand::new(cmd)
+        .args(args)
+        .output()
+        .map_err(|e| {
+            error!("Failed to execute command: {}", e);
+            AppError::IoError(e)
+        })?;
+        
+    if output.status.success() {
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        debug!("Command executed successfully");
+        trace!("Command output: {}", stdout);
+        Ok(stdout)
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        error!("Command execution failed: {}", stderr);
+        Err(AppError::CommandError(stderr))
+    }
+}
+
+/// Determines the MIME type of a file from its first bytes and its extension
+///
+/// Well-known binary formats are recognized by their signature; other files
+/// by their extension, and any other UTF-8 file is sent as plain text.
+///
+/// # Arguments
+///
+/// * `path` - Path of the file
+/// * `bytes` - Content of the file
+///
+/// # Returns
+///
+/// * `Result<String, AppError>` - The MIME type, or an error for unrecognized binary files
+fn sniff_mime_type(path: &str, bytes: &[u8]) -> Result<String, AppError> {
+    let signature = if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        Some("image/webp")
+    } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WAVE") {
+        Some("audio/wav")
+    } else if bytes.starts_with(b"%PDF-") {
+        Some("application/pdf")
+    } else if bytes.starts_with(b"ID3") || bytes.starts_with(&[0xFF, 0xFB]) {
+        Some("audio/mpeg")
+    } else if bytes.starts_with(b"OggS") {
+        Some("audio/ogg")
+    } else if bytes.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        Some("video/webm")
+    } else if bytes.get(4..8) == Some(b"ftyp") {
+        Some(if bytes.get(8..11) == Some(b"hei") { "image/heic" } else { "video/mp4" })
+    } else {
+        None
+    };
+    if let Some(mime_type) = signature {
+        return Ok(mime_type.to_string());
+    }
 
-    // Create the output directory if it doesn't exist
-    let output_path = Path::new(output_dir);
-    if !output_path.exists() {
-        fs::create_dir_all(output_path)?;
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let by_extension = match extension.as_str() {
+        "csv" => Some("text/csv"),
+        "md" | "markdown" => Some("text/markdown"),
+        "html" | "htm" => Some("text/html"),
+        "css" => Some("text/css"),
+        "xml" => Some("text/xml"),
+        "json" => Some("application/json"),
+        "js" | "mjs" => Some("text/javascript"),
+        "py" => Some("text/x-python"),
+        "rtf" => Some("text/rtf"),
+        _ => None,
+    };
+    if let Some(mime_type) = by_extension {
+        return Ok(mime_type.to_string());
     }
 
-    let prompt = format!(
-        "Create a complete codebase based on this description: {}\n\n\
-        Generate all necessary files for a working application. For each file:\n\
-        1. Use a clear header with the filename (e.g., '## app.py' or 'File: app.py')\n\
-        2. Provide the complete code content in a markdown code block with the appropriate language\n\
-        3. Briefly explain what the file does after the code block\n\n\
-        IMPORTANT: Make sure to include the actual code in markdown code blocks with the appropriate language tag, not just explanations.\n\
-        For example, for a Python file:\n\
-        ## app.py\n\
-        ```python\n\
-        # Your actual Python code here\n\
-        print('Hello, world!')\n\
-        ```\n\
-        This file is the main entry point of the application.\n\n\
-        Include a README.md with setup instructions, dependencies, and usage examples.\n\
-        Make sure the codebase is well-structured, follows best practices, and is ready to run.\n\
-        Format your response as markdown with code blocks for each file.",
-        description
-    );
+    if std::str::from_utf8(bytes).is_ok() {
+        return Ok("text/plain".to_string());
+    }
+
+    Err(AppError::AttachmentError(format!(
+        "Cannot determine the type of {}; supported are images, PDFs, audio, video and text files",
+        path
+    )))
+}
+
+/// Loads a local file as a part to send with a prompt
+///
+/// Files up to 15 MB are sent inline as base64; larger ones are uploaded with
+/// the Files API and referenced by URI.
+///
+/// # Arguments
+///
+/// * `client` - The model backend, used for uploads
+/// * `path` - Path of the file
+///
+/// # Returns
+///
+/// * `Result<Part, AppError>` - An `inlineData` or `fileData` part, or an error
+async fn load_attachment(client: &dyn LlmBackend, path: &str) -> Result<Part, AppError> {
+    let metadata = fs::metadata(path)
+        .map_err(|e| AppError::AttachmentError(format!("Cannot read {}: {}", path, e)))?;
+    if !metadata.is_file() {
+        return Err(AppError::AttachmentError(format!("{} is not a file", path)));
+    }
+    if metadata.len() == 0 {
+        return Err(AppError::AttachmentError(format!("{} is empty", path)));
+    }
+    if metadata.len() > MAX_ATTACHMENT_BYTES {
+        return Err(AppError::AttachmentError(format!(
+            "{} is {} bytes, more than the limit of {} bytes",
+            path,
+            metadata.len(),
+            MAX_ATTACHMENT_BYTES
+        )));
+    }
+
+    let bytes = fs::read(path).map_err(|e| AppError::AttachmentError(format!("Cannot read {}: {}", path, e)))?;
+    let mime_type = sniff_mime_type(path, &bytes)?;
+    let size = bytes.len();
+
+    let part = if metadata.len() <= MAX_INLINE_ATTACHMENT_BYTES {
+        Part::InlineData {
+            inline_data: Blob {
+                mime_type: mime_type.clone(),
+                data: BASE64_STANDARD.encode(&bytes),
+            },
+        }
+    } else {
+        let display_name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.to_string());
+        println!("Uploading {} ({} bytes) with the Files API...", path, size);
+        Part::FileData {
+            file_data: client.upload_file(&display_name, &mime_type, bytes).await?,
+        }
+    };
+
+    info!("Attached {} ({}, {} bytes)", path, mime_type, size);
+    println!("Attached {} ({}, {} bytes)", path, mime_type, size);
+    Ok(part)
+}
+
+/// Reads project files into a user turn for a cached content
+///
+/// # Arguments
+///
+/// * `paths` - Files and directories to read
+///
+/// # Returns
+///
+/// * `Result<Content, AppError>` - One text part per file, or an error if no file could be read
+fn load_project_files(paths: &[String]) -> Result<Content, AppError> {
+    let files = read_project_files(paths)?;
+    if files.is_empty() {
+        return Err(AppError::ConfigError("No readable text files found to cache".to_string()));
+    }
+    info!("Caching {} project files", files.len());
+
+    Ok(Content {
+        role: Some("user".to_string()),
+        parts: files
+            .into_iter()
+            .map(|(path, content)| Part::text(format!("--- {} ---\n{}", path, content)))
+            .collect(),
+    })
+}
+
+/// Reads the text files of a project
+///
+/// Directories are read recursively, skipping hidden entries, `target` and
+/// `node_modules`. Binary files and files over 1 MB are skipped.
+///
+/// # Arguments
+///
+/// * `paths` - Files and directories to read
+///
+/// # Returns
+///
+/// * `Result<Vec<(String, String)>, AppError>` - The (path, content) pairs sorted by path, or an error
+fn read_project_files(paths: &[String]) -> Result<Vec<(String, String)>, AppError> {
+    let mut files = Vec::new();
+    let mut pending: Vec<std::path::PathBuf> = paths.iter().map(std::path::PathBuf::from).collect();
+
+    while let Some(path) = pending.pop() {
+        let metadata = fs::metadata(&path)
+            .map_err(|e| AppError::ConfigError(format!("Cannot read {}: {}", path.display(), e)))?;
+        if metadata.is_dir() {
+            for entry in fs::read_dir(&path)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if !name.starts_with('.') && name != "target" && name != "node_modules" {
+                    pending.push(entry.path());
+                }
+            }
+        } else if metadata.len() > MAX_CACHED_FILE_BYTES {
+            warn!("Skipping {}: larger than {} bytes", path.display(), MAX_CACHED_FILE_BYTES);
+        } else {
+            match fs::read_to_string(&path) {
+                Ok(content) => files.push((path.display().to_string(), content)),
+                Err(e) => debug!("Skipping {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Extends a cached content that is about to expire
+///
+/// Keeps a long chat session from losing its cached context. Failures are
+/// only logged since the cache may still be valid for a while.
+///
+/// # Arguments
+///
+/// * `client` - The Gemini API client
+/// * `cache` - The cache in use, updated with the new expiry
+async fn keep_cache_alive(client: &GeminiClient, cache: &mut CachedContent) {
+    let expiring = cache
+        .remaining()
+        .is_some_and(|remaining| remaining < Duration::from_secs(CACHE_RENEWAL_MARGIN_SECS));
+    if !expiring {
+        return;
+    }
+
+    match client
+        .update_cached_content_ttl(&cache.name, Duration::from_secs(DEFAULT_CACHE_TTL_SECS))
+        .await
+    {
+        Ok(updated) => {
+            info!("Extended cached content {}", updated.summary());
+            *cache = updated;
+        }
+        Err(e) => warn!("Failed to extend cached content {}: {}", cache.name, e),
+    }
+}
+
+/// Embedding model used to index project files and to search the index
+///
+/// Indexing and retrieval only depend on this trait, so they can run against
+/// a local embedder in tests.
+#[async_trait::async_trait(?Send)]
+trait Embedder {
+    /// Gets the name of the embedding model
+    fn model(&self) -> &str;
+
+    /// Gets the requested size of the vectors, `None` for the model's default
+    fn dimensions(&self) -> Option<u32>;
+
+    /// Embeds chunks of project files for storage in the index
+    ///
+    /// # Arguments
+    ///
+    /// * `texts` - The chunks to embed, at most [`MAX_EMBED_BATCH_SIZE`]
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<Vec<f32>>, AppError>` - One vector per chunk, in order, or an error
+    async fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, AppError>;
+
+    /// Embeds a query to search the index with
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The query
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<f32>, AppError>` - The query vector or an error
+    async fn embed_query(&self, text: &str) -> Result<Vec<f32>, AppError>;
+}
+
+/// Embedder using the Gemini API's `embedContent` and `batchEmbedContents` methods
+struct GeminiEmbedder<'a> {
+    client: &'a GeminiClient,
+    model: String,
+    dimensions: Option<u32>,
+}
+
+impl<'a> GeminiEmbedder<'a> {
+    /// Creates an embedder for a model
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The Gemini API client to send requests with
+    /// * `model` - The embedding model, with or without the `models/` prefix
+    /// * `dimensions` - The requested size of the vectors, `None` for the model's default
+    fn new(client: &'a GeminiClient, model: &str, dimensions: Option<u32>) -> Self {
+        GeminiEmbedder {
+            client,
+            model: model.trim_start_matches("models/").to_string(),
+            dimensions,
+        }
+    }
+
+    /// Builds the request embedding one text for a task
+    fn request(&self, text: &str, task_type: &'static str) -> EmbedContentRequest {
+        EmbedContentRequest {
+            model: format!("models/{}", self.model),
+            content: Content::text(None, text),
+            task_type,
+            output_dimensionality: self.dimensions,
+        }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Embedder for GeminiEmbedder<'_> {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn dimensions(&self) -> Option<u32> {
+        self.dimensions
+    }
+
+    async fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, AppError> {
+        let requests = texts.iter().map(|text| self.request(text, "RETRIEVAL_DOCUMENT")).collect();
+        self.client.batch_embed_contents(&format!("models/{}", self.model), requests).await
+    }
+
+    async fn embed_query(&self, text: &str) -> Result<Vec<f32>, AppError> {
+        self.client.embed_content(&self.request(text, "RETRIEVAL_QUERY")).await
+    }
+}
+
+/// Chunk of a project file with its embedding
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IndexedChunk {
+    path: String,
+    start_line: usize,
+    end_line: usize,
+    /// SHA-256 of the embedded text, used to reuse vectors of unchanged chunks
+    hash: String,
+    text: String,
+    vector: Vec<f32>,
+}
+
+impl IndexedChunk {
+    /// Gets the text that is embedded for the chunk, its path followed by its lines
+    fn embedding_text(&self) -> String {
+        format!("{}\n{}", self.path, self.text)
+    }
+}
+
+/// On-disk index of embedded project chunks, written by the `index` subcommand
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CodeIndex {
+    model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dimensions: Option<u32>,
+    chunks: Vec<IndexedChunk>,
+}
+
+impl CodeIndex {
+    /// Loads an index from a JSON file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the index file
+    ///
+    /// # Returns
+    ///
+    /// * `Result<CodeIndex, AppError>` - The index or an error if it cannot be read
+    fn load(path: &str) -> Result<Self, AppError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| AppError::ConfigError(format!("Failed to read index {}: {}", path, e)))?;
+        serde_json::from_str(&text).map_err(|e| AppError::ConfigError(format!("Invalid index {}: {}", path, e)))
+    }
+
+    /// Writes the index to a JSON file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the index file
+    fn save(&self, path: &str) -> Result<(), AppError> {
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Finds the chunks most similar to a query vector
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The embedded query
+    /// * `top_k` - Maximum number of chunks to return
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<(f32, &IndexedChunk)>` - The cosine similarity and chunk of the best matches, best first
+    fn search(&self, query: &[f32], top_k: usize) -> Vec<(f32, &IndexedChunk)> {
+        let mut matches: Vec<_> = self
+            .chunks
+            .iter()
+            .map(|chunk| (cosine_similarity(query, &chunk.vector), chunk))
+            .collect();
+        matches.sort_by(|a, b| b.0.total_cmp(&a.0));
+        matches.truncate(top_k);
+        matches
+    }
+}
+
+/// Computes the cosine similarity of two vectors, 0 if either is empty or zero
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+/// Splits a file into chunks of consecutive lines
+///
+/// A chunk ends after `chunk_lines` lines or once it reaches [`MAX_CHUNK_BYTES`].
+/// Chunks with only whitespace are dropped.
+///
+/// # Arguments
+///
+/// * `path` - Path of the file, stored with each chunk
+/// * `content` - The content of the file
+/// * `chunk_lines` - Maximum number of lines per chunk
+///
+/// # Returns
+///
+/// * `Vec<IndexedChunk>` - The chunks, without vectors
+fn chunk_source(path: &str, content: &str, chunk_lines: usize) -> Vec<IndexedChunk> {
+    let mut chunks = Vec::new();
+    let mut text = String::new();
+    let mut start_line = 1;
+    let mut lines = 0;
+
+    let mut finish = |text: &mut String, start_line: usize, end_line: usize| {
+        if !text.trim().is_empty() {
+            let mut chunk = IndexedChunk {
+                path: path.to_string(),
+                start_line,
+                end_line,
+                hash: String::new(),
+                text: std::mem::take(text),
+                vector: Vec::new(),
+            };
+            chunk.hash = format!("{:x}", Sha256::digest(chunk.embedding_text()));
+            chunks.push(chunk);
+        }
+        text.clear();
+    };
+
+    for (index, line) in content.lines().enumerate() {
+        if lines == 0 {
+            start_line = index + 1;
+        }
+        text.push_str(line);
+        text.push('\n');
+        lines += 1;
+        if lines >= chunk_lines || text.len() >= MAX_CHUNK_BYTES {
+            finish(&mut text, start_line, index + 1);
+            lines = 0;
+        }
+    }
+    if lines > 0 {
+        finish(&mut text, start_line, start_line + lines - 1);
+    }
+
+    chunks
+}
+
+/// Chunks and embeds project files into an index
+///
+/// Vectors of chunks that are unchanged since the previous index are reused
+/// when it was built with the same model and dimensions.
+///
+/// # Arguments
+///
+/// * `embedder` - The embedding model
+/// * `files` - The (path, content) pairs to index
+/// * `chunk_lines` - Maximum number of lines per chunk
+/// * `previous` - The previous index, if any
+///
+/// # Returns
+///
+/// * `Result<(CodeIndex, usize), AppError>` - The index and the number of newly embedded chunks, or an error
+async fn build_index(
+    embedder: &dyn Embedder,
+    files: &[(String, String)],
+    chunk_lines: usize,
+    previous: Option<&CodeIndex>,
+) -> Result<(CodeIndex, usize), AppError> {
+    let mut chunks: Vec<IndexedChunk> = files
+        .iter()
+        .flat_map(|(path, content)| chunk_source(path, content, chunk_lines))
+        .collect();
+
+    let known: HashMap<&str, &[f32]> = previous
+        .filter(|index| index.model == embedder.model() && index.dimensions == embedder.dimensions())
+        .map(|index| {
+            index
+                .chunks
+                .iter()
+                .map(|chunk| (chunk.hash.as_str(), chunk.vector.as_slice()))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut missing = Vec::new();
+    for (position, chunk) in chunks.iter_mut().enumerate() {
+        match known.get(chunk.hash.as_str()) {
+            Some(vector) => chunk.vector = vector.to_vec(),
+            None => missing.push(position),
+        }
+    }
+    debug!("Reusing {} of {} chunk vectors", chunks.len() - missing.len(), chunks.len());
+
+    let mut embedded = 0;
+    for batch in missing.chunks(MAX_EMBED_BATCH_SIZE) {
+        let texts: Vec<String> = batch.iter().map(|&position| chunks[position].embedding_text()).collect();
+        let vectors = embedder.embed_documents(&texts).await?;
+        for (&position, vector) in batch.iter().zip(vectors) {
+            chunks[position].vector = vector;
+        }
+        embedded += batch.len();
+        info!("Embedded {}/{} chunks", embedded, missing.len());
+        println!("Embedded {}/{} chunks...", embedded, missing.len());
+    }
+
+    let index = CodeIndex {
+        model: embedder.model().to_string(),
+        dimensions: embedder.dimensions(),
+        chunks,
+    };
+    Ok((index, embedded))
+}
+
+/// Finds the indexed chunks relevant to a query and formats them as context
+///
+/// # Arguments
+///
+/// * `embedder` - The model the index was built with
+/// * `index` - The index to search
+/// * `query` - The user's query
+/// * `top_k` - Maximum number of chunks to include
+///
+/// # Returns
+///
+/// * `Result<Option<String>, AppError>` - The context, `None` if the index is empty, or an error
+async fn retrieve_context(
+    embedder: &dyn Embedder,
+    index: &CodeIndex,
+    query: &str,
+    top_k: usize,
+) -> Result<Option<String>, AppError> {
+    if index.chunks.is_empty() || top_k == 0 {
+        return Ok(None);
+    }
+    let vector = embedder.embed_query(query).await?;
+    let matches = index.search(&vector, top_k);
+
+    let mut context = String::from("Relevant project code:");
+    for (score, chunk) in matches {
+        debug!("Retrieved {}:{}-{} (similarity {:.3})", chunk.path, chunk.start_line, chunk.end_line, score);
+        context.push_str(&format!(
+            "\n\n--- {} (lines {}-{}) ---\n{}",
+            chunk.path, chunk.start_line, chunk.end_line, chunk.text
+        ));
+    }
+    Ok(Some(context))
+}
+
+/// Retrieves context for a query if an index is in use
+///
+/// Retrieval failures are reported and the query is sent without context.
+///
+/// # Arguments
+///
+/// * `retrieval` - The embedder and index, if `--index` was given
+/// * `query` - The user's query
+/// * `top_k` - Maximum number of chunks to include
+///
+/// # Returns
+///
+/// * `Option<String>` - The context to send along with the query, if any
+async fn project_context(retrieval: Option<(&dyn Embedder, &CodeIndex)>, query: &str, top_k: usize) -> Option<String> {
+    let (embedder, index) = retrieval?;
+    match retrieve_context(embedder, index, query, top_k).await {
+        Ok(context) => context,
+        Err(e) => {
+            warn!("Failed to search the index: {}", e);
+            println!("Could not search the index ({}), sending the query without project code.", e);
+            None
+        }
+    }
+}
+
+/// Gets system information for the prompt
+///
+/// Collects information about the operating system and environment.
+///
+/// # Returns
+///
+/// * `String` - A string containing system information
+fn get_system_info() -> String {
+    format!(
+        "OS: {}\nArch: {}\nDir: {:?}",
+        env::consts::OS,
+        env::consts::ARCH,
+        env::current_dir().unwrap_or_default()
+    )
+}
+
+/// Extracts JSON content from markdown code blocks
+///
+/// This function looks for JSON content wrapped in markdown code blocks (```json ... ```)
+/// and extracts the JSON content. If the content is not wrapped in code blocks, it returns
+/// the original content.
+///
+/// # Arguments
+///
+/// * `content` - The content potentially containing markdown code blocks
+///
+/// # Returns
+///
+/// * The extracted JSON content or the original content if no code blocks are found
+fn extract_json_from_markdown(content: &str) -> String {
+    // Check for markdown code blocks with JSON
+    if content.trim().starts_with("```json") && content.trim().ends_with("```") {
+        // Extract the content between the code blocks
+        let start_idx = content.find("```json").map(|i| i + 7).unwrap_or(0);
+        let end_idx = content.rfind("```").unwrap_or(content.len());
+        
+        if start_idx < end_idx {
+            return content[start_idx..end_idx].trim().to_string();
+        }
+    }
+    
+    // If no code blocks or extraction failed, return the original content
+    content.to_string()
+}
+
+/// Executes a single chat command, printing its progress and outcome
+///
+/// # Arguments
+///
+/// * `command` - The command to execute
+///
+/// # Returns
+///
+/// * `CommandFeedback` - Feedback about the execution to send back to Gemini
+async fn execute_chat_command(command: GeminiCommand) -> CommandFeedback {
+    match command {
+        GeminiCommand::CreateFolder { path } => {
+            info!("Creating folder: {}", path);
+            println!("Creating folder: {}", path);
+            let result = fs::create_dir_all(&path);
+            let feedback = CommandFeedback {
+                command_type: "create_folder".to_string(),
+                command_details: format!("path: {}", path),
+                status: if result.is_ok() {
+                    CommandStatus::Success
+                } else {
+                    error!("Failed to create folder: {}", path);
+                    println!("Failed to create folder: {}", path);
+                    CommandStatus::Failure
+                },
+                message: result
+                    .map(|_| "Folder created".to_string())
+                    .unwrap_or_else(|e| e.to_string()),
+            };
+
+            // Print success/failure message
+            if feedback.status == CommandStatus::Success {
+                println!("✅ Folder created successfully");
+            } else {
+                println!("❌ Error: {}", feedback.message);
+            }
+
+            feedback
+        }
+        GeminiCommand::CreateFile { path, content } => {
+            info!("Creating file: {}", path);
+            println!("Creating file: {}", path);
+            let result = fs::write(&path, &content);
+            let feedback = CommandFeedback {
+                command_type: "create_file".to_string(),
+                command_details: format!("path: {}", path),
+                status: if result.is_ok() {
+                    CommandStatus::Success
+                } else {
+                    error!("Failed to create file: {}", path);
+                    println!("Failed to create file: {}", path);
+                    CommandStatus::Failure
+                },
+                message: result
+                    .map(|_| "File created".to_string())
+                    .unwrap_or_else(|e| e.to_string()),
+            };
 
-    // Use the same request format as execute_with_gemini
-    let request_body = json!({
-        "tools": [{"code_execution": {}}],
-        "contents": [
-            {
-                "role": "user",
-                "parts": [{"text": prompt}]
+            // Print success/failure message
+            if feedback.status == CommandStatus::Success {
+                println!("✅ File created successfully");
+            } else {
+                println!("❌ Error: {}", feedback.message);
             }
-        ]
-    });
 
-    info!("Sending request to Gemini API to create codebase...");
+            feedback
+        }
+        GeminiCommand::WriteCodeToFile { path, code } => {
+            info!("Writing code to file: {}", path);
+            println!("Writing code to file: {}", path);
+            let result = fs::write(&path, &code);
+            let feedback = CommandFeedback {
+                command_type: "write_code_to_file".to_string(),
+                command_details: format!("path: {}", path),
+                status: if result.is_ok() {
+                    CommandStatus::Success
+                } else {
+                    error!("Failed to write code to file: {}", path);
+                    println!("Failed to write code to file: {}", path);
+                    CommandStatus::Failure
+                },
+                message: result
+                    .map(|_| "Code written to file".to_string())
+                    .unwrap_or_else(|e| e.to_string()),
+            };
 
-    let response = client
-        .post(gemini_api_endpoint)
-        .header("Content-Type", "application/json")
-        .query(&[("key", api_key)])
-        .json(&request_body)
-        .send()
-        .await?;
+            // Print success/failure message
+            if feedback.status == CommandStatus::Success {
+                println!("✅ Code written to file successfully");
+            } else {
+                println!("❌ Error: {}", feedback.message);
+            }
 
-    let status = response.status();
-    let response_text = response.text().await?;
+            feedback
+        }
+        GeminiCommand::ExecuteCommand { command, args } => {
+            let cmd_str = format!("{} {}", command, args.join(" "));
+            info!("Executing: {}", cmd_str);
+            println!("Executing: {}", cmd_str);
+            let result = execute_command(&cmd_str).await;
+            let feedback = CommandFeedback {
+                command_type: "execute_command".to_string(),
+                command_details: cmd_str.clone(),
+                status: if result.is_ok() {
+                    CommandStatus::Success
+                } else {
+                    error!("Failed to execute command: {}", cmd_str);
+                    println!("Failed to execute command: {}", cmd_str);
+                    CommandStatus::Failure
+                },
+                message: result.unwrap_or_else(|e| e.to_string()),
+            };
 
-    info!("API Response Status: {}", status);
+            // Print success/failure message
+            if feedback.status == CommandStatus::Success {
+                println!("✅ Command executed successfully");
+            } else {
+                println!("❌ Error: {}", feedback.message);
+            }
 
-    if !status.is_success() {
-        error!("API Error Response: {}", response_text);
-        return Err(AppError::ApiError(format!(
-            "API request failed with status {}: {}",
-            status, response_text
-        )));
+            feedback
+        }
     }
+}
 
-    info!("API Response received. Processing...");
+/// Prints a summary of the executed commands
+///
+/// # Arguments
+///
+/// * `feedback` - Feedback for every executed command
+fn print_command_summary(feedback: &[CommandFeedback]) {
+    let total_commands = feedback.len();
+    let command_failures = feedback
+        .iter()
+        .filter(|f| f.status == CommandStatus::Failure)
+        .count();
 
-    match serde_json::from_str::<GeminiApiResponse>(&response_text) {
-        Ok(api_response) => Ok(api_response),
-        Err(e) => {
-            error!("Failed to parse API response: {}", e);
-            error!("Response text: {}", response_text);
-            Err(AppError::JsonParseError(e))
+    if total_commands > 0 {
+        if command_failures == 0 {
+            println!("\n✅ All commands executed successfully");
+        } else if command_failures == total_commands {
+            println!("\n❌ All commands failed to execute");
+        } else {
+            println!("\n⚠️ {}/{} commands failed to execute", command_failures, total_commands);
         }
     }
 }
 
-/// Infers a file extension based on the content of the code
-///
-/// Analyzes the content of a file to determine the most appropriate file extension.
+/// Executes the commands of a chat response
 ///
 /// # Arguments
 ///
-/// * `content` - The content of the file
+/// * `commands` - The commands to execute
 ///
 /// # Returns
 ///
-/// * `String` - The inferred file extension
-fn infer_extension_from_content(content: &str) -> String {
-    if content.contains("<?php") {
-        return "php".to_string();
-    } else if content.contains("<!DOCTYPE html") || content.contains("<html") {
-        return "html".to_string();
-    } else if content.contains("import React") || content.contains("from 'react'") {
-        return "jsx".to_string();
-    } else if content.contains("import ") && content.contains("from '") && content.contains("export ") {
-        return "js".to_string();
-    } else if content.contains("#include <") {
-        if content.contains("iostream") {
-            return "cpp".to_string();
-        } else {
-            return "c".to_string();
-        }
-    } else if content.contains("package ") && content.contains("import ") && content.contains("public class ") {
-        return "java".to_string();
-    } else if content.contains("def ") && content.contains("import ") {
-        return "py".to_string();
-    } else if content.contains("fn ") && content.contains("pub ") && content.contains("use ") {
-        return "rs".to_string();
+/// * `Vec<CommandFeedback>` - Feedback for every command
+async fn execute_chat_commands(commands: Vec<GeminiCommand>) -> Vec<CommandFeedback> {
+    println!("\nExecuting commands:");
+
+    let mut feedback = Vec::new();
+    for command in commands {
+        feedback.push(execute_chat_command(command).await);
     }
 
-    // Default to txt if we can't infer
-    trace!("Could not infer extension from content, defaulting to txt");
-    "txt".to_string()
+    print_command_summary(&feedback);
+    feedback
 }
 
-/// Returns a file extension based on the language name
+/// Executes the function calls of a chat response
 ///
-/// Maps a programming language name to its standard file extension.
+/// Calls that do not map to a known command are answered with a failure so
+/// the model can correct them.
 ///
 /// # Arguments
 ///
-/// * `language` - The name of the programming language
+/// * `calls` - The function calls to execute
 ///
 /// # Returns
 ///
-/// * `String` - The file extension for the language
-fn get_extension_from_language(language: &str) -> String {
-    match language.to_lowercase().as_str() {
-        "python" | "py" => "py",
-        "javascript" | "js" => "js",
-        "typescript" | "ts" => "ts",
-        "jsx" => "jsx",
-        "tsx" => "tsx",
-        "html" => "html",
-        "css" => "css",
-        "rust" | "rs" => "rs",
-        "go" => "go",
-        "java" => "java",
-        "c" => "c",
-        "cpp" | "c++" => "cpp",
-        "csharp" | "cs" => "cs",
-        "php" => "php",
-        "ruby" | "rb" => "rb",
-        "shell" | "sh" | "bash" => "sh",
-        "sql" => "sql",
-        "json" => "json",
-        "yaml" | "yml" => "yml",
-        "markdown" | "md" => "md",
-        "dockerfile" => "Dockerfile",
-        "makefile" => "Makefile",
-        _ => "txt",
-    }.to_string()
+/// * `Vec<Part>` - One `functionResponse` part per call, in order
+async fn execute_function_calls(calls: &[FunctionCall]) -> Vec<Part> {
+    println!("\nExecuting commands:");
+
+    let mut feedback = Vec::new();
+    for call in calls {
+        debug!("Function call: {}({})", call.name, call.args);
+        let call_feedback = match GeminiCommand::from_function_call(call) {
+            Ok(command) => execute_chat_command(command).await,
+            Err(e) => {
+                warn!("{}", e);
+                println!("❌ Error: {}", e);
+                CommandFeedback {
+                    command_type: call.name.clone(),
+                    command_details: call.args.to_string(),
+                    status: CommandStatus::Failure,
+                    message: e.to_string(),
+                }
+            }
+        };
+        feedback.push(call_feedback);
+    }
+
+    print_command_summary(&feedback);
+    calls
+        .iter()
+        .zip(&feedback)
+        .map(|(call, feedback)| feedback.to_function_response(call))
+        .collect()
 }
 
-/// Extracts files from markdown text using headers and code blocks
-///
-/// Parses markdown text to extract file content and names from code blocks.
+/// Checks whether an API error means the model does not support function calling
 ///
 /// # Arguments
 ///
-/// * `text` - The markdown text to parse
+/// * `error` - The error returned by the API
 ///
 /// # Returns
 ///
-/// * `Vec<(String, String)>` - A vector of (filename, content) pairs
-fn extract_files_from_markdown(text: &str) -> Vec<(String, String)> {
-    let mut files = Vec::new();
-    let mut current_file = None;
-    let mut current_content = String::new();
-
-    // Split the text into lines for processing
-    let lines: Vec<&str> = text.lines().collect();
+/// * `bool` - True if the request should be retried with JSON commands instead
+fn is_function_calling_unsupported(error: &AppError) -> bool {
+    match error {
+        AppError::ApiError(message) => {
+            let message = message.to_lowercase();
+            message.contains("function calling")
+                && (message.contains("not enabled") || message.contains("not supported"))
+        }
+        _ => false,
+    }
+}
 
-    for (_i, line) in lines.iter().enumerate() {
-        // Check for file header pattern: "```filename" or "```language:filename"
-        if line.starts_with("```") && !line.trim_start_matches('`').is_empty() {
-            // If we were already collecting a file, save it before starting a new one
-            if let Some(filename) = current_file.take() {
-                debug!("Extracted file from markdown: {}", filename);
-                files.push((filename, current_content.clone()));
-                current_content.clear();
-            }
+/// Reports a failed request as a problem communicating with the API
+///
+/// Requests rejected before sending because they are too large keep their own
+/// error, since retrying them would fail the same way.
+fn communication_error(error: AppError) -> AppError {
+    match error {
+        AppError::InputTooLarge(_) => error,
+        error => AppError::ApiError(format!("Error communicating with Gemini API: {}", error)),
+    }
+}
 
-            let header = line.trim_start_matches('`').trim();
-            
-            // Handle different code block formats
-            if header.contains(':') {
-                // Format: ```language:filename
-                let parts: Vec<&str> = header.splitn(2, ':').collect();
-                if parts.len() == 2 {
-                    let filename = parts[1].trim();
-                    current_file = Some(filename.to_string());
-                    trace!("Found file in markdown with language prefix: {}", filename);
-                }
-            } else if !header.contains(' ') {
-                // Format: ```filename
-                current_file = Some(header.to_string());
-                trace!("Found file in markdown: {}", header);
-            }
-        }
-        // Check for end of code block
-        else if line.trim() == "```" && current_file.is_some() {
-            if let Some(filename) = current_file.take() {
-                debug!("Completed extraction of file: {}", filename);
-                files.push((filename, current_content.clone()));
-                current_content.clear();
-            }
+/// Formats command feedback as a JSON string
+///
+/// Converts a vector of CommandFeedback into a JSON string for sending back to Gemini.
+///
+/// # Arguments
+///
+/// * `feedback` - A vector of CommandFeedback objects
+///
+/// # Returns
+///
+/// * `Result<String, AppError>` - A JSON string containing the feedback or an error
+fn format_feedback(feedback: Vec<CommandFeedback>) -> Result<String, AppError> {
+    match serde_json::to_string(&feedback) {
+        Ok(json) => {
+            debug!("Formatted feedback as JSON: {}", json);
+            Ok(json)
         }
-        // If we're inside a code block, collect the content
-        else if current_file.is_some() {
-            current_content.push_str(line);
-            current_content.push('\n');
+        Err(e) => {
+            error!("Failed to format feedback as JSON: {}", e);
+            Err(AppError::JsonParseError(e))
         }
     }
+}
 
-    // Handle case where the last code block doesn't have a closing ```
-    if let Some(filename) = current_file {
-        debug!("Extracted file from markdown (unclosed block): {}", filename);
-        files.push((filename, current_content));
+/// Attempts to parse a response from Gemini in various formats
+///
+/// This function tries different parsing strategies:
+/// 1. Parse as a GeminiResponse directly
+/// 2. If that fails, check if it's a plain text response without JSON structure
+/// 3. If it looks like JSON but doesn't match our schema, try to extract user_message
+///
+/// # Arguments
+///
+/// * `text_content` - The raw text response from Gemini
+///
+/// # Returns
+///
+/// * `Result<(GeminiResponse, bool), AppError>` - The parsed response and a flag indicating if it was parsed as JSON
+fn parse_gemini_response(text_content: &str) -> Result<(GeminiResponse, bool), AppError> {
+    // First, try to extract JSON from markdown code blocks
+    let json_content = extract_json_from_markdown(text_content);
+    debug!("Attempting to parse JSON content: {}", json_content);
+    
+    // Try to parse as a GeminiResponse directly
+    if let Ok(response) = serde_json::from_str::<GeminiResponse>(&json_content) {
+        return Ok((response, true));
+    }
+    
+    // If it doesn't look like JSON at all, treat it as a plain text response
+    if !json_content.contains("{") && !json_content.contains("}") {
+        debug!("Response doesn't appear to be JSON, treating as plain text");
+        return Ok((
+            GeminiResponse {
+                commands: Vec::new(),
+                user_message: json_content.to_string(),
+            },
+            false
+        ));
+    }
+    
+    // If it looks like JSON but doesn't match our schema, try to extract user_message
+    if json_content.contains("\"user_message\"") {
+        debug!("Attempting to extract user_message from JSON");
+        if let Ok(value) = serde_json::from_str::<serde_json::Value>(&json_content) {
+            if let Some(user_message) = value.get("user_message").and_then(|m| m.as_str()) {
+                return Ok((
+                    GeminiResponse {
+                        commands: Vec::new(),
+                        user_message: user_message.to_string(),
+                    },
+                    false
+                ));
+            }
+        }
     }
+    
+    // If all else fails, return the original text as the user message
+    debug!("Could not parse JSON, using raw text as user_message");
+    Ok((
+        GeminiResponse {
+            commands: Vec::new(),
+            user_message: text_content.to_string(),
+        },
+        false
+    ))
+}
 
-    info!("Extracted {} files from markdown", files.len());
-    files
+/// Strategy for choosing one of several response candidates
+///
+/// - First: Use the first candidate
+/// - Valid: Use the first candidate that is usable as-is: a chat reply that deserializes
+///   to `GeminiResponse` or calls known functions, code whose execution succeeded, or a
+///   codebase with extractable files
+/// - Verify: Use the first candidate whose generated files pass the verification command
+///   (create-codebase only)
+/// - Interactive: Show a preview of every candidate and ask which one to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum SelectionStrategy {
+    First,
+    Valid,
+    Verify,
+    Interactive,
 }
 
-/// Extracts files from code blocks
+/// Chooses one of the candidates of a response
 ///
-/// Parses text to extract code blocks and generates filenames based on the language.
+/// The valid and verify strategies fall back to the first candidate if none
+/// is accepted, so the user still sees what the model returned.
 ///
 /// # Arguments
 ///
-/// * `text` - The text to parse
+/// * `candidates` - The candidates of the response
+/// * `strategy` - How to choose
+/// * `accept` - Check used by the valid and verify strategies, called with the candidate's position
 ///
 /// # Returns
 ///
-/// * `Vec<(String, String)>` - A vector of (filename, content) pairs
-fn extract_files_from_code_blocks(text: &str) -> Vec<(String, String)> {
-    let mut files = Vec::new();
-    let re = regex::Regex::new(r"(?m)^```(\w+)?\s*\n([\s\S]*?)^```").unwrap();
-
-    // Counter for generating unique filenames
-    let mut counter = 1;
-
-    for cap in re.captures_iter(text) {
-        let language = cap.get(1).map_or("txt", |m| m.as_str());
-        let content = cap.get(2).map_or("", |m| m.as_str());
-
-        // Generate a filename based on the language and counter
-        let extension = get_extension_from_language(language);
-        let filename = format!("file_{}.{}", counter, extension);
-        counter += 1;
-
-        debug!("Extracted code block: {} (language: {})", filename, language);
-        files.push((filename, content.to_string()));
+/// * `Option<Candidate>` - The chosen candidate, or `None` if there are no candidates
+fn select_candidate(
+    mut candidates: Vec<Candidate>,
+    strategy: SelectionStrategy,
+    accept: &mut dyn FnMut(usize, &Candidate) -> bool,
+) -> Option<Candidate> {
+    if candidates.len() <= 1 {
+        return candidates.into_iter().next();
     }
 
-    info!("Extracted {} files from code blocks", files.len());
-    files
+    let index = match strategy {
+        SelectionStrategy::First => 0,
+        SelectionStrategy::Valid | SelectionStrategy::Verify => {
+            match candidates.iter().enumerate().position(|(index, candidate)| accept(index, candidate)) {
+                Some(index) => {
+                    info!("Selected candidate {} of {}", index + 1, candidates.len());
+                    index
+                }
+                None => {
+                    warn!("None of the {} candidates was accepted, using the first one", candidates.len());
+                    println!("None of the {} candidates passed the check. Using the first one.", candidates.len());
+                    0
+                }
+            }
+        }
+        SelectionStrategy::Interactive => prompt_for_candidate(&candidates),
+    };
+
+    Some(candidates.swap_remove(index))
 }
 
-/// Cleans and validates a file path
-///
-/// Ensures a file path is safe and normalized.
+/// Shows a preview of every candidate and asks the user to pick one
 ///
 /// # Arguments
 ///
-/// * `file_path` - The file path to clean and validate
+/// * `candidates` - The candidates to choose from
 ///
 /// # Returns
 ///
-/// * `Result<String, AppError>` - The cleaned path or an error if the path is suspicious
-fn clean_and_validate_file_path(file_path: &str) -> Result<String, AppError> {
-    let path = file_path.trim();
-    
-    // Check for suspicious paths
-    if path.contains("..") || path.starts_with('/') || path.starts_with('\\') {
-        warn!("Suspicious file path detected: {}", path);
-        return Err(AppError::ResponseError(format!("Suspicious file path: {}", path)));
+/// * `usize` - The position of the chosen candidate, the first one if input ends
+fn prompt_for_candidate(candidates: &[Candidate]) -> usize {
+    println!("\nGemini returned {} candidates:", candidates.len());
+    for (index, candidate) in candidates.iter().enumerate() {
+        println!("\n[{}] {}", index + 1, describe_candidate(candidate));
+    }
+
+    loop {
+        print!("\nChoose a candidate (1-{}): ", candidates.len());
+        let _ = std::io::stdout().flush();
+
+        let mut input = String::new();
+        match std::io::stdin().read_line(&mut input) {
+            Ok(0) | Err(_) => return 0,
+            Ok(_) => {}
+        }
+        match input.trim().parse::<usize>() {
+            Ok(choice) if (1..=candidates.len()).contains(&choice) => return choice - 1,
+            _ => println!("Please enter a number between 1 and {}.", candidates.len()),
+        }
     }
-    
-    // Normalize path separators
-    let normalized_path = path.replace('\\', "/");
-    
-    trace!("Normalized file path: {} -> {}", path, normalized_path);
-    Ok(normalized_path)
 }
 
-/// Writes files to disk and returns a list of created file paths
-///
-/// Creates files on disk based on the provided content.
+/// Builds a short preview of a candidate for interactive selection
 ///
 /// # Arguments
 ///
-/// * `files` - A vector of (filename, content) pairs
-/// * `output_dir` - The directory where files should be created
+/// * `candidate` - The candidate to describe
 ///
 /// # Returns
 ///
-/// * `Result<Vec<String>, AppError>` - A list of created file paths or an error
-fn write_files_to_disk(
-    files: Vec<(String, String)>,
-    output_dir: &str,
-) -> Result<Vec<String>, AppError> {
-    let mut created_files = Vec::new();
-    let mut file_counter = 0;
-
-    for (file_path, content) in files {
-        file_counter += 1;
-        
-        // Clean and validate the file path
-        let clean_path = clean_and_validate_file_path(&file_path)?;
-        
-        // If the file doesn't have an extension, try to infer one from the content
-        let final_path = if !clean_path.contains('.') {
-            let extension = infer_extension_from_content(&content);
-            format!("{}.{}", clean_path, extension)
-        } else {
-            clean_path
-        };
-        
-        // Create the full path
-        let full_path = Path::new(output_dir).join(&final_path);
-        
-        // Create parent directories if they don't exist
-        if let Some(parent) = full_path.parent() {
-            if !parent.exists() {
-                debug!("Creating parent directory: {}", parent.display());
-                if let Err(e) = fs::create_dir_all(parent) {
-                    error!("Failed to create parent directory {}: {}", parent.display(), e);
-                    return Err(AppError::IoError(e));
-                }
+/// * `String` - The first lines of its text, followed by its function calls and executed code
+fn describe_candidate(candidate: &Candidate) -> String {
+    let mut text = String::new();
+    let mut details = Vec::new();
+    for part in &candidate.content.parts {
+        match part {
+            Part::Text { text: part_text, .. } => text.push_str(part_text),
+            Part::FunctionCall { function_call, .. } => details.push(format!("calls {}", function_call.name)),
+            Part::CodeExecutionResult { code_execution_result } => {
+                details.push(format!("code execution: {}", code_execution_result.outcome))
             }
+            _ => {}
         }
-        
-        // Write the file
-        fs::write(&full_path, content)?;
-        info!("Created file: {}", full_path.display());
-        created_files.push(full_path.to_string_lossy().to_string());
     }
 
-    info!("Successfully created {} files", file_counter);
-    Ok(created_files)
+    let mut preview: Vec<String> = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .take(4)
+        .map(|line| line.chars().take(120).collect())
+        .collect();
+    if text.lines().filter(|line| !line.trim().is_empty()).count() > preview.len() {
+        preview.push("...".to_string());
+    }
+    if !details.is_empty() {
+        preview.push(format!("({})", details.join(", ")));
+    }
+    if let Some(finish_reason) = &candidate.finish_reason {
+        if finish_reason != "STOP" {
+            preview.push(format!("(finished with {})", finish_reason));
+        }
+    }
+
+    preview.join("\n    ")
 }
 
-/// Creates files from a Gemini API response
-///
-/// Extracts file information from the API response and creates the files on disk.
+/// Takes the candidates out of a response
 ///
 /// # Arguments
 ///
-/// * `text` - The text response from Gemini
-/// * `output_dir` - The directory where files should be created
+/// * `response` - The API response
 ///
 /// # Returns
 ///
-/// * `Result<Vec<String>, AppError>` - A list of created file paths or an error
-fn create_files_from_response(
-    text: &str,
-    output_dir: &str,
-) -> Result<Vec<String>, AppError> {
-    // First, try to extract files based on markdown patterns
-    let mut files = extract_files_from_markdown(text);
-
-    // If no files were found using markdown pattern, try to extract code blocks
-    if files.is_empty() {
-        debug!("No files found using markdown pattern, trying code blocks extraction");
-        files = extract_files_from_code_blocks(text);
+/// * `Result<Vec<Candidate>, AppError>` - The candidates, or an error if the prompt was blocked or none came back
+fn response_candidates(response: GeminiApiResponse) -> Result<Vec<Candidate>, AppError> {
+    if let Some(block_reason) = response.prompt_feedback.and_then(|feedback| feedback.block_reason) {
+        error!("Request was blocked: {}", block_reason);
+        return Err(AppError::ResponseError(format!("Request was blocked: {}", block_reason)));
     }
-
-    // If still no files, treat the entire response as a single file
-    if files.is_empty() {
-        warn!("No files found in structured format, treating entire response as a single file");
-        files.push(("README.md".to_string(), text.to_string()));
+    match response.candidates {
+        Some(candidates) if !candidates.is_empty() => Ok(candidates),
+        _ => {
+            error!("No candidates received from Gemini API");
+            Err(AppError::ResponseError("No candidates received from Gemini API".to_string()))
+        }
     }
+}
 
-    write_files_to_disk(
-        files,
-        output_dir
-    )
+/// Gets the concatenated text parts of a candidate
+fn candidate_text(candidate: &Candidate) -> String {
+    candidate
+        .content
+        .parts
+        .iter()
+        .filter_map(|part| match part {
+            Part::Text { text, .. } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect()
 }
 
-/// Executes a shell command
-///
-/// Runs a command in the shell and returns the output.
+/// Checks whether a candidate stopped at the output token limit
+fn is_truncated(candidate: &Candidate) -> bool {
+    candidate.finish_reason.as_deref() == Some("MAX_TOKENS")
+}
+
+/// Checks whether a chat candidate can be used as-is
 ///
 /// # Arguments
 ///
-/// * `command` - The command to execute
+/// * `candidate` - The candidate to check
+/// * `mode` - How commands were requested
 ///
 /// # Returns
 ///
-/// * `Result<String, AppError>` - The command output or an error
-async fn execute_command(command: &str) -> Result<String, AppError> {
-    let parts: Vec<&str> = command.split_whitespace().collect();
-    if parts.is_empty() {
-        error!("Empty command provided");
-        return Err(AppError::CommandError("Empty command".to_string()));
+/// * `bool` - True if all function calls map to commands, or the text is a valid reply for the mode
+fn is_valid_chat_candidate(candidate: &Candidate, mode: CommandMode) -> bool {
+    let calls: Vec<&FunctionCall> = candidate
+        .content
+        .parts
+        .iter()
+        .filter_map(|part| match part {
+            Part::FunctionCall { function_call, .. } => Some(function_call),
+            _ => None,
+        })
+        .collect();
+    if !calls.is_empty() {
+        return calls.iter().all(|call| GeminiCommand::from_function_call(call).is_ok());
     }
-    let cmd = parts[0];
-    let args = &parts[1..];
-    
-    debug!("Executing command: {} with args: {:?}", cmd, args);
-    
-    let output = ProcessCommand::new(cmd)
-        .args(args)
-        .output()
-        .map_err(|e| {
-            error!("Failed to execute command: {}", e);
-            AppError::IoError(e)
-        })?;
-        
-    if output.status.success() {
-        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-        debug!("Command executed successfully");
-        trace!("Command output: {}", stdout);
-        Ok(stdout)
-    } else {
-        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-        error!("Command execution failed: {}", stderr);
-        Err(AppError::CommandError(stderr))
+
+    let text = candidate_text(candidate);
+    match parse_gemini_response(&text) {
+        Ok((_, is_json)) => {
+            !is_truncated(candidate) && !text.trim().is_empty() && (is_json || mode == CommandMode::Functions)
+        }
+        Err(_) => false,
     }
 }
 
-/// Gets system information for the prompt
+/// Checks whether an execute candidate ran its code successfully
 ///
-/// Collects information about the operating system and environment.
+/// # Arguments
+///
+/// * `candidate` - The candidate to check
 ///
 /// # Returns
 ///
-/// * `String` - A string containing system information
-fn get_system_info() -> String {
-    format!(
-        "OS: {}\nArch: {}\nDir: {:?}",
-        env::consts::OS,
-        env::consts::ARCH,
-        env::current_dir().unwrap_or_default()
-    )
+/// * `bool` - True if the candidate finished normally and every execution result is `OUTCOME_OK`
+fn is_valid_execute_candidate(candidate: &Candidate) -> bool {
+    candidate.finish_reason.as_deref().is_none_or(|reason| reason == "STOP")
+        && !candidate.content.parts.is_empty()
+        && candidate.content.parts.iter().all(|part| match part {
+            Part::CodeExecutionResult { code_execution_result } => code_execution_result.outcome == "OUTCOME_OK",
+            _ => true,
+        })
 }
 
-/// Extracts JSON content from markdown code blocks
-///
-/// This function looks for JSON content wrapped in markdown code blocks (```json ... ```)
-/// and extracts the JSON content. If the content is not wrapped in code blocks, it returns
-/// the original content.
+/// Checks whether a create-codebase candidate contains files in the expected layout
 ///
 /// # Arguments
 ///
-/// * `content` - The content potentially containing markdown code blocks
+/// * `candidate` - The candidate to check
 ///
 /// # Returns
 ///
-/// * The extracted JSON content or the original content if no code blocks are found
-fn extract_json_from_markdown(content: &str) -> String {
-    // Check for markdown code blocks with JSON
-    if content.trim().starts_with("```json") && content.trim().ends_with("```") {
-        // Extract the content between the code blocks
-        let start_idx = content.find("```json").map(|i| i + 7).unwrap_or(0);
-        let end_idx = content.rfind("```").unwrap_or(content.len());
-        
-        if start_idx < end_idx {
-            return content[start_idx..end_idx].trim().to_string();
-        }
-    }
-    
-    // If no code blocks or extraction failed, return the original content
-    content.to_string()
+/// * `bool` - True if files can be extracted from its text
+fn is_valid_codebase_candidate(candidate: &Candidate) -> bool {
+    let text = candidate_text(candidate);
+    !extract_files_from_markdown(&text).is_empty() || !extract_files_from_code_blocks(&text).is_empty()
 }
 
-/// Processes a command from the Gemini API
+/// Checks a create-codebase candidate by running a command in its generated files
 ///
-/// Executes a command received from the Gemini API and returns feedback about the execution.
+/// The files are written to a temporary directory that is removed afterwards.
 ///
 /// # Arguments
 ///
-/// * `command` - The command to execute
-/// * `output_dir` - The directory where files should be created
+/// * `candidate` - The candidate to check
+/// * `index` - The candidate's position, used to name the directory
+/// * `verify_command` - The shell command to run
 ///
 /// # Returns
 ///
-/// * `Result<CommandFeedback, AppError>` - Feedback about the command execution or an error
-async fn process_command(
-    command: &GeminiCommand,
-    output_dir: &str,
-) -> Result<CommandFeedback, AppError> {
-    match command {
-        GeminiCommand::CreateFolder { path } => {
-            let clean_path = clean_and_validate_file_path(path)?;
-            let full_path = Path::new(output_dir).join(&clean_path);
-            
-            debug!("Creating folder: {}", full_path.display());
-            
-            if let Err(e) = fs::create_dir_all(&full_path) {
-                error!("Failed to create folder {}: {}", full_path.display(), e);
-                return Ok(CommandFeedback {
-                    command_type: "create_folder".to_string(),
-                    command_details: format!("path: {}", path),
-                    status: CommandStatus::Failure,
-                    message: format!("Failed to create folder: {}", e),
-                });
-            }
-            
-            info!("Created folder: {}", full_path.display());
-            
-            Ok(CommandFeedback {
-                command_type: "create_folder".to_string(),
-                command_details: format!("path: {}", path),
-                status: CommandStatus::Success,
-                message: format!("Created folder: {}", full_path.display()),
-            })
+/// * `bool` - True if the command exits successfully
+fn verify_codebase_candidate(candidate: &Candidate, index: usize, verify_command: &str) -> bool {
+    let staging_dir = env::temp_dir().join(format!(
+        "gemini-codemaker-candidate-{}-{}",
+        std::process::id(),
+        index + 1
+    ));
+    let staging = staging_dir.to_string_lossy().to_string();
+
+    let result = create_files_from_response(&candidate_text(candidate), &staging).and_then(|_| {
+        let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+        ProcessCommand::new(shell)
+            .args([flag, verify_command])
+            .current_dir(&staging_dir)
+            .output()
+            .map_err(AppError::IoError)
+    });
+    let _ = fs::remove_dir_all(&staging_dir);
+
+    match result {
+        Ok(output) if output.status.success() => {
+            println!("✅ Candidate {} passed verification", index + 1);
+            true
         }
-        GeminiCommand::CreateFile { path, content } => {
-            let clean_path = clean_and_validate_file_path(path)?;
-            let full_path = Path::new(output_dir).join(&clean_path);
-            
-            debug!("Creating file: {}", full_path.display());
-            
-            // Create parent directories if they don't exist
-            if let Some(parent) = full_path.parent() {
-                if !parent.exists() {
-                    debug!("Creating parent directory: {}", parent.display());
-                    if let Err(e) = fs::create_dir_all(parent) {
-                        error!("Failed to create parent directory {}: {}", parent.display(), e);
-                        return Ok(CommandFeedback {
-                            command_type: "create_file".to_string(),
-                            command_details: format!("path: {}", path),
-                            status: CommandStatus::Failure,
-                            message: format!("Failed to create parent directory: {}", e),
-                        });
-                    }
-                }
-            }
-            
-            if let Err(e) = fs::write(&full_path, content) {
-                error!("Failed to write file {}: {}", full_path.display(), e);
-                return Ok(CommandFeedback {
-                    command_type: "create_file".to_string(),
-                    command_details: format!("path: {}", path),
-                    status: CommandStatus::Failure,
-                    message: format!("Failed to write file: {}", e),
-                });
-            }
-            
-            info!("Created file: {}", full_path.display());
-            
-            Ok(CommandFeedback {
-                command_type: "create_file".to_string(),
-                command_details: format!("path: {}", path),
-                status: CommandStatus::Success,
-                message: format!("Created file: {}", full_path.display()),
-            })
+        Ok(output) => {
+            println!("❌ Candidate {} failed verification ({})", index + 1, output.status);
+            debug!("Verification output: {}", String::from_utf8_lossy(&output.stderr));
+            false
         }
-        GeminiCommand::WriteCodeToFile { path, code } => {
-            let clean_path = clean_and_validate_file_path(path)?;
-            let full_path = Path::new(output_dir).join(&clean_path);
-            
-            debug!("Writing code to file: {}", full_path.display());
-            
-            // Create parent directories if they don't exist
-            if let Some(parent) = full_path.parent() {
-                if !parent.exists() {
-                    debug!("Creating parent directory: {}", parent.display());
-                    if let Err(e) = fs::create_dir_all(parent) {
-                        error!("Failed to create parent directory {}: {}", parent.display(), e);
-                        return Ok(CommandFeedback {
-                            command_type: "write_code_to_file".to_string(),
-                            command_details: format!("path: {}", path),
-                            status: CommandStatus::Failure,
-                            message: format!("Failed to create parent directory: {}", e),
-                        });
-                    }
-                }
-            }
-            
-            if let Err(e) = fs::write(&full_path, code) {
-                error!("Failed to write code to file {}: {}", full_path.display(), e);
-                return Ok(CommandFeedback {
-                    command_type: "write_code_to_file".to_string(),
-                    command_details: format!("path: {}", path),
-                    status: CommandStatus::Failure,
-                    message: format!("Failed to write code to file: {}", e),
-                });
-            }
-            
-            info!("Wrote code to file: {}", full_path.display());
-            
-            Ok(CommandFeedback {
-                command_type: "write_code_to_file".to_string(),
-                command_details: format!("path: {}", path),
-                status: CommandStatus::Success,
-                message: format!("Wrote code to file: {}", full_path.display()),
-            })
+        Err(e) => {
+            println!("❌ Candidate {} could not be verified: {}", index + 1, e);
+            false
         }
-        GeminiCommand::ExecuteCommand { command, args } => {
-            let cmd_str = format!("{} {}", command, args.join(" "));
-            debug!("Executing command: {}", cmd_str);
-            
-            match execute_command(&cmd_str).await {
-                Ok(output) => {
-                    info!("Command executed successfully: {}", cmd_str);
-                    Ok(CommandFeedback {
-                        command_type: "execute_command".to_string(),
-                        command_details: cmd_str.clone(),
-                        status: CommandStatus::Success,
-                        message: format!("Command executed successfully. Output: {}", output),
-                    })
-                }
-                Err(e) => {
-                    error!("Command execution failed: {}", e);
-                    Ok(CommandFeedback {
-                        command_type: "execute_command".to_string(),
-                        command_details: cmd_str.clone(),
-                        status: CommandStatus::Failure,
-                        message: format!("Command execution failed: {}", e),
-                    })
-                }
-            }
+    }
+}
+
+/// Prints a streamed part of a response as soon as it arrives
+///
+/// # Arguments
+///
+/// * `part` - The part to print
+/// * `show_thoughts` - Whether to print thought summaries
+fn print_streamed_part(part: &Part, show_thoughts: bool) {
+    match part {
+        Part::Text { text, .. } => print!("{}", text),
+        Part::Thought { text, .. } if show_thoughts => print!("{}", dimmed(text)),
+        Part::Thought { .. } => {}
+        Part::ExecutableCode { executable_code } => {
+            print!("\n```{}\n{}\n```\n", executable_code.language, executable_code.code);
+        }
+        Part::CodeExecutionResult { code_execution_result } => {
+            print!(
+                "\nExecution result: {}\n{}\n",
+                code_execution_result.outcome, code_execution_result.output
+            );
+        }
+        Part::FunctionCall { function_call, .. } => {
+            print!("\n[calling {}]\n", function_call.name);
+        }
+        other => debug!("Skipping unsupported streamed part: {:?}", other),
+    }
+    if let Err(e) = std::io::stdout().flush() {
+        warn!("Failed to flush streamed output: {}", e);
+    }
+}
+
+/// Dims text with ANSI escape codes when stdout is a terminal
+///
+/// # Arguments
+///
+/// * `text` - The text to dim
+///
+/// # Returns
+///
+/// * `String` - The text, wrapped in escape codes if stdout is a terminal
+fn dimmed(text: &str) -> String {
+    if std::io::stdout().is_terminal() {
+        format!("\x1b[2m{}\x1b[0m", text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Prints the thought summaries of a complete candidate, dimmed
+///
+/// # Arguments
+///
+/// * `candidate` - The candidate whose thoughts to print
+fn print_thoughts(candidate: &Candidate) {
+    for part in &candidate.content.parts {
+        if let Part::Thought { text, .. } = part {
+            println!("\n{}", dimmed(text.trim_end()));
         }
     }
 }
 
-/// Processes the response from the Gemini API
+/// Formats the sources of a candidate's answer as numbered lines
 ///
-/// Extracts and executes commands from the Gemini API response.
+/// Web sources from the grounding metadata come first, numbered by their position
+/// so the numbers match the chunk indices of the grounding supports. Recitation
+/// citations that are not already listed follow.
 ///
 /// # Arguments
 ///
-/// * `response_text` - The text response from Gemini
-/// * `output_dir` - The directory where files should be created
+/// * `candidate` - The candidate whose sources to format
 ///
 /// # Returns
 ///
-/// * `Result<Vec<CommandFeedback>, AppError>` - Feedback about the command executions or an error
-async fn process_response(
-    response_text: &str,
-    output_dir: &str,
-) -> Result<Vec<CommandFeedback>, AppError> {
-    // Try to parse the response as JSON
-    let response_result: Result<GeminiResponse, serde_json::Error> = serde_json::from_str(response_text);
-    
-    match response_result {
-        Ok(response) => {
-            info!("Successfully parsed JSON response with {} commands", response.commands.len());
-            let mut feedback = Vec::new();
-            
-            for command in response.commands {
-                match process_command(&command, output_dir).await {
-                    Ok(cmd_feedback) => {
-                        feedback.push(cmd_feedback);
-                    }
-                    Err(e) => {
-                        error!("Error processing command: {}", e);
-                        return Err(e);
-                    }
-                }
-            }
-            
-            Ok(feedback)
+/// * `Vec<String>` - One line per source, e.g. `[1] Title - https://...`
+fn format_sources(candidate: &Candidate) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut uris = Vec::new();
+
+    let chunks = candidate
+        .grounding_metadata
+        .as_ref()
+        .map(|metadata| metadata.grounding_chunks.as_slice())
+        .unwrap_or_default();
+    for (index, chunk) in chunks.iter().enumerate() {
+        let Some(uri) = chunk.web.as_ref().and_then(|web| web.uri.as_deref()) else {
+            continue;
+        };
+        match chunk.web.as_ref().and_then(|web| web.title.as_deref()) {
+            Some(title) => lines.push(format!("[{}] {} - {}", index + 1, title, uri)),
+            None => lines.push(format!("[{}] {}", index + 1, uri)),
         }
-        Err(e) => {
-            warn!("Failed to parse response as JSON: {}", e);
-            debug!("Attempting to extract files from markdown response");
-            
-            // If JSON parsing fails, try to extract files from markdown
-            match create_files_from_response(response_text, output_dir) {
-                Ok(files) => {
-                    info!("Created {} files from markdown response", files.len());
-                    let mut feedback = Vec::new();
-                    
-                    for file in files {
-                        feedback.push(CommandFeedback {
-                            command_type: "create_file".to_string(),
-                            command_details: format!("path: {}", file),
-                            status: CommandStatus::Success,
-                            message: format!("Created file: {}", file),
-                        });
-                    }
-                    
-                    Ok(feedback)
-                }
-                Err(e) => {
-                    error!("Failed to create files from response: {}", e);
-                    Err(e)
-                }
-            }
+        uris.push(uri);
+    }
+
+    let citations = candidate
+        .citation_metadata
+        .as_ref()
+        .map(|metadata| metadata.citation_sources.as_slice())
+        .unwrap_or_default();
+    let mut number = chunks.len();
+    for uri in citations.iter().filter_map(|source| source.uri.as_deref()) {
+        if !uris.contains(&uri) {
+            number += 1;
+            lines.push(format!("[{}] {}", number, uri));
+            uris.push(uri);
         }
     }
+
+    lines
 }
 
-/// Formats command feedback as a JSON string
+/// Marks the grounded segments of a text part with the numbers of their sources
 ///
-/// Converts a vector of CommandFeedback into a JSON string for sending back to Gemini.
+/// A marker such as `[1][2]` is inserted where each segment ends, using the
+/// numbering of [`format_sources`].
 ///
 /// # Arguments
 ///
-/// * `feedback` - A vector of CommandFeedback objects
+/// * `candidate` - The candidate holding the grounding metadata
+/// * `part_index` - The index of the text part in the candidate's content
+/// * `text` - The text of the part
 ///
 /// # Returns
 ///
-/// * `Result<String, AppError>` - A JSON string containing the feedback or an error
-fn format_feedback(feedback: Vec<CommandFeedback>) -> Result<String, AppError> {
-    match serde_json::to_string(&feedback) {
-        Ok(json) => {
-            debug!("Formatted feedback as JSON: {}", json);
-            Ok(json)
-        }
-        Err(e) => {
-            error!("Failed to format feedback as JSON: {}", e);
-            Err(AppError::JsonParseError(e))
-        }
+/// * `String` - The text with citation markers, unchanged without grounding supports
+fn cite_grounded_text(candidate: &Candidate, part_index: usize, text: &str) -> String {
+    let supports = candidate
+        .grounding_metadata
+        .as_ref()
+        .map(|metadata| metadata.grounding_supports.as_slice())
+        .unwrap_or_default();
+    let mut markers: Vec<(usize, String)> = supports
+        .iter()
+        .filter_map(|support| {
+            let segment = support.segment.as_ref().filter(|segment| segment.part_index == part_index)?;
+            let mut end = segment.end_index?.min(text.len());
+            while !text.is_char_boundary(end) {
+                end += 1;
+            }
+            let marker: String = support
+                .grounding_chunk_indices
+                .iter()
+                .map(|index| format!("[{}]", index + 1))
+                .collect();
+            Some((end, marker))
+        })
+        .collect();
+
+    // Inserting from the back keeps the earlier offsets valid
+    markers.sort_by_key(|(end, _)| std::cmp::Reverse(*end));
+    let mut cited = text.to_string();
+    for (end, marker) in markers {
+        cited.insert_str(end, &marker);
     }
+    cited
 }
 
-/// Extracts text from a Gemini API response
-///
-/// Gets the text content from the API response.
+/// Prints the sources and search queries of a candidate's answer, if any
 ///
 /// # Arguments
 ///
-/// * `response` - The Gemini API response
-///
-/// # Returns
-///
-/// * `Result<String, AppError>` - The extracted text or an error
-fn extract_text_from_response(response: GeminiApiResponse) -> Result<String, AppError> {
-    // Check if the response was blocked
-    if let Some(feedback) = response.prompt_feedback {
-        if let Some(reason) = feedback.block_reason {
-            error!("Response was blocked: {}", reason);
-            return Err(AppError::ResponseError(format!("Response was blocked: {}", reason)));
+/// * `candidate` - The candidate whose sources to print
+fn print_sources(candidate: &Candidate) {
+    let sources = format_sources(candidate);
+    if !sources.is_empty() {
+        println!("\nSources:");
+        for source in &sources {
+            println!("  {}", source);
         }
     }
-    
-    // Extract the text from the response
-    if let Some(candidates) = response.candidates {
-        if candidates.is_empty() {
-            warn!("No candidates in response");
-            return Err(AppError::ResponseError("No candidates in response".to_string()));
-        }
-        
-        let candidate = &candidates[0];
-        
-        // Check if the response was cut off
-        if let Some(reason) = &candidate.finish_reason {
-            if reason != "STOP" {
-                warn!("Response was cut off: {}", reason);
-            }
-        }
-        
-        // Extract the text from the parts
-        let mut result = String::new();
-        
-        for part in &candidate.content.parts {
-            match part {
-                Part::Text { text } => {
-                    result.push_str(&text);
-                }
-                Part::ExecutableCode { executable_code } => {
-                    debug!("Found executable code in response: {}", executable_code.language);
-                    result.push_str(&format!("```{}\n{}\n```\n", executable_code.language, executable_code.code));
-                }
-                Part::CodeExecutionResult { code_execution_result } => {
-                    debug!("Found code execution result in response: {}", code_execution_result.outcome);
-                    result.push_str(&format!("Execution result: {}\nOutput: {}\n", 
-                                           code_execution_result.outcome, 
-                                           code_execution_result.output));
-                }
-            }
-        }
-        
-        if result.is_empty() {
-            warn!("Empty response from Gemini");
-            return Err(AppError::ResponseError("Empty response from Gemini".to_string()));
-        }
-        
-        debug!("Extracted text from response: {}", result.len());
-        Ok(result)
-    } else {
-        warn!("No candidates in response");
-        Err(AppError::ResponseError("No candidates in response".to_string()))
+
+    let queries = candidate
+        .grounding_metadata
+        .as_ref()
+        .map(|metadata| metadata.web_search_queries.as_slice())
+        .unwrap_or_default();
+    if !queries.is_empty() {
+        info!("Searched for: {}", queries.join("; "));
+        println!("{}", dimmed(&format!("Searched for: {}", queries.join("; "))));
     }
 }
 
-/// Attempts to parse a response from Gemini in various formats
-///
-/// This function tries different parsing strategies:
-/// 1. Parse as a GeminiResponse directly
-/// 2. If that fails, check if it's a plain text response without JSON structure
-/// 3. If it looks like JSON but doesn't match our schema, try to extract user_message
+/// Gets the Gemini client for features only the Gemini API and Vertex AI offer
 ///
 /// # Arguments
 ///
-/// * `text_content` - The raw text response from Gemini
+/// * `client` - The Gemini client, `None` when another backend is configured
+/// * `feature` - The feature being used, for the error message
 ///
 /// # Returns
 ///
-/// * `Result<(GeminiResponse, bool), AppError>` - The parsed response and a flag indicating if it was parsed as JSON
-fn parse_gemini_response(text_content: &str) -> Result<(GeminiResponse, bool), AppError> {
-    // First, try to extract JSON from markdown code blocks
-    let json_content = extract_json_from_markdown(text_content);
-    debug!("Attempting to parse JSON content: {}", json_content);
-    
-    // Try to parse as a GeminiResponse directly
-    if let Ok(response) = serde_json::from_str::<GeminiResponse>(&json_content) {
-        return Ok((response, true));
-    }
-    
-    // If it doesn't look like JSON at all, treat it as a plain text response
-    if !json_content.contains("{") && !json_content.contains("}") {
-        debug!("Response doesn't appear to be JSON, treating as plain text");
-        return Ok((
-            GeminiResponse {
-                commands: Vec::new(),
-                user_message: json_content.to_string(),
-            },
-            false
-        ));
-    }
-    
-    // If it looks like JSON but doesn't match our schema, try to extract user_message
-    if json_content.contains("\"user_message\"") {
-        debug!("Attempting to extract user_message from JSON");
-        if let Ok(value) = serde_json::from_str::<serde_json::Value>(&json_content) {
-            if let Some(user_message) = value.get("user_message").and_then(|m| m.as_str()) {
-                return Ok((
-                    GeminiResponse {
-                        commands: Vec::new(),
-                        user_message: user_message.to_string(),
-                    },
-                    false
-                ));
-            }
-        }
-    }
-    
-    // If all else fails, return the original text as the user message
-    debug!("Could not parse JSON, using raw text as user_message");
-    Ok((
-        GeminiResponse {
-            commands: Vec::new(),
-            user_message: text_content.to_string(),
-        },
-        false
-    ))
+/// * `Result<&GeminiClient, AppError>` - The client or an error naming the feature
+fn require_gemini<'a>(client: Option<&'a GeminiClient>, feature: &str) -> Result<&'a GeminiClient, AppError> {
+    client.ok_or_else(|| AppError::ConfigError(format!("{} is only available with the gemini and vertex backends", feature)))
+}
+
+/// Returns the streaming callback to use, or `None` when streaming is disabled
+///
+/// # Arguments
+///
+/// * `stream` - Whether the `--stream` flag was given
+/// * `printer` - The callback printing streamed parts
+fn stream_handler(
+    stream: bool,
+    printer: &mut dyn FnMut(&Part),
+) -> Option<&mut dyn FnMut(&Part)> {
+    stream.then_some(printer)
 }
 
 /// Main function
@@ -1264,38 +8020,228 @@ async fn main() -> Result<(), AppError> {
     
     let cli = Cli::parse();
 
-    // Get API key from environment variable or prompt user if not set
-    let api_key = match env::var("GEMINI_API_KEY") {
-        Ok(key) => key,
-        Err(_) => {
-            error!("GEMINI_API_KEY environment variable not set");
-            return Err(AppError::EnvError(
-                "GEMINI_API_KEY environment variable not set. Please set it with: export GEMINI_API_KEY=your_api_key_here".to_string()
-            ));
+    // The mock server stands in for the API, so it needs neither a client nor credentials
+    if let Commands::MockServer { scenario, address } = &cli.command {
+        return run_mock_server(MockScenario::load(scenario)?, address).await;
+    }
+
+    let config = Config::resolve(cli.config.as_deref())?;
+
+    // Build the API client once; every subcommand shares it
+    let http = config.http.client()?;
+    let retry_policy = RetryPolicy {
+        max_attempts: cli.retry_max_attempts,
+        base_delay: Duration::from_millis(cli.retry_base_delay_ms),
+        max_delay: Duration::from_millis(cli.retry_max_delay_ms),
+        jitter: !cli.no_retry_jitter,
+        read_timeout: Some(config.http.read_timeout()),
+    };
+    debug!("Using retry policy: {:?}", retry_policy);
+
+    let mut generation = cli.generation.clone().or(&config.generation);
+    if cli.show_thoughts && generation.include_thoughts.is_none() {
+        generation.include_thoughts = Some(true);
+    }
+    generation.validate()?;
+    debug!("Using generation settings: {:?}", generation);
+
+    let input_token_limit = cli.input_token_limit.or(config.input_token_limit);
+    if input_token_limit == Some(0) {
+        return Err(AppError::ConfigError("input_token_limit must be at least 1".to_string()));
+    }
+
+    // Gemini and Vertex AI get a Gemini client, the other backends a chat completions client
+    let input_token_limit_or_default = input_token_limit.unwrap_or(DEFAULT_INPUT_TOKEN_LIMIT);
+    let secrets = config.backend.secrets();
+
+    // A replay answers from disk, so it needs neither the network nor credentials
+    let replayer = cli
+        .replay
+        .as_deref()
+        .map(|dir| Replayer::load(Path::new(dir), secrets.clone()))
+        .transpose()?
+        .map(|replayer| {
+            replayer
+                .with_input_token_limit(input_token_limit_or_default)
+                .with_lenient_matching(cli.replay_lenient)
+        });
+    // Replayed requests never reach the API, so they are not rate limited
+    let rate_limiter = match &replayer {
+        Some(_) => None,
+        None => RateLimiter::from_config(&config.rate_limits)?.map(Arc::new),
+    };
+    let mut gemini = match &replayer {
+        Some(_) => None,
+        None => GeminiClient::from_config(&config.backend)?.map(|client| {
+            let client = client
+                .with_http_client(http.clone())
+                .with_retry_policy(retry_policy.clone())
+                .with_generation_config(generation.to_generation_config())
+                .with_input_token_limit(input_token_limit_or_default);
+            match &rate_limiter {
+                Some(limiter) => client.with_rate_limiter(limiter.clone()),
+                None => client,
+            }
+        }),
+    };
+    let openai = match &replayer {
+        Some(_) => None,
+        None => OpenAiClient::from_config(&config.backend)?.map(|client| {
+            let client = client
+                .with_http_client(http.clone())
+                .with_retry_policy(retry_policy.clone())
+                .with_generation_config(generation.to_generation_config())
+                .with_input_token_limit(input_token_limit_or_default);
+            match &rate_limiter {
+                Some(limiter) => client.with_rate_limiter(limiter.clone()),
+                None => client,
+            }
+        }),
+    };
+
+    // Check that the model exists and can serve the subcommand before sending anything.
+    // When the models endpoint cannot be reached the request itself will report the problem.
+    if let Some(client) = gemini.as_mut().filter(|client| !client.is_vertex()) {
+        if !matches!(cli.command, Commands::Models { .. }) {
+            match client.get_model(&client.model).await {
+                Ok(Some(model)) => {
+                    check_model_capabilities(&model, &cli.command, &generation)?;
+                    if let (None, Some(limit)) = (input_token_limit, model.input_token_limit) {
+                        debug!("Using the input token limit of {} tokens of model {}", limit, model.id());
+                        client.input_token_limit = limit;
+                    }
+                }
+                Ok(None) => {
+                    return Err(AppError::ConfigError(format!(
+                        "Model {} was not found. Set {} to one of the models shown by `models list`",
+                        client.model, GEMINI_MODEL_ENV_VAR
+                    )));
+                }
+                Err(e) => warn!("Could not validate model {}: {}", client.model, e),
+            }
+        }
+    }
+
+    // Check the cache up front so an expired one fails before any work is done
+    let mut active_cache = None;
+    if let Some(name) = &cli.cached_content {
+        let cache = require_gemini(gemini.as_ref(), "--cached-content")?
+            .get_cached_content(name)
+            .await?;
+        if cache.remaining().is_some_and(|remaining| remaining.is_zero()) {
+            return Err(AppError::ConfigError(format!("Cached content {} has expired", cache.name)));
+        }
+        println!("Using cached content {}", cache.summary());
+        gemini = gemini.map(|client| client.with_cached_content(cache.name.clone()));
+        active_cache = Some(cache);
+    }
+
+    // Queries are embedded with the model the index was built with
+    let retrieval = match &cli.index {
+        Some(path) => {
+            let index = CodeIndex::load(path)?;
+            let client = require_gemini(gemini.as_ref(), "--index")?;
+            info!("Using index {} with {} chunks embedded by {}", path, index.chunks.len(), index.model);
+            Some((GeminiEmbedder::new(client, &index.model, index.dimensions), index))
         }
+        None => None,
+    };
+    let retrieval = retrieval
+        .as_ref()
+        .map(|(embedder, index)| (embedder as &dyn Embedder, index));
+
+    let backend: &dyn LlmBackend = match (&replayer, &gemini, &openai) {
+        (Some(replayer), _, _) => replayer,
+        (None, Some(gemini), _) => gemini,
+        (None, None, Some(openai)) => openai,
+        (None, None, None) => unreachable!("every backend configuration selects a client"),
+    };
+    let recorder = cli
+        .record
+        .as_deref()
+        .map(|dir| Recorder::new(backend, Path::new(dir), secrets))
+        .transpose()?;
+    let client: &dyn LlmBackend = match &recorder {
+        Some(recorder) => recorder,
+        None => backend,
     };
 
+    let prices = cli.price_table.as_deref().map(PriceTable::load).transpose()?;
+    let mut usage = TokenUsage::default();
+
+    let system_prompt = cli
+        .system_prompt_file
+        .as_deref()
+        .map(|path| {
+            fs::read_to_string(path).map_err(|e| {
+                AppError::ConfigError(format!("Failed to read system prompt file {}: {}", path, e))
+            })
+        })
+        .transpose()?;
+    if let Some(path) = &cli.system_prompt_file {
+        debug!("Using system prompt from {}", path);
+    }
+
+    // Validate the candidate selection before spending any tokens
+    if cli.select == SelectionStrategy::Verify {
+        if !matches!(cli.command, Commands::CreateCodebase { .. }) {
+            return Err(AppError::ConfigError("--select verify is only supported by create-codebase".to_string()));
+        }
+        if cli.verify_command.is_none() {
+            return Err(AppError::ConfigError("--select verify requires --verify-command".to_string()));
+        }
+    }
+
+    // Parts of several candidates would be interleaved, so only a single candidate is streamed
+    let stream = cli.stream && generation.candidate_count.unwrap_or(1) <= 1;
+    if cli.stream && !stream {
+        warn!("Streaming is disabled when requesting several candidates");
+        println!("Streaming is disabled when requesting several candidates.");
+    }
+
     let system_info = get_system_info();
-    let mut feedback_messages = Vec::new();
-    let mut feedback_string = String::new();
+    let mut attachments = Vec::new();
+    for path in &cli.attach {
+        attachments.push(load_attachment(client, path).await?);
+    }
+
+    let show_thoughts = cli.show_thoughts;
+    let mut stream_printer = |part: &Part| print_streamed_part(part, show_thoughts);
 
     match &cli.command {
-        Commands::Chat { query } => {
+        Commands::Chat { query, history_length, max_tokens_budget, command_mode } => {
             // Initialize with the query from command line if provided
             let mut initial_query = query.clone();
+            let mut command_mode = grounded_command_mode(*command_mode, cli.grounding);
+            let mut history = ChatHistory::new(*history_length);
             
             // Create a continuous chat loop
+            // Files given with --attach go with the first query
+            let mut pending_attachments = attachments.clone();
+
             println!("Starting chat with Gemini. Type 'exit' or 'quit' to end the conversation.");
+            println!("Type '/attach <path>' to send a file with your next query.");
             
             loop {
+                // Stop once the session has used up its token budget
+                if let Some(budget) = max_tokens_budget {
+                    if usage.total_tokens >= *budget {
+                        println!(
+                            "\nToken budget of {} exhausted ({} tokens used). Ending chat session.",
+                            budget, usage.total_tokens
+                        );
+                        break;
+                    }
+                }
+
                 // Get the current query
                 let current_query = match initial_query.take() {
                     Some(q) => q,
                     None => {
                         print!("\nEnter your query: ");
-                        std::io::stdout().flush().map_err(|e| AppError::IoError(e))?;
+                        std::io::stdout().flush().map_err(AppError::IoError)?;
                         let mut input = String::new();
-                        std::io::stdin().read_line(&mut input).map_err(|e| AppError::IoError(e))?;
+                        std::io::stdin().read_line(&mut input).map_err(AppError::IoError)?;
                         let input = input.trim().to_string();
                         
                         // Check for exit commands
@@ -1308,286 +8254,220 @@ async fn main() -> Result<(), AppError> {
                         if input.is_empty() {
                             continue;
                         }
+
+                        // Queue a file for the next query
+                        if let Some(path) = input.strip_prefix("/attach") {
+                            let path = path.trim();
+                            if path.is_empty() {
+                                println!("Usage: /attach <path>");
+                            } else {
+                                match load_attachment(client, path).await {
+                                    Ok(part) => pending_attachments.push(part),
+                                    Err(e) => {
+                                        error!("{}", e);
+                                        println!("{}", e);
+                                    }
+                                }
+                            }
+                            continue;
+                        }
                         
                         input
                     }
                 };
                 
                 info!("User Query: '{}'", current_query);
+
+                if let (Some(cache), Some(gemini)) = (active_cache.as_mut(), gemini.as_ref()) {
+                    keep_cache_alive(gemini, cache).await;
+                }
                 
-                let gemini_response = match chat_with_gemini(&current_query, &system_info, &api_key, &feedback_string).await {
-                    Ok(response) => response,
-                    Err(e) => {
-                        error!("Error communicating with Gemini API: {}", e);
-                        println!("Error communicating with Gemini API: {}. Please try again.", e);
-                        continue;
-                    }
+                // Send the query, then keep answering function calls until
+                // Gemini replies without calling one
+                let mut pending_query = Some(Content::user(&current_query, &pending_attachments));
+                let query_info = match project_context(retrieval, &current_query, cli.index_top_k).await {
+                    Some(context) => format!("{}\n\n{}", system_info, context),
+                    None => system_info.clone(),
                 };
-                
-                let candidates = match gemini_response.candidates {
-                    Some(candidates) => candidates,
-                    None => {
-                        if let Some(prompt_feedback) = gemini_response.prompt_feedback {
-                            if let Some(block_reason) = prompt_feedback.block_reason {
-                                error!("Request was blocked: {}", block_reason);
-                                println!("Your request was blocked: {}. Please try a different query.", block_reason);
-                            } else {
-                                error!("No candidates received from Gemini API");
-                                println!("No response received from Gemini. Please try again.");
-                            }
-                        } else {
-                            error!("No candidates received from Gemini API");
-                            println!("No response received from Gemini. Please try again.");
+                for round in 1..=MAX_FUNCTION_CALL_ROUNDS {
+                    let gemini_response = match chat_with_gemini(client, &mut history, pending_query.as_ref(), &query_info, command_mode, system_prompt.as_deref(), cli.grounding, stream_handler(stream, &mut stream_printer)).await {
+                        Ok(response) => response,
+                        Err(e) if command_mode == CommandMode::Functions && is_function_calling_unsupported(&e) => {
+                            warn!("Model does not support function calling, falling back to JSON commands: {}", e);
+                            println!("The model does not support function calling. Falling back to JSON commands.");
+                            command_mode = CommandMode::Json;
+                            continue;
                         }
-                        continue;
+                        Err(e @ AppError::InputTooLarge(_)) => {
+                            error!("{}", e);
+                            println!("{}. Shorten the query, attach fewer files or lower --history-length.", e);
+                            break;
+                        }
+                        Err(e) => {
+                            error!("Error communicating with Gemini API: {}", e);
+                            println!("Error communicating with Gemini API: {}. Please try again.", e);
+                            break;
+                        }
+                    };
+                    usage.record(&gemini_response);
+
+                    let candidates = match response_candidates(gemini_response) {
+                        Ok(candidates) => candidates,
+                        Err(e) => {
+                            println!("{}. Please try again or rephrase your query.", e);
+                            break;
+                        }
+                    };
+
+                    let candidate = match select_candidate(candidates, cli.select, &mut |_, candidate| {
+                        is_valid_chat_candidate(candidate, command_mode)
+                    }) {
+                        Some(candidate) => candidate,
+                        None => {
+                            error!("No candidates in response");
+                            println!("Received an empty response from Gemini. Please try again.");
+                            break;
+                        }
+                    };
+                    if show_thoughts && !stream {
+                        print_thoughts(&candidate);
                     }
-                };
-                
-                let candidate = match candidates.get(0) {
-                    Some(candidate) => candidate,
-                    None => {
-                        error!("No candidates in response");
-                        println!("Received an empty response from Gemini. Please try again.");
-                        continue;
+
+                    let mut text_content = String::new();
+                    let mut function_calls = Vec::new();
+                    for (index, part) in candidate.content.parts.iter().enumerate() {
+                        match part {
+                            Part::Text { text, .. } => text_content.push_str(&cite_grounded_text(&candidate, index, text)),
+                            Part::FunctionCall { function_call, .. } => function_calls.push(function_call.clone()),
+                            _ => {}
+                        }
                     }
-                };
-                
-                // Find the text part in the response
-                let mut text_content = String::new();
-                for part in &candidate.content.parts {
-                    if let Part::Text { text } = part {
-                        text_content.push_str(&text);
+
+                    if text_content.is_empty() && function_calls.is_empty() {
+                        error!("No text content in response");
+                        println!("Received an empty response from Gemini. Please try again.");
                         break;
                     }
-                }
 
-                if text_content.is_empty() {
-                    error!("No text content in response");
-                    println!("Received an empty response from Gemini. Please try again.");
-                    continue;
-                }
-                
-                debug!("Received text content: {}", text_content);
-                
-                // Extract JSON from potential markdown code blocks
-                let json_content = extract_json_from_markdown(&text_content);
-                debug!("Extracted JSON content: {}", json_content);
-                
-                // Parse the response with our improved parser
-                let (gemini_response, is_json) = match parse_gemini_response(&text_content) {
-                    Ok(response) => response,
-                    Err(e) => {
-                        error!("Failed to parse response: {}", e);
-                        println!("Failed to parse response from Gemini. The response may not be in the expected format.");
-                        println!("Response: {}", text_content);
-                        
-                        // If it doesn't look like JSON at all, just display it as a regular message
-                        if !text_content.contains("{") && !text_content.contains("}") {
+                    debug!("Received text content: {}", text_content);
+
+                    // Record the exchange so follow-up queries keep their context
+                    if pending_query.take().is_some() {
+                        history.push_user(&current_query, &std::mem::take(&mut pending_attachments));
+                    }
+
+                    if !function_calls.is_empty() {
+                        info!("Received {} function calls (round {})", function_calls.len(), round);
+                        history.push_model_content(candidate.content);
+                        if !stream && !text_content.is_empty() {
                             println!("\n{}", text_content);
                         }
-                        
+
+                        let responses = execute_function_calls(&function_calls).await;
+                        history.push_function_responses(responses);
+
+                        if round == MAX_FUNCTION_CALL_ROUNDS {
+                            warn!("Stopped after {} rounds of function calls", round);
+                            println!("\nStopped after {} rounds of commands. Send another query to continue.", round);
+                        }
                         continue;
                     }
-                };
-                
-                // Only process commands if we actually got a JSON response
-                let mut command_failures = 0;
-                let total_commands = gemini_response.commands.len();
-                
-                feedback_messages.clear();
-                
-                if is_json && !gemini_response.commands.is_empty() {
-                    println!("\nExecuting commands:");
-                    
-                    for cmd in gemini_response.commands {
-                        let feedback = match cmd {
-                            GeminiCommand::CreateFolder { path } => {
-                                info!("Creating folder: {}", path);
-                                println!("Creating folder: {}", path);
-                                let result = fs::create_dir_all(&path);
-                                let feedback = CommandFeedback {
-                                    command_type: "create_folder".to_string(),
-                                    command_details: format!("path: {}", path),
-                                    status: if result.is_ok() {
-                                        CommandStatus::Success
-                                    } else {
-                                        command_failures += 1;
-                                        error!("Failed to create folder: {}", path);
-                                        println!("Failed to create folder: {}", path);
-                                        CommandStatus::Failure
-                                    },
-                                    message: result
-                                        .map(|_| "Folder created".to_string())
-                                        .unwrap_or_else(|e| e.to_string()),
-                                };
-                                
-                                // Print success/failure message
-                                if feedback.status == CommandStatus::Success {
-                                    println!("✅ Folder created successfully");
-                                } else {
-                                    println!("❌ Error: {}", feedback.message);
-                                }
-                                
-                                feedback
-                            }
-                            GeminiCommand::CreateFile { path, content } => {
-                                info!("Creating file: {}", path);
-                                println!("Creating file: {}", path);
-                                let result = fs::write(&path, &content);
-                                let feedback = CommandFeedback {
-                                    command_type: "create_file".to_string(),
-                                    command_details: format!("path: {}", path),
-                                    status: if result.is_ok() {
-                                        CommandStatus::Success
-                                    } else {
-                                        command_failures += 1;
-                                        error!("Failed to create file: {}", path);
-                                        println!("Failed to create file: {}", path);
-                                        CommandStatus::Failure
-                                    },
-                                    message: result
-                                        .map(|_| "File created".to_string())
-                                        .unwrap_or_else(|e| e.to_string()),
-                                };
-                                
-                                // Print success/failure message
-                                if feedback.status == CommandStatus::Success {
-                                    println!("✅ File created successfully");
-                                } else {
-                                    println!("❌ Error: {}", feedback.message);
-                                }
-                                
-                                feedback
-                            }
-                            GeminiCommand::WriteCodeToFile { path, code } => {
-                                info!("Writing code to file: {}", path);
-                                println!("Writing code to file: {}", path);
-                                let result = fs::write(&path, &code);
-                                let feedback = CommandFeedback {
-                                    command_type: "write_code_to_file".to_string(),
-                                    command_details: format!("path: {}", path),
-                                    status: if result.is_ok() {
-                                        CommandStatus::Success
-                                    } else {
-                                        command_failures += 1;
-                                        error!("Failed to write code to file: {}", path);
-                                        println!("Failed to write code to file: {}", path);
-                                        CommandStatus::Failure
-                                    },
-                                    message: result
-                                        .map(|_| "Code written to file".to_string())
-                                        .unwrap_or_else(|e| e.to_string()),
-                                };
-                                
-                                // Print success/failure message
-                                if feedback.status == CommandStatus::Success {
-                                    println!("✅ Code written to file successfully");
-                                } else {
-                                    println!("❌ Error: {}", feedback.message);
-                                }
-                                
-                                feedback
-                            }
-                            GeminiCommand::ExecuteCommand { command, args } => {
-                                let cmd_str = format!("{} {}", command, args.join(" "));
-                                info!("Executing: {}", cmd_str);
-                                println!("Executing: {}", cmd_str);
-                                let result = execute_command(&cmd_str).await;
-                                let feedback = CommandFeedback {
-                                    command_type: "execute_command".to_string(),
-                                    command_details: cmd_str.clone(),
-                                    status: if result.is_ok() {
-                                        CommandStatus::Success
-                                    } else {
-                                        command_failures += 1;
-                                        error!("Failed to execute command: {}", cmd_str);
-                                        println!("Failed to execute command: {}", cmd_str);
-                                        CommandStatus::Failure
-                                    },
-                                    message: result.unwrap_or_else(|e| e.to_string()),
-                                };
-                                
-                                // Print success/failure message
-                                if feedback.status == CommandStatus::Success {
-                                    println!("✅ Command executed successfully");
-                                } else {
-                                    println!("❌ Error: {}", feedback.message);
-                                }
-                                
-                                feedback
-                            }
-                        };
-                        feedback_messages.push(feedback);
-                    }
-                    
-                    // Summarize command execution results
-                    if total_commands > 0 {
-                        if command_failures == 0 {
-                            println!("\n✅ All commands executed successfully");
-                        } else if command_failures == total_commands {
-                            println!("\n❌ All commands failed to execute");
-                        } else {
-                            println!("\n⚠️ {}/{} commands failed to execute", command_failures, total_commands);
+
+                    // The whole content is kept so its thought signatures go back to the model
+                    history.push_model_content(candidate.content.clone());
+
+                    // A cut-off reply may end in the middle of a command, so it is shown instead of run
+                    if is_truncated(&candidate) {
+                        warn!("Response was cut off at the output token limit, not running its commands");
+                        if !stream {
+                            println!("\n{}", text_content);
                         }
+                        println!("\nThe response was cut off at the output token limit, so its commands were not run. Raise --max-output-tokens or ask for a smaller step.");
+                        break;
                     }
-                }
-                
-                info!("User message: {}", gemini_response.user_message);
-                println!("\n{}", gemini_response.user_message);
-                
-                // Process feedback for next interaction
-                if !feedback_messages.is_empty() {
-                    match format_feedback(feedback_messages.clone()) {
-                        Ok(formatted_feedback) => {
-                            feedback_string = formatted_feedback;
-                            debug!("Updated feedback for next interaction: {}", feedback_string);
-                        },
+
+                    // Parse the response with our improved parser
+                    let (gemini_response, is_json) = match parse_gemini_response(&text_content) {
+                        Ok(response) => response,
                         Err(e) => {
-                            warn!("Failed to format feedback: {}", e);
-                            println!("Warning: Failed to format command feedback for next interaction");
-                            // Keep the previous feedback string if formatting fails
+                            error!("Failed to parse response: {}", e);
+                            println!("Failed to parse response from Gemini. The response may not be in the expected format.");
+                            println!("Response: {}", text_content);
+                            break;
+                        }
+                    };
+
+                    // Only process commands if we actually got a JSON response
+                    if is_json && !gemini_response.commands.is_empty() {
+                        let feedback_messages = execute_chat_commands(gemini_response.commands).await;
+
+                        // Process feedback for next interaction
+                        match format_feedback(feedback_messages) {
+                            Ok(formatted_feedback) => {
+                                debug!("Updated feedback for next interaction: {}", formatted_feedback);
+                                history.push_feedback(&formatted_feedback);
+                            },
+                            Err(e) => {
+                                warn!("Failed to format feedback: {}", e);
+                                println!("Warning: Failed to format command feedback for next interaction");
+                            }
                         }
                     }
+
+                    info!("User message: {}", gemini_response.user_message);
+                    // A streamed plain-text reply is already on screen, while a streamed
+                    // JSON reply still needs its message pulled out
+                    if !stream || is_json {
+                        println!("\n{}", gemini_response.user_message);
+                    }
+                    print_sources(&candidate);
+                    break;
                 }
             }
+
+            print_usage_report(&usage, client.model(), prices.as_ref());
         }
         Commands::Execute { query } => {
             info!("User Query for Code Execution: '{}'", query);
+
+            if stream {
+                println!("\n--- Gemini Response ---");
+            }
             
-            let gemini_response = execute_with_gemini(query, &api_key)
+            let gemini_response = execute_with_gemini(client, query, &attachments, system_prompt.as_deref(), cli.grounding, stream_handler(stream, &mut stream_printer))
                 .await
-                .map_err(|e| AppError::ApiError(format!("Error communicating with Gemini API: {}", e)))?;
+                .map_err(communication_error)?;
+            usage.record(&gemini_response);
             
-            let candidates = gemini_response.candidates.ok_or_else(|| {
-                if let Some(prompt_feedback) = gemini_response.prompt_feedback {
-                    if let Some(block_reason) = prompt_feedback.block_reason {
-                        error!("Request was blocked: {}", block_reason);
-                        AppError::ResponseError(format!("Request was blocked: {}", block_reason))
-                    } else {
-                        error!("No candidates received from Gemini API");
-                        AppError::ResponseError("No candidates received from Gemini API".to_string())
-                    }
-                } else {
-                    error!("No candidates received from Gemini API");
-                    AppError::ResponseError("No candidates received from Gemini API".to_string())
-                }
-            })?;
+            let candidates = response_candidates(gemini_response)?;
             
-            let candidate = candidates.get(0).ok_or_else(|| {
+            let candidate = select_candidate(candidates, cli.select, &mut |_, candidate| {
+                is_valid_execute_candidate(candidate)
+            })
+            .ok_or_else(|| {
                 error!("No candidates in response");
                 AppError::ResponseError("No candidates in response".to_string())
             })?;
             
-            println!("\n--- Gemini Response ---");
+            if !stream {
+                println!("\n--- Gemini Response ---");
+                if show_thoughts {
+                    print_thoughts(&candidate);
+                }
+            }
 
             // Process each part of the response
-            for part in &candidate.content.parts {
+            for (index, part) in candidate.content.parts.iter().enumerate() {
                 match part {
-                    Part::Text { text } => {
-                        if !text.is_empty() {
-                            info!("{}", text);
+                    Part::Text { text, .. } => {
+                        // Streamed text is already on screen, without the source markers
+                        if !stream && !text.is_empty() {
+                            println!("{}", cite_grounded_text(&candidate, index, text));
                         }
                     }
+                    Part::Thought { text, .. } => {
+                        debug!("Thought: {}", text);
+                    }
                     Part::ExecutableCode { executable_code } => {
                         info!(
                             "\n--- Generated Code ({}): ---",
@@ -1606,45 +8486,57 @@ async fn main() -> Result<(), AppError> {
                         info!("{}", code_execution_result.output);
                         info!("--- End of Execution Result ---\n");
                     }
+                    other => {
+                        debug!("Skipping unsupported part in response: {:?}", other);
+                    }
                 }
             }
+            print_sources(&candidate);
+            if is_truncated(&candidate) {
+                warn!("Response was cut off at the output token limit");
+                println!("\nThe response was cut off at the output token limit and is incomplete. Raise --max-output-tokens or ask for less.");
+            }
+
+            print_usage_report(&usage, client.model(), prices.as_ref());
         }
         Commands::CreateCodebase {
             description,
             output_dir,
+            max_continuations,
         } => {
             info!("Creating codebase with description: '{}'", description);
             info!("Output directory: '{}'", output_dir);
 
-            let gemini_response = create_codebase_with_gemini(description, output_dir, &api_key)
+            let mut attachments = attachments.clone();
+            if let Some(context) = project_context(retrieval, description, cli.index_top_k).await {
+                attachments.push(Part::text(context));
+            }
+
+            let gemini_response = create_codebase_with_gemini(client, description, output_dir, &attachments, system_prompt.as_deref(), *max_continuations, stream_handler(stream, &mut stream_printer))
                 .await
-                .map_err(|e| AppError::ApiError(format!("Error communicating with Gemini API: {}", e)))?;
+                .map_err(communication_error)?;
+            usage.record(&gemini_response);
             
-            let candidates = gemini_response.candidates.ok_or_else(|| {
-                if let Some(prompt_feedback) = gemini_response.prompt_feedback {
-                    if let Some(block_reason) = prompt_feedback.block_reason {
-                        error!("Request was blocked: {}", block_reason);
-                        AppError::ResponseError(format!("Request was blocked: {}", block_reason))
-                    } else {
-                        error!("No candidates received from Gemini API");
-                        AppError::ResponseError("No candidates received from Gemini API".to_string())
-                    }
-                } else {
-                    error!("No candidates received from Gemini API");
-                    AppError::ResponseError("No candidates received from Gemini API".to_string())
-                }
-            })?;
+            let candidates = response_candidates(gemini_response)?;
             
-            let candidate = candidates.get(0).ok_or_else(|| {
+            let verify_command = cli.verify_command.as_deref().filter(|_| cli.select == SelectionStrategy::Verify);
+            let candidate = select_candidate(candidates, cli.select, &mut |index, candidate| {
+                is_valid_codebase_candidate(candidate)
+                    && verify_command.is_none_or(|command| verify_codebase_candidate(candidate, index, command))
+            })
+            .ok_or_else(|| {
                 error!("No candidates in response");
                 AppError::ResponseError("No candidates in response".to_string())
             })?;
+            if show_thoughts && !stream {
+                print_thoughts(&candidate);
+            }
             
             // Find the text part in the response
             let mut text_content = String::new();
             for part in &candidate.content.parts {
-                if let Part::Text { text } = part {
-                    text_content.push_str(&text);
+                if let Part::Text { text, .. } = part {
+                    text_content.push_str(text);
                 }
             }
 
@@ -1665,7 +8557,1213 @@ async fn main() -> Result<(), AppError> {
             for file in created_files {
                 info!("- {}", file);
             }
+
+            print_usage_report(&usage, client.model(), prices.as_ref());
+        }
+        Commands::Models { action } => {
+            let client = require_gemini(gemini.as_ref(), "The models subcommand")?;
+            match action {
+                ModelsAction::List => {
+                    let mut models = client.list_models().await?;
+                    models.sort_by(|a, b| a.name.cmp(&b.name));
+                    for model in &models {
+                        println!("{}", model.summary());
+                    }
+                    println!("{} models available.", models.len());
+                }
+                ModelsAction::Show { name } => {
+                    let model = client
+                        .get_model(name)
+                        .await?
+                        .ok_or_else(|| AppError::ConfigError(format!("Model {} was not found", name)))?;
+                    println!("{}", model.details());
+                }
+            }
         }
+        Commands::Cache { action } => {
+            let client = require_gemini(gemini.as_ref(), "The cache subcommand")?;
+            match action {
+                CacheAction::Create {
+                    path,
+                    ttl,
+                    display_name,
+                    target,
+                    command_mode,
+                } => {
+                    let (system_instruction, tools) = match target {
+                        CacheTarget::Chat => chat_instructions(
+                            grounded_command_mode(*command_mode, cli.grounding),
+                            system_prompt.as_deref(),
+                            cli.grounding,
+                        ),
+                        CacheTarget::Execute => execute_instructions(system_prompt.as_deref(), cli.grounding),
+                        CacheTarget::CreateCodebase => codebase_instructions(system_prompt.as_deref()),
+                    };
+                    let request = CreateCachedContentRequest {
+                        model: client.model_resource(),
+                        display_name: display_name.clone(),
+                        contents: vec![load_project_files(path)?],
+                        system_instruction,
+                        tools,
+                        ttl: format!("{}s", ttl),
+                    };
+
+                    let cache = client.create_cached_content(&request).await?;
+                    println!("Created cached content {}", cache.summary());
+                    println!("Use it with: --cached-content {}", cache.name);
+                }
+                CacheAction::List => {
+                    let caches = client.list_cached_contents().await?;
+                    if caches.is_empty() {
+                        println!("No cached contents.");
+                    }
+                    for cache in caches {
+                        println!("{}", cache.summary());
+                    }
+                }
+                CacheAction::Delete { name } => {
+                    client.delete_cached_content(name).await?;
+                    println!("Deleted {}", cache_resource_name(name));
+                }
+                CacheAction::Extend { name, ttl } => {
+                    let cache = client.update_cached_content_ttl(name, Duration::from_secs(*ttl)).await?;
+                    println!("Updated {}", cache.summary());
+                }
+            }
+        }
+        Commands::Index {
+            path,
+            output,
+            embedding_model,
+            dimensions,
+            chunk_lines,
+        } => {
+            let client = require_gemini(gemini.as_ref(), "The index subcommand")?;
+            if *chunk_lines == 0 || *dimensions == 0 {
+                return Err(AppError::ConfigError("--chunk-lines and --dimensions must be at least 1".to_string()));
+            }
+
+            let files = read_project_files(path)?;
+            if files.is_empty() {
+                return Err(AppError::ConfigError("No readable text files found to index".to_string()));
+            }
+            let previous = if Path::new(output).exists() {
+                CodeIndex::load(output)
+                    .inspect_err(|e| warn!("Rebuilding the index from scratch: {}", e))
+                    .ok()
+            } else {
+                None
+            };
+
+            let embedder = GeminiEmbedder::new(client, embedding_model, Some(*dimensions));
+            let (index, embedded) = build_index(&embedder, &files, *chunk_lines, previous.as_ref()).await?;
+            index.save(output)?;
+            println!(
+                "Indexed {} chunks from {} files into {} ({} embedded, {} unchanged)",
+                index.chunks.len(),
+                files.len(),
+                output,
+                embedded,
+                index.chunks.len() - embedded
+            );
+            println!("Use it with: --index {}", output);
+        }
+        Commands::MockServer { .. } => unreachable!("the mock server is started before any client is created"),
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses a response fixture from `tests/fixtures`
+    fn load_fixture(json: &str) -> GeminiApiResponse {
+        serde_json::from_str(json).expect("fixture should deserialize")
+    }
+
+    #[test]
+    fn parses_text_response_with_usage_metadata() {
+        let response = load_fixture(include_str!("../tests/fixtures/text_response.json"));
+
+        let candidates = response.candidates.as_ref().unwrap();
+        assert_eq!(candidates[0].finish_reason.as_deref(), Some("STOP"));
+        assert!(candidates[0].avg_logprobs.unwrap() < 0.0);
+        assert_eq!(candidates[0].content.role.as_deref(), Some("model"));
+        assert_eq!(response.model_version.as_deref(), Some("gemini-2.0-flash"));
+
+        let usage = response.usage_metadata.as_ref().unwrap();
+        assert_eq!(usage.prompt_token_count, Some(412));
+        assert_eq!(usage.candidates_token_count, Some(47));
+        assert_eq!(usage.total_token_count, Some(459));
+
+        let text = candidate_text(&response_candidates(response).unwrap()[0]);
+        let (parsed, is_json) = parse_gemini_response(&text).unwrap();
+        assert!(is_json);
+        assert_eq!(parsed.commands.len(), 1);
+    }
+
+    #[test]
+    fn parses_code_execution_parts_and_citations() {
+        let response = load_fixture(include_str!("../tests/fixtures/code_execution_response.json"));
+
+        let candidate = &response.candidates.as_ref().unwrap()[0];
+        let parts = &candidate.content.parts;
+        assert_eq!(parts.len(), 4);
+        assert!(matches!(&parts[0], Part::Text { .. }));
+        assert!(matches!(
+            &parts[1],
+            Part::ExecutableCode { executable_code } if executable_code.language == "PYTHON"
+        ));
+        assert!(matches!(
+            &parts[2],
+            Part::CodeExecutionResult { code_execution_result }
+                if code_execution_result.outcome == "OUTCOME_OK" && code_execution_result.output == "120\n"
+        ));
+
+        assert_eq!(candidate.safety_ratings.as_ref().unwrap().len(), 4);
+        let sources = &candidate.citation_metadata.as_ref().unwrap().citation_sources;
+        assert_eq!(sources[0].uri.as_deref(), Some("https://github.com/python/cpython"));
+        assert_eq!(sources[0].end_index, Some(160));
+
+        let usage = response.usage_metadata.as_ref().unwrap();
+        assert_eq!(usage.thoughts_token_count, Some(54));
+        assert_eq!(usage.tool_use_prompt_token_count, Some(142));
+    }
+
+    #[test]
+    fn grounds_answers_and_lists_numbered_sources() {
+        let (_, tools) = execute_instructions(None, true);
+        assert_eq!(serde_json::to_value(&tools).unwrap(), json!([{"codeExecution": {}}, {"googleSearch": {}}]));
+        assert_eq!(grounded_command_mode(CommandMode::Functions, true), CommandMode::Json);
+        assert_eq!(grounded_command_mode(CommandMode::Schema, false), CommandMode::Schema);
+
+        let response = load_fixture(include_str!("../tests/fixtures/grounded_response.json"));
+        let candidate = &response.candidates.as_ref().unwrap()[0];
+        let metadata = candidate.grounding_metadata.as_ref().unwrap();
+        assert_eq!(metadata.web_search_queries, ["latest tokio release", "tokio 1.47 changelog"]);
+        assert_eq!(metadata.grounding_supports[1].grounding_chunk_indices, [0, 1]);
+        assert_eq!(
+            cite_grounded_text(candidate, 0, &candidate_text(candidate)),
+            "The latest release of tokio is 1.47.1.[1] It added `tokio::task::coop::poll_proceed` and stabilized `JoinSet::spawn_local_on`.[1][2]"
+        );
+        assert_eq!(cite_grounded_text(candidate, 1, "other part"), "other part");
+
+        // Citations repeating a grounding source are listed once
+        assert_eq!(
+            format_sources(candidate),
+            [
+                "[1] github.com - https://github.com/tokio-rs/tokio/releases",
+                "[2] docs.rs - https://docs.rs/tokio/latest/tokio/",
+                "[3] https://crates.io/crates/tokio",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_blocked_prompt_feedback() {
+        let response = load_fixture(include_str!("../tests/fixtures/blocked_prompt_response.json"));
+
+        assert!(response.candidates.is_none());
+        let feedback = response.prompt_feedback.as_ref().unwrap();
+        assert_eq!(feedback.block_reason.as_deref(), Some("SAFETY"));
+        let ratings = feedback.safety_ratings.as_ref().unwrap();
+        assert_eq!(ratings.iter().filter(|r| r.blocked == Some(true)).count(), 1);
+
+        // Vertex AI adds scores and severities, and may leave out the probability
+        let rating: SafetyRating = serde_json::from_value(json!({
+            "category": "HARM_CATEGORY_HATE_SPEECH",
+            "probabilityScore": 0.03,
+            "severity": "HARM_SEVERITY_NEGLIGIBLE",
+            "severityScore": 0.05
+        }))
+        .unwrap();
+        assert!(rating.probability.is_none());
+        assert_eq!(rating.severity.as_deref(), Some("HARM_SEVERITY_NEGLIGIBLE"));
+        assert_eq!(rating.severity_score, Some(0.05));
+
+        let error = response_candidates(response).unwrap_err();
+        assert!(error.to_string().contains("SAFETY"));
+        assert!(matches!(response_candidates(load_fixture(r#"{"candidates": []}"#)), Err(AppError::ResponseError(_))));
+    }
+
+    #[test]
+    fn accumulates_usage_and_estimates_cost() {
+        let mut usage = TokenUsage::default();
+        usage.record(&load_fixture(include_str!("../tests/fixtures/text_response.json")));
+        usage.record(&load_fixture(include_str!("../tests/fixtures/max_tokens_response.json")));
+
+        assert_eq!(usage.requests, 2);
+        assert_eq!(usage.prompt_tokens, 592);
+        assert_eq!(usage.cached_tokens, 128);
+        assert_eq!(usage.total_tokens, 655);
+
+        let prices: PriceTable = serde_json::from_str(
+            r#"{"gemini-2.0-flash": {"input": 0.1, "output": 0.4, "cached_input": 0.025}}"#,
+        )
+        .unwrap();
+        let price = prices.price_for("models/gemini-2.0-flash-001").unwrap();
+        let expected = (464.0 * 0.1 + 128.0 * 0.025 + 63.0 * 0.4) / 1_000_000.0;
+        assert!((usage.estimate_cost(price) - expected).abs() < 1e-12);
+        assert!(prices.price_for("gemini-1.5-pro").is_none());
+    }
+
+    #[test]
+    fn converts_function_calls_into_commands() {
+        let part: Part = serde_json::from_str(
+            r#"{"functionCall": {"name": "execute_command", "args": {"command": "cargo", "args": ["test"]}}}"#,
+        )
+        .unwrap();
+        let Part::FunctionCall { function_call, .. } = part else {
+            panic!("expected a function call part");
+        };
+
+        let command = GeminiCommand::from_function_call(&function_call).unwrap();
+        assert!(matches!(
+            command,
+            GeminiCommand::ExecuteCommand { command, args } if command == "cargo" && args == ["test"]
+        ));
+
+        let unknown = FunctionCall {
+            id: None,
+            name: "delete_everything".to_string(),
+            args: json!({}),
+        };
+        assert!(GeminiCommand::from_function_call(&unknown).is_err());
+    }
+
+    #[test]
+    fn tolerates_unknown_parts_and_reports_finish_reason() {
+        let response = load_fixture(include_str!("../tests/fixtures/max_tokens_response.json"));
+
+        let candidate = &response.candidates.as_ref().unwrap()[0];
+        assert_eq!(candidate.finish_reason.as_deref(), Some("MAX_TOKENS"));
+        assert!(is_truncated(candidate));
+        assert!(!is_valid_chat_candidate(candidate, CommandMode::Functions));
+        assert!(matches!(&candidate.content.parts[1], Part::Other(value) if value.get("videoMetadata").is_some()));
+        assert_eq!(
+            response.usage_metadata.as_ref().unwrap().cached_content_token_count,
+            Some(128)
+        );
+    }
+
+    #[test]
+    fn derives_gemini_schema_from_command_types() {
+        let schema = GeminiResponse::response_schema();
+        let text = schema.to_string();
+        for keyword in ["$ref", "$defs", "oneOf", "const", "$schema"] {
+            assert!(!text.contains(keyword), "unsupported keyword {} in {}", keyword, text);
+        }
+
+        let variants = schema["properties"]["commands"]["items"]["anyOf"].as_array().unwrap();
+        assert_eq!(variants.len(), 4);
+        assert_eq!(schema["propertyOrdering"], json!(["commands", "user_message"]));
+
+        let declarations = GeminiCommand::function_declarations();
+        let execute = declarations.iter().find(|d| d.name == "execute_command").unwrap();
+        assert_eq!(execute.parameters["required"], json!(["command", "args"]));
+        assert!(execute.parameters["properties"].get("type").is_none());
+        assert!(GeminiCommand::prompt_spec().contains(r#""type": "create_file", "path": "<path>", "content": "<content>""#));
+    }
+
+    #[test]
+    fn merges_generation_settings_from_flags_and_config() {
+        let config: Config = serde_json::from_str(
+            r#"{"generation": {"temperature": 0.2, "top_k": 40, "stop_sequences": ["END"], "thinking_budget": 1024}}"#,
+        )
+        .unwrap();
+        let cli = Cli::try_parse_from([
+            "gemini-codemaker",
+            "execute",
+            "--query",
+            "hi",
+            "--temperature",
+            "0.7",
+            "--seed",
+            "42",
+            "--thinking-budget",
+            "-1",
+        ])
+        .unwrap();
+
+        let settings = cli.generation.or(&config.generation);
+        settings.validate().unwrap();
+        assert_eq!(
+            serde_json::to_value(settings.to_generation_config()).unwrap(),
+            json!({
+                "temperature": 0.7,
+                "topK": 40,
+                "stopSequences": ["END"],
+                "seed": 42,
+                "thinkingConfig": {"thinkingBudget": -1}
+            })
+        );
+
+        let invalid = GenerationSettings { top_p: Some(1.5), ..Default::default() };
+        assert!(matches!(invalid.validate(), Err(AppError::ConfigError(_))));
+        assert!(serde_json::from_str::<Config>(r#"{"generation": {"temprature": 1.0}}"#).is_err());
+    }
+
+    #[test]
+    fn sends_instructions_as_system_instruction() {
+        assert!(system_instruction(None, "").is_none());
+
+        let instruction = system_instruction(Some("Be terse.\n"), "Reply in JSON.").unwrap();
+        assert_eq!(
+            serde_json::to_value(&instruction).unwrap(),
+            json!({"parts": [{"text": "Be terse.\n\nReply in JSON."}]})
+        );
+
+        let mut history = ChatHistory::new(DEFAULT_CHAT_HISTORY_LENGTH);
+        history.push_user("first", &[]);
+        history.push_model_content(Content::text(None, "done"));
+        let contents = history.to_contents("System Information:\nOS: linux", Some(&Content::user("second", &[])));
+        assert_eq!(contents.len(), 3);
+        assert_eq!(contents[0].parts.len(), 2, "context and query are separate parts");
+        assert_eq!(contents[2].role.as_deref(), Some("user"));
+    }
+
+    #[test]
+    fn drops_oldest_exchanges_and_estimates_tokens() {
+        let mut history = ChatHistory::new(DEFAULT_CHAT_HISTORY_LENGTH);
+        history.push_user("first", &[]);
+        history.push_model_content(Content {
+            role: None,
+            parts: vec![Part::FunctionCall {
+                function_call: FunctionCall {
+                    id: None,
+                    name: "create_folder".to_string(),
+                    args: json!({"path": "app"}),
+                },
+                thought_signature: None,
+            }],
+        });
+        history.push_function_responses(vec![Part::FunctionResponse {
+            function_response: FunctionResponse {
+                id: None,
+                name: "create_folder".to_string(),
+                response: json!({"status": "success"}),
+            },
+        }]);
+        history.push_model_content(Content::text(None, "done"));
+        history.push_user("second", &[]);
+        history.push_model_content(Content::text(None, "ok"));
+
+        // The function response belongs to the first exchange and goes with it
+        assert_eq!(history.drop_oldest_exchange(), 4);
+        assert_eq!(history.turns.len(), 2);
+        assert_eq!(history.drop_oldest_exchange(), 0, "the latest exchange is kept");
+
+        let request = GenerateContentRequest {
+            contents: vec![Content::text(Some("user"), "a".repeat(400))],
+            ..Default::default()
+        };
+        let estimate = estimate_tokens(&request);
+        assert!((100..=120).contains(&estimate), "estimate was {}", estimate);
+
+        // Oversized requests get their own error, not an API or function calling one
+        let client = GeminiClient::with_backend(
+            Backend::GeminiApi {
+                root: "http://localhost/v1beta".to_string(),
+                api_key: "key".to_string(),
+            },
+            None,
+        )
+        .with_input_token_limit(100);
+        let error = client.check_input_size(&TokenCount { tokens: estimate, estimated: true }).unwrap_err();
+        assert!(matches!(error, AppError::InputTooLarge(_)));
+        assert!(!is_function_calling_unsupported(&error));
+        assert!(matches!(communication_error(error), AppError::InputTooLarge(_)));
+    }
+
+    #[test]
+    fn selects_first_valid_candidate() {
+        let candidates = || load_fixture(
+            r#"{"candidates": [
+                {"index": 0, "content": {"role": "model", "parts": [{"text": "{\"commands\": [{\"type\": \"delete_all\"}]}"}]}},
+                {"index": 1, "content": {"role": "model", "parts": [{"text": "{\"commands\": [], \"user_message\": \"done\"}"}]}},
+                {"index": 2, "content": {"role": "model", "parts": [{"text": "also fine"}]}}
+            ]}"#,
+        )
+        .candidates
+        .unwrap();
+
+        let first = select_candidate(candidates(), SelectionStrategy::First, &mut |_, _| false).unwrap();
+        assert_eq!(first.index, Some(0));
+
+        let valid = select_candidate(candidates(), SelectionStrategy::Valid, &mut |_, candidate| {
+            is_valid_chat_candidate(candidate, CommandMode::Json)
+        })
+        .unwrap();
+        assert_eq!(valid.index, Some(1));
+
+        let fallback = select_candidate(candidates(), SelectionStrategy::Verify, &mut |_, _| false).unwrap();
+        assert_eq!(fallback.index, Some(0), "falls back to the first candidate");
+    }
+
+    #[test]
+    fn separates_thought_parts_from_text() {
+        let mut response = load_fixture(
+            r#"{"candidates": [{"index": 0, "content": {"role": "model", "parts": [
+                {"text": "Planning the ", "thought": true},
+                {"text": "{\"commands\": [], \"user_message\": \"hi\"}", "thought": false}
+            ]}}]}"#,
+        );
+        response.merge_chunk(load_fixture(
+            r#"{"candidates": [{"index": 0, "content": {"parts": [
+                {"text": "files.", "thought": true, "thoughtSignature": "c2ln"}
+            ]}}]}"#,
+        ));
+
+        let candidate = &response.candidates.as_ref().unwrap()[0];
+        assert!(matches!(&candidate.content.parts[0], Part::Thought { text, .. } if text == "Planning the "));
+        assert!(matches!(&candidate.content.parts[2], Part::Thought { thought_signature: Some(_), .. }));
+        assert_eq!(candidate_text(candidate), r#"{"commands": [], "user_message": "hi"}"#);
+        assert!(is_valid_chat_candidate(candidate, CommandMode::Json));
+
+        // Signatures on text and function calls survive merging and go back unchanged
+        let mut response = load_fixture(r#"{"candidates": [{"content": {"parts": [{"text": "Hel"}]}}]}"#);
+        response.merge_chunk(load_fixture(
+            r#"{"candidates": [{"content": {"parts": [
+                {"text": "lo", "thoughtSignature": "dGV4dA=="},
+                {"functionCall": {"name": "create_folder", "args": {"path": "app"}}, "thoughtSignature": "Y2FsbA=="}
+            ]}}]}"#,
+        ));
+        let parts = &response.candidates.as_ref().unwrap()[0].content.parts;
+        assert_eq!(
+            serde_json::to_value(parts).unwrap(),
+            json!([
+                {"text": "Hello", "thoughtSignature": "dGV4dA=="},
+                {"functionCall": {"name": "create_folder", "args": {"path": "app"}}, "thoughtSignature": "Y2FsbA=="}
+            ])
+        );
+
+        let cli = Cli::try_parse_from(["gemini-codemaker", "chat", "--include-thoughts", "--thinking-budget", "512"]).unwrap();
+        assert_eq!(
+            serde_json::to_value(cli.generation.to_generation_config()).unwrap(),
+            json!({"thinkingConfig": {"thinkingBudget": 512, "includeThoughts": true}})
+        );
+    }
+
+    #[test]
+    fn sniffs_attachments_and_sends_them_inline() {
+        assert_eq!(sniff_mime_type("shot", b"\x89PNG\r\n\x1a\n...").unwrap(), "image/png");
+        assert_eq!(sniff_mime_type("spec.bin", b"%PDF-1.7").unwrap(), "application/pdf");
+        assert_eq!(sniff_mime_type("data.csv", b"a,b\n1,2").unwrap(), "text/csv");
+        assert_eq!(sniff_mime_type("notes", "plain ü".as_bytes()).unwrap(), "text/plain");
+        assert!(matches!(sniff_mime_type("blob", &[0, 159, 146, 150]), Err(AppError::AttachmentError(_))));
+
+        let attachment = Part::InlineData {
+            inline_data: Blob {
+                mime_type: "image/png".to_string(),
+                data: BASE64_STANDARD.encode(b"png"),
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(Content::user("What is wrong here?", &[attachment])).unwrap(),
+            json!({"role": "user", "parts": [
+                {"inlineData": {"mimeType": "image/png", "data": "cG5n"}},
+                {"text": "What is wrong here?"}
+            ]})
+        );
+    }
+
+    #[test]
+    fn uses_cached_content_instead_of_instructions() {
+        assert_eq!(cache_resource_name("abc123"), "cachedContents/abc123");
+        assert_eq!(cache_resource_name("cachedContents/abc123"), "cachedContents/abc123");
+
+        let cache: CachedContent = serde_json::from_value(json!({
+            "name": "cachedContents/abc123",
+            "model": "models/gemini-2.0-flash",
+            "displayName": "project",
+            "expireTime": "2001-02-03T04:05:06.123456Z",
+            "usageMetadata": {"totalTokenCount": 51234}
+        }))
+        .unwrap();
+        assert_eq!(cache.remaining(), Some(Duration::ZERO));
+        assert_eq!(
+            cache.summary(),
+            "cachedContents/abc123 \"project\" [models/gemini-2.0-flash] 51234 tokens, expired at 2001-02-03T04:05:06.123456Z"
+        );
+
+        let client = GeminiClient::new("key".to_string()).with_cached_content(cache.name.clone());
+        let (system_instruction, tools) = chat_instructions(CommandMode::Functions, None, false);
+        let request = GenerateContentRequest {
+            contents: vec![Content::user("hi", &[])],
+            tools,
+            generation_config: None,
+            system_instruction,
+            cached_content: None,
+        };
+        let body = serde_json::to_value(client.apply_cached_content(&request)).unwrap();
+        assert_eq!(
+            body,
+            json!({"contents": [{"role": "user", "parts": [{"text": "hi"}]}], "cachedContent": "cachedContents/abc123"})
+        );
+    }
+
+    #[test]
+    fn checks_model_capabilities_for_each_subcommand() {
+        let model = |name: &str, methods: &[&str]| -> ModelInfo {
+            serde_json::from_value(json!({
+                "name": format!("models/{}", name),
+                "inputTokenLimit": 1048576,
+                "outputTokenLimit": 65536,
+                "supportedGenerationMethods": methods,
+                "thinking": true
+            }))
+            .unwrap()
+        };
+        let command = |args: &[&str]| Cli::try_parse_from([&["gemini-codemaker"], args].concat()).unwrap();
+
+        let flash = model("gemini-2.5-flash", &["generateContent", "countTokens", "createCachedContent"]);
+        let gemma = model("gemma-3-27b-it", &["generateContent", "countTokens"]);
+        let embedding = model("text-embedding-004", &["embedContent"]);
+
+        let execute = command(&["execute", "--query", "x"]);
+        assert!(check_model_capabilities(&flash, &execute.command, &execute.generation).is_ok());
+        // The code execution guess only warns, so the API has the final say
+        assert!(!gemma.likely_supports_code_execution());
+        assert!(check_model_capabilities(&gemma, &execute.command, &execute.generation).is_ok());
+
+        let chat = command(&["chat", "--max-output-tokens", "8192"]);
+        assert!(check_model_capabilities(&gemma, &chat.command, &chat.generation).is_ok());
+        assert!(check_model_capabilities(&embedding, &chat.command, &chat.generation).is_err());
+
+        let too_long = command(&["chat", "--max-output-tokens", "100000"]);
+        assert!(check_model_capabilities(&flash, &too_long.command, &too_long.generation).is_err());
+
+        let cache = command(&["cache", "create", "--path", "src"]);
+        assert!(check_model_capabilities(&flash, &cache.command, &cache.generation).is_ok());
+        assert!(check_model_capabilities(&gemma, &cache.command, &cache.generation).is_err());
+
+        assert!(flash.summary().starts_with("gemini-2.5-flash "));
+        assert!(flash.details().contains("Code execution:       likely (guessed from the model name)"));
+    }
+
+    #[test]
+    fn builds_urls_and_resources_for_each_backend() {
+        assert_eq!(
+            parse_api_endpoint("https://proxy.local/v1beta/models/gemini-2.0-flash:generateContent"),
+            ("https://proxy.local/v1beta".to_string(), Some("gemini-2.0-flash".to_string()))
+        );
+        assert_eq!(parse_api_endpoint("https://proxy.local/v1beta/"), ("https://proxy.local/v1beta".to_string(), None));
+        assert_eq!(vertex_api_root("global"), "https://aiplatform.googleapis.com/v1");
+
+        let mut gemini = GeminiClient::with_backend(
+            Backend::GeminiApi {
+                root: GEMINI_API_BASE_URL.to_string(),
+                api_key: "key".to_string(),
+            },
+            None,
+        );
+        gemini.model = "gemini-2.5-flash".to_string();
+        assert_eq!(
+            gemini.model_url("countTokens"),
+            "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.5-flash:countTokens"
+        );
+        assert_eq!(
+            gemini.resource_url(&cache_resource_name("abc")),
+            "https://generativelanguage.googleapis.com/v1beta/cachedContents/abc"
+        );
+
+        let config: Config = serde_json::from_value(json!({
+            "backend": {"type": "vertex", "project": "demo", "location": "europe-west4"}
+        }))
+        .unwrap();
+        assert!(matches!(config.backend, BackendConfig::Vertex { ref project, .. } if project.as_deref() == Some("demo")));
+
+        let credentials: GoogleCredentials = serde_json::from_value(json!({
+            "type": "authorized_user",
+            "client_id": "id",
+            "client_secret": "secret",
+            "refresh_token": "token",
+            "quota_project_id": "quota"
+        }))
+        .unwrap();
+        assert_eq!(credentials.project(), Some("quota"));
+
+        let mut vertex = GeminiClient::with_backend(
+            Backend::Vertex {
+                root: vertex_api_root("europe-west4"),
+                project: "demo".to_string(),
+                location: "europe-west4".to_string(),
+                tokens: Arc::new(TokenSource::new(credentials)),
+            },
+            None,
+        );
+        vertex.model = "gemini-2.5-flash".to_string();
+        assert_eq!(
+            vertex.model_url("generateContent"),
+            "https://europe-west4-aiplatform.googleapis.com/v1/projects/demo/locations/europe-west4/publishers/google/models/gemini-2.5-flash:generateContent"
+        );
+        assert_eq!(
+            vertex.resource_url(&cache_resource_name("projects/demo/locations/europe-west4/cachedContents/42")),
+            "https://europe-west4-aiplatform.googleapis.com/v1/projects/demo/locations/europe-west4/cachedContents/42"
+        );
+        assert!(!format!("{:?}", vertex).contains("secret"));
+    }
+
+    #[test]
+    fn translates_requests_and_replies_for_chat_completions() {
+        let (system_instruction, tools) = chat_instructions(CommandMode::Functions, None, false);
+        let request = GenerateContentRequest {
+            contents: vec![
+                Content::user("make a folder", &[]),
+                Content {
+                    role: Some("model".to_string()),
+                    parts: vec![Part::FunctionCall {
+                        function_call: FunctionCall {
+                            id: None,
+                            name: "create_folder".to_string(),
+                            args: json!({"path": "demo"}),
+                        },
+                        thought_signature: None,
+                    }],
+                },
+                Content {
+                    role: Some("user".to_string()),
+                    parts: vec![Part::FunctionResponse {
+                        function_response: FunctionResponse {
+                            id: None,
+                            name: "create_folder".to_string(),
+                            response: json!({"status": "success"}),
+                        },
+                    }],
+                },
+            ],
+            tools,
+            system_instruction,
+            ..Default::default()
+        };
+        let generation_config = GenerationConfig {
+            temperature: Some(0.2),
+            max_output_tokens: Some(512),
+            response_schema: Some(json!({
+                "type": "object",
+                "properties": {
+                    "summary": {"type": "string", "nullable": true},
+                    "nullable": {"type": "boolean"}
+                },
+                "propertyOrdering": ["summary", "nullable"]
+            })),
+            ..Default::default()
+        };
+
+        let body = chat_completion_request("qwen2.5-coder", &request, &generation_config, false);
+        // Gemini's schema dialect is translated to plain JSON Schema
+        assert_eq!(
+            body["response_format"]["json_schema"]["schema"],
+            json!({
+                "type": "object",
+                "properties": {
+                    "summary": {"type": ["string", "null"]},
+                    "nullable": {"type": "boolean"}
+                }
+            })
+        );
+        let parameters = &body["tools"][0]["function"]["parameters"];
+        assert_eq!(parameters["properties"]["path"]["type"], "string");
+        assert!(parameters.get("propertyOrdering").is_none());
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages[0]["role"], "system");
+        assert_eq!(messages[1], json!({"role": "user", "content": "make a folder"}));
+        assert_eq!(messages[2]["tool_calls"][0]["id"], "call_1_0");
+        assert_eq!(messages[2]["tool_calls"][0]["function"]["arguments"], r#"{"path":"demo"}"#);
+        assert_eq!(messages[3]["role"], "tool");
+        assert_eq!(messages[3]["tool_call_id"], "call_1_0");
+        assert_eq!(body["tools"][0]["type"], "function");
+        assert_eq!(body["max_tokens"], 512);
+        assert!(body.get("stream").is_none());
+
+        let completion: ChatCompletion = serde_json::from_value(json!({
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "content": null,
+                    "reasoning_content": "Need a folder.",
+                    "tool_calls": [{"id": "abc", "type": "function", "function": {"name": "create_folder", "arguments": "{\"path\": \"x\"}"}}]
+                },
+                "finish_reason": "tool_calls"
+            }],
+            "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15}
+        }))
+        .unwrap();
+        let response = completion.into_gemini_response();
+        let candidate = &response.candidates.as_ref().unwrap()[0];
+        assert_eq!(candidate.finish_reason.as_deref(), Some("STOP"));
+        assert!(matches!(&candidate.content.parts[0], Part::Thought { .. }));
+        assert!(matches!(
+            &candidate.content.parts[1],
+            Part::FunctionCall { function_call, .. } if function_call.id.as_deref() == Some("abc") && function_call.args == json!({"path": "x"})
+        ));
+        assert_eq!(response.usage_metadata.unwrap().total_token_count, Some(15));
+
+        let mut buffer = ToolCallBuffer::default();
+        for fragment in [
+            json!([{"index": 0, "id": "abc", "function": {"name": "create_folder", "arguments": "{\"pa"}}]),
+            json!([{"index": 0, "function": {"arguments": "th\": \"y\"}"}}]),
+        ] {
+            buffer.extend(0, serde_json::from_value(fragment).unwrap());
+        }
+        let parts = buffer.into_parts();
+        assert!(matches!(&parts[0].1, Part::FunctionCall { function_call, .. } if function_call.args == json!({"path": "y"})));
+    }
+
+    /// Backend answering every request with the same response fixture
+    struct FixtureBackend(&'static str);
+
+    #[async_trait::async_trait(?Send)]
+    impl LlmBackend for FixtureBackend {
+        fn model(&self) -> &str {
+            "gemini-2.5-flash"
+        }
+
+        fn input_token_limit(&self) -> u32 {
+            DEFAULT_INPUT_TOKEN_LIMIT
+        }
+
+        async fn count_tokens(&self, _request: &GenerateContentRequest) -> TokenCount {
+            TokenCount { tokens: 412, estimated: false }
+        }
+
+        async fn send(
+            &self,
+            _request: &GenerateContentRequest,
+            _on_part: Option<&mut dyn for<'p> FnMut(&'p Part)>,
+        ) -> Result<GeminiApiResponse, AppError> {
+            Ok(load_fixture(self.0))
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_chat_requests_recorded_in_another_directory() {
+        let dir = env::temp_dir().join(format!("gemini-codemaker-chat-cassette-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let system_info = |cwd: &str| format!("OS: {}\nArch: {}\nDir: {:?}", env::consts::OS, env::consts::ARCH, Path::new(cwd));
+        let query = Content::user("make a hello folder", &[]);
+
+        let fixture = FixtureBackend(include_str!("../tests/fixtures/text_response.json"));
+        let recorder = Recorder::new(&fixture, &dir, Vec::new()).unwrap();
+        let mut history = ChatHistory::new(DEFAULT_CHAT_HISTORY_LENGTH);
+        chat_with_gemini(&recorder, &mut history, Some(&query), &system_info("/home/dev/project"), CommandMode::Functions, None, false, None)
+            .await
+            .unwrap();
+
+        // The strict replay on another checkout still finds the recorded reply
+        let replayer = Replayer::load(&dir, Vec::new()).unwrap();
+        let mut history = ChatHistory::new(DEFAULT_CHAT_HISTORY_LENGTH);
+        let response = chat_with_gemini(&replayer, &mut history, Some(&query), &system_info("/builds/ci/checkout"), CommandMode::Functions, None, false, None)
+            .await
+            .unwrap();
+        assert_eq!(response.usage_metadata.unwrap().total_token_count, Some(459));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn records_and_replays_interactions() {
+        let dir = env::temp_dir().join(format!("gemini-codemaker-cassette-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let secrets = vec!["sk-test-secret".to_string()];
+        let request = GenerateContentRequest {
+            contents: vec![Content::user("make a hello folder, key sk-test-secret", &[])],
+            ..Default::default()
+        };
+
+        let fixture = FixtureBackend(include_str!("../tests/fixtures/text_response.json"));
+        let recorder = Recorder::new(&fixture, &dir, secrets.clone()).unwrap();
+        assert_eq!(recorder.count_tokens(&request).await.tokens, 412);
+        recorder.send(&request, None).await.unwrap();
+
+        let files = cassette_files(&dir).unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(files[1].file_name().unwrap().to_str().unwrap().starts_with("0002-generate_content-"));
+        let recorded = fs::read_to_string(&files[1]).unwrap();
+        assert!(!recorded.contains("sk-test-secret"));
+        assert!(recorded.contains(REDACTED));
+
+        // A request that drifted from the recording fails even though its reply is unplayed
+        let other = GenerateContentRequest {
+            contents: vec![Content::user("something else", &[])],
+            ..Default::default()
+        };
+        let strict = Replayer::load(&dir, secrets.clone()).unwrap();
+        let error = strict.send(&other, None).await.unwrap_err().to_string();
+        let other_hash = request_hash("generate_content", "gemini-2.5-flash", &serde_json::to_string(&other).unwrap());
+        assert!(error.contains(&other_hash) && error.contains(&dir.display().to_string()), "{}", error);
+        let lenient = Replayer::load(&dir, secrets.clone()).unwrap().with_lenient_matching(true);
+        assert!(lenient.send(&other, None).await.is_ok());
+
+        let replayer = Replayer::load(&dir, secrets).unwrap();
+        assert_eq!(replayer.model(), "gemini-2.5-flash");
+        assert_eq!(replayer.count_tokens(&request).await, TokenCount { tokens: 412, estimated: false });
+
+        let mut streamed = Vec::new();
+        let mut on_part = |part: &Part| streamed.push(part.clone());
+        let response = replayer.send(&request, Some(&mut on_part)).await.unwrap();
+        assert_eq!(streamed.len(), 1);
+        assert_eq!(response.usage_metadata.as_ref().unwrap().total_token_count, Some(459));
+        let text = candidate_text(&response_candidates(response).unwrap()[0]);
+        let (parsed, _) = parse_gemini_response(&text).unwrap();
+        assert_eq!(parsed.commands.len(), 1);
+
+        // Every reply has been played and none matches, so a new request fails
+        assert!(replayer.send(&other, None).await.is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Starts a mock server for the scenario and returns a client pointed at it
+    async fn mock_client(scenario: MockScenario) -> GeminiClient {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(MockServer::new(scenario).serve(listener));
+        GeminiClient::with_backend(
+            Backend::GeminiApi {
+                root: format!("http://{}/v1beta", address),
+                api_key: "key".to_string(),
+            },
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn serves_scripted_scenarios_from_the_mock_server() {
+        let scenario: MockScenario = serde_json::from_str(include_str!("../tests/fixtures/mock_scenario.json")).unwrap();
+        let yaml = MockScenario::load("tests/fixtures/mock_scenario.yaml").unwrap();
+        assert_eq!(format!("{:?}", yaml.steps), format!("{:?}", scenario.steps));
+
+        let client = mock_client(scenario).await.with_retry_policy(RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+            jitter: false,
+            read_timeout: None,
+        });
+        let request = GenerateContentRequest {
+            contents: vec![Content::user("make a hello folder", &[])],
+            ..Default::default()
+        };
+
+        assert!(!client.count_tokens(&request).await.estimated);
+        assert!(client.get_model(&client.model).await.unwrap().unwrap().supports("generateContent"));
+
+        // Without declared functions the commands come back as a JSON block
+        let text = candidate_text(&response_candidates(client.send(&request, None).await.unwrap()).unwrap()[0]);
+        let (parsed, is_json) = parse_gemini_response(&text).unwrap();
+        assert!(is_json);
+        assert_eq!(parsed.commands.len(), 1);
+
+        // The 429 is retried and answered by the truncated step
+        let mut streamed = String::new();
+        let mut on_part = |part: &Part| {
+            if let Part::Text { text, .. } = part {
+                streamed.push_str(text);
+            }
+        };
+        let response = client.send(&request, Some(&mut on_part)).await.unwrap();
+        let candidate = &response.candidates.as_ref().unwrap()[0];
+        assert_eq!(candidate.finish_reason.as_deref(), Some("MAX_TOKENS"));
+        assert_eq!(streamed.chars().count(), 30);
+
+        let blocked = client.send(&request, None).await.unwrap();
+        assert!(matches!(response_candidates(blocked), Err(AppError::ResponseError(_))));
+        assert!(client.send(&request, None).await.is_err());
+
+        // Declared functions turn the commands into function calls
+        let step: MockStep = serde_json::from_value(json!({
+            "type": "reply",
+            "commands": [{"type": "create_folder", "path": "hello"}]
+        }))
+        .unwrap();
+        let (_, tools) = chat_instructions(CommandMode::Functions, None, false);
+        let request = serde_json::to_value(GenerateContentRequest { tools, ..request }).unwrap();
+        let response = mock_step_response(&step, &request, "gemini-2.5-flash", false);
+        let response: GeminiApiResponse = serde_json::from_value(response.chunks[0].clone()).unwrap();
+        let parts = &response.candidates.unwrap()[0].content.parts;
+        assert!(matches!(&parts[0], Part::FunctionCall { function_call, .. } if function_call.name == "create_folder"));
+    }
+
+    #[tokio::test]
+    async fn applies_http_settings_and_times_out_silent_servers() {
+        let config: Config = serde_json::from_value(json!({
+            "http": {"connect_timeout_secs": 5, "read_timeout_secs": 1, "proxy": "http://proxy.corp:3128", "no_proxy": "localhost,127.0.0.1", "user_agent": "test-agent"}
+        }))
+        .unwrap();
+        assert_eq!(config.http.read_timeout(), Duration::from_secs(1));
+        assert!(config.http.client().is_ok());
+
+        let invalid = |http: serde_json::Value| serde_json::from_value::<HttpConfig>(http).unwrap().client();
+        assert!(matches!(invalid(json!({"timeout_secs": 0})), Err(AppError::ConfigError(_))));
+        assert!(matches!(invalid(json!({"ca_bundle": "/nonexistent/ca.pem"})), Err(AppError::ConfigError(_))));
+        assert!(serde_json::from_value::<HttpConfig>(json!({"proxy_url": "http://proxy"})).is_err());
+
+        // A server that accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1beta/models", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                connections.push(socket);
+            }
+        });
+
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+            jitter: false,
+            read_timeout: Some(Duration::from_millis(50)),
+        };
+        let http = Client::new();
+        let result = policy.send(|| async { Ok(http.get(&url)) }).await;
+        assert!(matches!(result, Err(AppError::TimeoutError(message)) if message.contains("2 attempt")));
+    }
+
+    #[tokio::test]
+    async fn retries_model_lookups_and_reports_missing_models() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // A server that is briefly unavailable and then does not know the model
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for status in ["503 Service Unavailable", "404 Not Found"] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0; 4096];
+                let _ = socket.read(&mut request).await;
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{{}}", status);
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let client = GeminiClient::with_backend(
+            Backend::GeminiApi {
+                root: format!("http://{}/v1beta", address),
+                api_key: "key".to_string(),
+            },
+            None,
+        )
+        .with_retry_policy(RetryPolicy {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+            jitter: false,
+            read_timeout: None,
+        });
+        assert!(client.get_model("gemini-missing").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn fails_on_long_server_delays_and_retries_only_transient_transport_errors() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(reqwest::header::RETRY_AFTER, "7".parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(7)));
+        headers.insert(reqwest::header::RETRY_AFTER, "Wed, 21 Oct 2026 07:28:00 GMT".parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), None);
+
+        // Waiting less than the server asked would only burn an attempt
+        let scenario: MockScenario = serde_json::from_value(json!({"steps": [
+            {"type": "error", "status": 429, "retry_after": 120},
+            {"type": "reply", "text": "never reached"}
+        ]}))
+        .unwrap();
+        let client = mock_client(scenario).await.with_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_secs(60),
+            jitter: false,
+            read_timeout: None,
+        });
+        let request = GenerateContentRequest {
+            contents: vec![Content::user("hello", &[])],
+            ..Default::default()
+        };
+        let error = client.send(&request, None).await.unwrap_err().to_string();
+        assert!(error.contains("retry in 120s"), "{}", error);
+
+        assert!(is_connection_reset(&std::io::Error::from(std::io::ErrorKind::ConnectionReset)));
+        assert!(!is_connection_reset(&std::io::Error::from(std::io::ErrorKind::PermissionDenied)));
+        let refused = Client::new().get("http://127.0.0.1:1/").send().await.unwrap_err();
+        assert!(is_retryable_transport_error(&refused));
+    }
+
+    #[tokio::test]
+    async fn limits_requests_and_tokens_per_model_across_processes() {
+        let limit = ModelRateLimit {
+            requests_per_minute: Some(60),
+            tokens_per_minute: Some(1000),
+        };
+        let mut buckets = ModelBuckets::full(&limit, 0.0);
+        assert_eq!(buckets.take(&limit, 900, 0.0), None);
+        // 500 more tokens need 400 refilled tokens, which takes 24 seconds
+        assert_eq!(buckets.take(&limit, 500, 0.0), Some(Duration::from_secs(24)));
+        assert_eq!(buckets.take(&limit, 500, 24.0), None);
+        // Requests larger than the bucket wait for a full bucket instead of forever
+        assert_eq!(buckets.take(&limit, 5000, 84.0), None);
+
+        let dir = env::temp_dir().join(format!("gemini-codemaker-rate-limits-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config: RateLimitConfig = serde_json::from_value(json!({
+            "models": {"*": {"requests_per_minute": 2}, "gemini-2.5-pro": {"tokens_per_minute": 100}},
+            "shared": true,
+            "state_dir": dir,
+        }))
+        .unwrap();
+
+        // Two limiters with the same state directory stand in for two processes
+        let first = RateLimiter::from_config(&config).unwrap().unwrap();
+        let second = RateLimiter::from_config(&config).unwrap().unwrap();
+        assert_eq!(first.try_acquire("gemini-2.5-flash", 10).await.unwrap(), None);
+        assert_eq!(second.try_acquire("gemini-2.5-flash", 10).await.unwrap(), None);
+        let wait = first.try_acquire("gemini-2.5-flash", 10).await.unwrap().unwrap();
+        assert!(wait > Duration::from_secs(25) && wait <= Duration::from_secs(30));
+
+        // The pro model has its own token limit, corrected by the reported usage
+        assert_eq!(second.try_acquire("gemini-2.5-pro", 40).await.unwrap(), None);
+        first.settle("gemini-2.5-pro", 40, 90).await.unwrap();
+        assert!(second.try_acquire("gemini-2.5-pro", 20).await.unwrap().is_some());
+        assert!(!dir.join("rate-limits.lock").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(RateLimiter::from_config(&RateLimitConfig::default()).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rate_limits_every_api_call_by_the_model_in_its_url() {
+        let scenario: MockScenario = serde_json::from_value(json!({"steps": []})).unwrap();
+
+        let config: RateLimitConfig = serde_json::from_value(json!({
+            "models": {"gemini-2.5-flash": {"requests_per_minute": 2}, "gemini-embedding-001": {"requests_per_minute": 1}},
+        }))
+        .unwrap();
+        let limiter = Arc::new(RateLimiter::from_config(&config).unwrap().unwrap());
+        let client = mock_client(scenario).await.with_rate_limiter(limiter.clone());
+        assert_eq!(client.rate_limit_model(&client.model_url("countTokens")), "gemini-2.5-flash");
+        assert_eq!(client.rate_limit_model(&format!("{}/cachedContents", client.api_root())), "gemini-2.5-flash");
+
+        // Model lookups, token counts and embeddings all draw from the buckets
+        client.get_model("gemini-2.5-flash").await.unwrap().unwrap();
+        client.count_tokens(&GenerateContentRequest::default()).await;
+        assert!(limiter.try_acquire("gemini-2.5-flash", 0).await.unwrap().is_some());
+
+        let embedder = GeminiEmbedder::new(&client, DEFAULT_EMBEDDING_MODEL, Some(4));
+        embedder.embed_query("rate limits").await.unwrap();
+        assert!(limiter.try_acquire(DEFAULT_EMBEDDING_MODEL, 0).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn continues_truncated_codebases_at_code_fences() {
+        assert_eq!(fence_boundary("## a.py\n```python\nprint(1)\n```\n## b.py\n```python\npri"), 31);
+        assert_eq!(fence_boundary("## a.py\n```python\nprint(1)\n```\nSome"), 35);
+        assert_eq!(
+            stitch_continuation("```rust\nfn main() {\n    println!(\"hello world\");\n", "```rust\n    println!(\"hello world\");\n}\n```\n", true),
+            "```rust\nfn main() {\n    println!(\"hello world\");\n}\n```\n"
+        );
+
+        // Files whose block never closes are marked instead of silently written half-finished
+        let files = extract_files_from_markdown("```a.py\nprint(1)\n```\n```b.py\nprint(");
+        assert_eq!(files[1].0, "b.py.incomplete");
+
+        let scenario: MockScenario = serde_json::from_value(json!({"steps": [
+            {"type": "reply", "text": "```python:a.py\nprint(1)\n```\n```python:b.py\nprint(2)\npri", "finish_reason": "MAX_TOKENS"},
+            {"type": "reply", "text": "```python:b.py\nprint(2)\nprint(3)\n```\n"}
+        ]}))
+        .unwrap();
+        let client = mock_client(scenario).await;
+
+        let dir = env::temp_dir().join(format!("gemini-codemaker-continuation-{}", std::process::id()));
+        let output_dir = dir.to_str().unwrap();
+        let response = create_codebase_with_gemini(&client, "two scripts", output_dir, &[], None, 3, None)
+            .await
+            .unwrap();
+        let candidate = &response.candidates.as_ref().unwrap()[0];
+        assert_eq!(candidate.finish_reason.as_deref(), Some("STOP"));
+        assert!(response.usage_metadata.unwrap().prompt_token_count.unwrap() > 0);
+
+        let created = create_files_from_response(&candidate_text(candidate), output_dir).unwrap();
+        assert_eq!(created.len(), 2);
+        assert_eq!(fs::read_to_string(dir.join("b.py")).unwrap(), "print(2)\nprint(3)\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Embedder hashing words into buckets, counting the embedded documents
+    struct WordEmbedder {
+        embedded: Cell<usize>,
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl Embedder for WordEmbedder {
+        fn model(&self) -> &str {
+            "words"
+        }
+
+        fn dimensions(&self) -> Option<u32> {
+            Some(64)
+        }
+
+        async fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, AppError> {
+            self.embedded.set(self.embedded.get() + texts.len());
+            let mut vectors = Vec::new();
+            for text in texts {
+                vectors.push(self.embed_query(text).await?);
+            }
+            Ok(vectors)
+        }
+
+        async fn embed_query(&self, text: &str) -> Result<Vec<f32>, AppError> {
+            let embedding = mock_embedding(&json!({"content": {"parts": [{"text": text}]}, "outputDimensionality": 64}));
+            Ok(serde_json::from_value(embedding["values"].clone()).unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn indexes_project_chunks_and_retrieves_relevant_ones() {
+        let long_file: String = (1..=130).map(|line| format!("let value_{} = {};\n", line, line)).collect();
+        let chunks = chunk_source("src/values.rs", &long_file, 60);
+        let ranges: Vec<_> = chunks.iter().map(|chunk| (chunk.start_line, chunk.end_line)).collect();
+        assert_eq!(ranges, [(1, 60), (61, 120), (121, 130)]);
+
+        let dir = env::temp_dir().join(format!("gemini-codemaker-index-{}", std::process::id()));
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join("db.rs"), "fn open_database_pool(url: &str) -> Pool {\n    // postgres connection pool\n}\n").unwrap();
+        fs::write(dir.join("ui.rs"), "fn render_button(label: &str) {\n    // draw the button and its click handler\n}\n").unwrap();
+        fs::write(dir.join(".git").join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        let paths = [dir.to_string_lossy().to_string()];
+
+        let embedder = WordEmbedder { embedded: Cell::new(0) };
+        let (index, embedded) = build_index(&embedder, &read_project_files(&paths).unwrap(), 60, None).await.unwrap();
+        assert_eq!((index.chunks.len(), embedded), (2, 2));
+
+        // Only the changed file is embedded again
+        fs::write(dir.join("ui.rs"), "fn render_button(label: &str, color: Color) {}\n").unwrap();
+        let (index, embedded) = build_index(&embedder, &read_project_files(&paths).unwrap(), 60, Some(&index)).await.unwrap();
+        assert_eq!((index.chunks.len(), embedded, embedder.embedded.get()), (2, 1, 3));
+
+        let context = retrieve_context(&embedder, &index, "Which database pool does the app use?", 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(context.contains("db.rs (lines 1-3)"));
+        assert!(!context.contains("render_button"));
+        let context = project_context(Some((&embedder, &index)), "Where is the button drawn?", 1).await.unwrap();
+        assert!(context.contains("ui.rs (lines 1-1)"));
+        assert!(project_context(None, "Where is the button drawn?", 1).await.is_none());
+
+        let file = dir.join("index.json").to_string_lossy().to_string();
+        index.save(&file).unwrap();
+        assert_eq!(CodeIndex::load(&file).unwrap().chunks[0].vector, index.chunks[0].vector);
+        fs::remove_dir_all(&dir).unwrap();
+
+        // The Gemini embedder speaks the embedContent and batchEmbedContents format of the mock server
+        let scenario: MockScenario = serde_json::from_value(json!({"steps": []})).unwrap();
+        let client = mock_client(scenario).await;
+        let gemini = GeminiEmbedder::new(&client, "models/gemini-embedding-001", Some(64));
+        let texts = ["db.rs\nfn open_database_pool".to_string(), "ui.rs\nfn render_button".to_string()];
+        let vectors = gemini.embed_documents(&texts).await.unwrap();
+        assert_eq!(vectors.len(), 2);
+        assert_eq!(vectors[0], embedder.embed_query(&texts[0]).await.unwrap());
+        assert_eq!(gemini.embed_query(&texts[1]).await.unwrap(), vectors[1]);
+    }
+}